@@ -1,4 +1,5 @@
 #![no_std]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BlockAccessError {
     BlockOutOfRange,
     MiscError
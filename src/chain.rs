@@ -0,0 +1,359 @@
+use heapless::Vec;
+use heapless::consts::U512;
+use block_accessor::BlockAccessor;
+
+use fat32::MAX_BLOCK_SIZE;
+
+/// Enumeration of possible methods to seek within a file, mirrors
+/// `std::io::SeekFrom`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64)
+}
+
+/// A volume that stores files in cluster chains, which `FileReader` reads
+/// through. Both FAT and exFAT volumes are read this way.
+pub trait ClusterChains {
+    type Error: From<ChainError>;
+
+    fn bytes_per_cluster(&self) -> u32;
+
+    fn is_valid_cluster(&self, cluster_num: u32) -> bool;
+
+    /// The cluster after `cluster_num` in its chain, `None` at the end of
+    /// the chain or when it's broken.
+    fn next_cluster(&mut self, cluster_num: u32) -> Option<u32>;
+
+    /// Fill `buffer` with bytes starting `offset` bytes into `cluster_num`.
+    fn read_cluster(&mut self, cluster_num: u32, offset: u64, buffer: &mut [u8]);
+}
+
+/// Errors from reading a cluster chain, which each volume type turns into
+/// its own error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainError {
+    /// A cluster chain ended early, or pointed at a free, bad or
+    /// out-of-range cluster
+    CorruptChain,
+    /// Attempted to seek to a negative position
+    InvalidSeek
+}
+
+/// Number of chain positions remembered by a `ChainCursor`
+const CHAIN_CACHE_SIZE: usize = 16;
+
+/// Remembers the cluster at every `stride`th position in a cluster chain,
+/// so seeking within a file only walks the FAT from the closest known
+/// position instead of from the start of the chain.
+///
+/// When the cache fills up, every other position is dropped and the stride
+/// doubles, so the cache covers a file of any length with bounded memory.
+struct ChainCache {
+    stride: u32,
+    clusters: [u32; CHAIN_CACHE_SIZE],
+    len: usize
+}
+
+impl ChainCache {
+    fn new(first_cluster: u32) -> ChainCache {
+        let mut clusters = [0; CHAIN_CACHE_SIZE];
+        clusters[0] = first_cluster;
+
+        ChainCache {
+            stride: 1,
+            clusters,
+            len: 1
+        }
+    }
+
+    /// The closest known `(chain index, cluster)` at or before `chain_index`.
+    fn closest(&self, chain_index: u32) -> (u32, u32) {
+        let slot = usize::min((chain_index / self.stride) as usize, self.len - 1);
+        (slot as u32 * self.stride, self.clusters[slot])
+    }
+
+    fn record(&mut self, chain_index: u32, cluster_num: u32) {
+        if !chain_index.is_multiple_of(self.stride) || (chain_index / self.stride) as usize != self.len {
+            return;
+        }
+
+        if self.len == CHAIN_CACHE_SIZE {
+            for slot in 0..CHAIN_CACHE_SIZE / 2 {
+                self.clusters[slot] = self.clusters[slot * 2];
+            }
+            self.len = CHAIN_CACHE_SIZE / 2;
+            self.stride *= 2;
+            return self.record(chain_index, cluster_num);
+        }
+
+        self.clusters[self.len] = cluster_num;
+        self.len += 1;
+    }
+}
+
+/// A position in a chain, so reading forwards doesn't walk the FAT from the
+/// start every time. Chains can also be consecutive clusters that don't use
+/// the FAT, which exFAT stores some files as.
+pub(crate) struct ChainCursor {
+    contiguous: bool,
+    /// The `(chain index, cluster)` that was found last
+    current: Option<(u32, u32)>,
+    chain_cache: ChainCache
+}
+
+impl ChainCursor {
+    pub(crate) fn new(first_cluster: u32, contiguous: bool) -> ChainCursor {
+        ChainCursor {
+            contiguous,
+            current: None,
+            chain_cache: ChainCache::new(first_cluster)
+        }
+    }
+
+    /// Find the cluster at position `chain_index` in the chain, starting
+    /// from the closest position that's already known.
+    pub(crate) fn cluster_at<V: ClusterChains>(&mut self, volume: &mut V, chain_index: u32)
+        -> Result<u32, ChainError>
+    {
+        if self.contiguous {
+            let first_cluster = self.chain_cache.clusters[0];
+            return match first_cluster.checked_add(chain_index) {
+                Some(cluster_num) if volume.is_valid_cluster(cluster_num) => Ok(cluster_num),
+                _ => Err(ChainError::CorruptChain)
+            };
+        }
+
+        let (mut index, mut cluster_num) = self.chain_cache.closest(chain_index);
+        if let Some((current_index, current_cluster)) = self.current {
+            if current_index <= chain_index && current_index > index {
+                index = current_index;
+                cluster_num = current_cluster;
+            }
+        }
+
+        if !volume.is_valid_cluster(cluster_num) {
+            return Err(ChainError::CorruptChain);
+        }
+
+        while index < chain_index {
+            cluster_num = volume.next_cluster(cluster_num).ok_or(ChainError::CorruptChain)?;
+            index += 1;
+            self.chain_cache.record(index, cluster_num);
+        }
+
+        self.current = Some((index, cluster_num));
+        Ok(cluster_num)
+    }
+
+    /// Fill `buffer` with the bytes starting at `offset` in the chain.
+    pub(crate) fn read<V: ClusterChains>(&mut self, volume: &mut V, offset: u64, buffer: &mut [u8])
+        -> Result<(), ChainError>
+    {
+        let bytes_per_cluster = u64::from(volume.bytes_per_cluster());
+
+        let mut bytes_read = 0;
+        while bytes_read < buffer.len() {
+            let position = offset + bytes_read as u64;
+            let cluster_num = self.cluster_at(volume, (position / bytes_per_cluster) as u32)?;
+            let offset_in_cluster = position % bytes_per_cluster;
+            let length = u64::min(bytes_per_cluster - offset_in_cluster,
+                                  (buffer.len() - bytes_read) as u64) as usize;
+
+            volume.read_cluster(cluster_num, offset_in_cluster, &mut buffer[bytes_read..bytes_read+length]);
+            bytes_read += length;
+        }
+
+        Ok(())
+    }
+}
+
+/// Random access reader for the contents of a file.
+///
+/// Reads go straight into the caller's buffer, only partial blocks at the
+/// start or end of a read are copied through a temporary block.
+pub struct FileReader<'a, V: 'a> {
+    volume: &'a mut V,
+    cursor: ChainCursor,
+    size: u64,
+    /// Bytes past this are read as zeros, for exFAT files whose data hasn't
+    /// all been written
+    valid_size: u64,
+    position: u64
+}
+
+impl<'a, V: ClusterChains> FileReader<'a, V> {
+    pub(crate) fn new(volume: &'a mut V, cursor: ChainCursor, size: u64, valid_size: u64) -> FileReader<'a, V> {
+        FileReader {
+            volume,
+            cursor,
+            size,
+            valid_size,
+            position: 0
+        }
+    }
+
+    /// The size of the file in bytes.
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// The current position in the file, in bytes from the start.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Move to a new position in the file, returning the new position.
+    ///
+    /// Seeking past the end of the file is allowed, reads from there return
+    /// no data.
+    pub fn seek(&mut self, position: SeekFrom) -> Result<u64, V::Error> {
+        let (base, offset) = match position {
+            SeekFrom::Start(position) => {
+                self.position = position;
+                return Ok(position);
+            },
+            SeekFrom::End(offset) => (self.len(), offset),
+            SeekFrom::Current(offset) => (self.position, offset)
+        };
+
+        let new_position = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.wrapping_neg() as u64)
+        };
+
+        match new_position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            },
+            None => Err(ChainError::InvalidSeek.into())
+        }
+    }
+
+    /// Read into `buffer` from the current position, returning the number of
+    /// bytes read. Only returns fewer bytes than `buffer` can hold at the end
+    /// of the file.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, V::Error> {
+        let bytes_left = self.size.saturating_sub(self.position);
+        let bytes_to_read = u64::min(bytes_left, buffer.len() as u64) as usize;
+        let valid_bytes = u64::min(self.valid_size.saturating_sub(self.position),
+                                   bytes_to_read as u64) as usize;
+
+        self.cursor.read(self.volume, self.position, &mut buffer[..valid_bytes])?;
+        for byte in buffer[valid_bytes..bytes_to_read].iter_mut() {
+            *byte = 0;
+        }
+
+        self.position += bytes_to_read as u64;
+        Ok(bytes_to_read)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, V: ClusterChains> ::std::io::Read for FileReader<'a, V> {
+    fn read(&mut self, buffer: &mut [u8]) -> ::std::io::Result<usize> {
+        FileReader::read(self, buffer).map_err(|_| {
+            ::std::io::Error::new(::std::io::ErrorKind::InvalidData, "corrupt cluster chain")
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, V: ClusterChains> ::std::io::Seek for FileReader<'a, V> {
+    fn seek(&mut self, position: ::std::io::SeekFrom) -> ::std::io::Result<u64> {
+        let position = match position {
+            ::std::io::SeekFrom::Start(position) => SeekFrom::Start(position),
+            ::std::io::SeekFrom::End(offset) => SeekFrom::End(offset),
+            ::std::io::SeekFrom::Current(offset) => SeekFrom::Current(offset)
+        };
+
+        FileReader::seek(self, position).map_err(|_| {
+            ::std::io::Error::new(::std::io::ErrorKind::InvalidInput, "invalid seek to a negative position")
+        })
+    }
+}
+
+/// Iterator over the contents of a file in chunks of up to 512 bytes. A
+/// corrupt chain is reported once, then iteration stops.
+pub struct FileIterator<'a, V: 'a> {
+    reader: FileReader<'a, V>,
+    failed: bool
+}
+
+impl<'a, V: ClusterChains> FileIterator<'a, V> {
+    pub(crate) fn new(reader: FileReader<'a, V>) -> FileIterator<'a, V> {
+        FileIterator {
+            reader,
+            failed: false
+        }
+    }
+}
+
+// Replace U512 with a generic ArrayLength type when GAT's are implemented
+// in rust
+impl<'a, V: ClusterChains> Iterator for FileIterator<'a, V> {
+    type Item = Result<Vec<u8, U512>, V::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut block = Vec::new();
+
+        let bytes_left = self.reader.len().saturating_sub(self.reader.position());
+        let bytes_to_read = u64::min(bytes_left, block.capacity() as u64) as usize;
+        if bytes_to_read == 0 || self.failed {
+            return None;
+        }
+
+        // Shouldn't fail since it's resized with its own capacity
+        block.resize_default(bytes_to_read).unwrap();
+
+        match self.reader.read(&mut block) {
+            Ok(0) => None,
+            Ok(bytes_read) => {
+                block.truncate(bytes_read);
+                Some(Ok(block))
+            },
+            Err(error) => {
+                self.failed = true;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+/// Fill `result` with bytes starting at `volume_offset` bytes from the
+/// start of a volume that starts at `physical_start_block`.
+///
+/// Whole blocks are read straight into `result`, only the partial blocks
+/// at either end go through a temporary block buffer.
+pub(crate) fn read_volume<B: BlockAccessor>(block_storage: &mut B, physical_start_block: u32,
+                                            volume_offset: u64, result: &mut [u8])
+{
+    let block_size = block_storage.block_size();
+    let mut block = [0; MAX_BLOCK_SIZE];
+
+    let mut position = 0;
+    while position < result.len() {
+        let address = volume_offset + position as u64;
+        let block_num = u64::from(physical_start_block) + address / block_size;
+        let offset_in_block = (address % block_size) as usize;
+        let length = usize::min(block_size as usize - offset_in_block,
+                                result.len() - position);
+
+        if length == block_size as usize {
+            block_storage.read_block(block_num, &mut result[position..position+length]);
+        } else {
+            block_storage.read_block(block_num, &mut block[..block_size as usize]);
+            result[position..position+length]
+                .copy_from_slice(&block[offset_in_block..offset_in_block+length]);
+        }
+
+        position += length;
+    }
+}
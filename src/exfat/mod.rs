@@ -0,0 +1,1014 @@
+use byte_util::little_endian_to_int;
+use block_accessor::BlockAccessor;
+
+use fat32::{Name, DateTime, FileInfo, DirectoryEntryFlags, EntryPosition, FatEntry,
+            MAX_BLOCK_SIZE, decode_long_name};
+use chain::{self, ChainCursor, ChainError, ClusterChains};
+use path;
+
+const BOOT_SECTOR_SIZE: usize = 512;
+/// Sectors of the main boot region covered by the boot checksum, which is
+/// stored in the sector after them
+const BOOT_CHECKSUM_SECTORS: u64 = 11;
+const BYTES_PER_DIRECTORY_ENTRY: u64 = 32;
+const NAME_CHARACTERS_PER_ENTRY: usize = 15;
+const MAX_NAME_LENGTH: usize = 255;
+const MAX_LABEL_LENGTH: usize = 11;
+
+/// Up-case table mappings kept in memory, covering Latin-1 and Latin
+/// Extended-A and B. The rest of the table is read from the volume when it's
+/// needed.
+const UP_CASE_CACHE_SIZE: usize = 0x250;
+/// In the compressed up-case table, this is followed by the number of
+/// characters that map to themselves
+const UP_CASE_IDENTITY_RUN: u16 = 0xFFFF;
+
+const ENTRY_END_OF_DIRECTORY: u8 = 0x00;
+const ENTRY_IN_USE: u8 = 0x80;
+const ENTRY_SECONDARY: u8 = 0x40;
+const ENTRY_ALLOCATION_BITMAP: u8 = 0x81;
+const ENTRY_UP_CASE_TABLE: u8 = 0x82;
+const ENTRY_VOLUME_LABEL: u8 = 0x83;
+const ENTRY_FILE: u8 = 0x85;
+const ENTRY_STREAM_EXTENSION: u8 = 0xC0;
+const ENTRY_FILE_NAME: u8 = 0xC1;
+
+bitflags! {
+    /// Flags of the stream extension entry, at offset 0x01.
+    pub struct StreamFlags: u8 {
+        const ALLOCATION_POSSIBLE = 0b0000_0001;
+        /// The data is stored in consecutive clusters and the FAT isn't
+        /// used for it
+        const NO_FAT_CHAIN        = 0b0000_0010;
+    }
+}
+
+/// A read-only exFAT volume, with the same directory and file API as
+/// `Fat32`.
+pub struct ExFat<B> where B: BlockAccessor {
+    pub block_storage: B,
+    pub physical_start_block: u32,
+    pub boot_sector: BootSector,
+    allocation_bitmap: Chain,
+    up_case_table: UpCaseTable
+}
+
+impl<B: BlockAccessor> ExFat<B> {
+    /// Mount the volume starting at `physical_start_block`, checking the
+    /// boot region and loading the up-case table.
+    pub fn new(mut block_storage: B, physical_start_block: u32) -> Result<ExFat<B>, ExFatError> {
+        let mut boot_sector_bytes = [0; BOOT_SECTOR_SIZE];
+        chain::read_volume(&mut block_storage, physical_start_block, 0, &mut boot_sector_bytes);
+        let boot_sector = BootSector::new(&boot_sector_bytes)?;
+
+        let mut exfat = ExFat {
+            block_storage,
+            physical_start_block,
+            boot_sector,
+            allocation_bitmap: Chain::contiguous(0),
+            up_case_table: UpCaseTable {
+                data: Chain::contiguous(0),
+                data_length: 0,
+                checksum: 0,
+                length: 0,
+                cache: [0; UP_CASE_CACHE_SIZE]
+            }
+        };
+
+        exfat.check_boot_checksum()?;
+        exfat.find_system_entries()?;
+        exfat.load_up_case_table()?;
+        Ok(exfat)
+    }
+
+    pub fn bytes_per_sector(&self) -> u32 {
+        self.boot_sector.bytes_per_sector()
+    }
+
+    pub fn bytes_per_cluster(&self) -> u32 {
+        self.boot_sector.bytes_per_cluster()
+    }
+
+    pub fn cluster_count(&self) -> u32 {
+        self.boot_sector.cluster_count
+    }
+
+    fn read_volume(&mut self, volume_offset: u64, result: &mut [u8]) {
+        chain::read_volume(&mut self.block_storage, self.physical_start_block, volume_offset, result);
+    }
+
+    fn cluster_offset(&self, cluster_num: u32) -> u64 {
+        u64::from(self.boot_sector.cluster_heap_offset) * u64::from(self.bytes_per_sector()) +
+            u64::from(cluster_num - 2) * u64::from(self.bytes_per_cluster())
+    }
+
+    fn is_valid_cluster(&self, cluster_num: u32) -> bool {
+        cluster_num >= 2 && cluster_num - 2 < self.cluster_count()
+    }
+
+    /// The entry for `cluster_num` in the active FAT.
+    ///
+    /// Files stored without a FAT chain have free entries, so this only
+    /// describes files and directories that use one.
+    pub fn fat_entry(&mut self, cluster_num: u32) -> Result<FatEntry, ExFatError> {
+        if !self.is_valid_cluster(cluster_num) {
+            return Err(ExFatError::InvalidCluster);
+        }
+
+        let fat_sector = self.boot_sector.fat_offset +
+                         u32::from(self.boot_sector.active_fat()) * self.boot_sector.fat_length;
+        let entry_offset = u64::from(fat_sector) * u64::from(self.bytes_per_sector()) +
+                           u64::from(cluster_num) * 4;
+
+        let mut entry_bytes = [0; 4];
+        self.read_volume(entry_offset, &mut entry_bytes);
+        Ok(decode_fat_entry(little_endian_to_int(&entry_bytes), self.cluster_count()))
+    }
+
+    /// The boot checksum sector is filled with copies of the checksum of
+    /// the sectors before it.
+    fn check_boot_checksum(&mut self) -> Result<(), ExFatError> {
+        let bytes_per_sector = self.bytes_per_sector() as usize;
+        let mut sector = [0; MAX_BLOCK_SIZE];
+
+        let mut checksum = 0;
+        for sector_num in 0..BOOT_CHECKSUM_SECTORS {
+            self.read_volume(sector_num * bytes_per_sector as u64, &mut sector[..bytes_per_sector]);
+            checksum = add_to_boot_checksum(checksum, sector_num == 0, &sector[..bytes_per_sector]);
+        }
+
+        self.read_volume(BOOT_CHECKSUM_SECTORS * bytes_per_sector as u64, &mut sector[..bytes_per_sector]);
+        if sector[..bytes_per_sector].chunks(4).all(|stored| little_endian_to_int(stored) == checksum) {
+            Ok(())
+        } else {
+            Err(ExFatError::BootChecksumMismatch)
+        }
+    }
+
+    /// Find the allocation bitmap for the active FAT and the up-case table,
+    /// which are both described by entries in the root directory.
+    fn find_system_entries(&mut self) -> Result<(), ExFatError> {
+        let active_fat = self.boot_sector.active_fat();
+        let root = self.root_dir();
+        let mut allocation_bitmap = None;
+        let mut up_case_table = None;
+
+        {
+            let mut entries = self.iter_dir(&root);
+            while let Some((_, entry_bytes)) = entries.next_entry() {
+                match entry_bytes[0] {
+                    ENTRY_END_OF_DIRECTORY => break,
+                    // With two FATs there's a bitmap for each of them
+                    ENTRY_ALLOCATION_BITMAP if entry_bytes[1] & 0x01 == active_fat => {
+                        allocation_bitmap = Some(Chain::new(little_endian_to_int(&entry_bytes[20..24]), false));
+                    },
+                    ENTRY_UP_CASE_TABLE => {
+                        up_case_table = Some((little_endian_to_int(&entry_bytes[20..24]),
+                                              u64_at(&entry_bytes[24..32]),
+                                              little_endian_to_int(&entry_bytes[4..8])));
+                    },
+                    _ => {}
+                }
+            }
+        }
+
+        self.allocation_bitmap = allocation_bitmap.ok_or(ExFatError::MissingAllocationBitmap)?;
+
+        let (cluster, data_length, checksum) = up_case_table.ok_or(ExFatError::MissingUpCaseTable)?;
+        self.up_case_table.data = Chain::new(cluster, false);
+        self.up_case_table.data_length = data_length;
+        self.up_case_table.checksum = checksum;
+        Ok(())
+    }
+
+    /// Check the up-case table against its checksum and cache the start of
+    /// it. Characters past the end of the table map to themselves.
+    fn load_up_case_table(&mut self) -> Result<(), ExFatError> {
+        let mut cache = [0; UP_CASE_CACHE_SIZE];
+        for (index, unit) in cache.iter_mut().enumerate() {
+            *unit = index as u16;
+        }
+
+        let (checksum, length) = self.walk_up_case_table(|index, mapping| {
+            if (index as usize) < UP_CASE_CACHE_SIZE {
+                cache[index as usize] = mapping;
+            }
+            true
+        })?;
+
+        if checksum != self.up_case_table.checksum {
+            return Err(ExFatError::UpCaseChecksumMismatch);
+        }
+
+        self.up_case_table.cache = cache;
+        self.up_case_table.length = length;
+        Ok(())
+    }
+
+    /// Decompress the up-case table, calling `f` with each character that
+    /// doesn't map to itself until it returns false. Returns the checksum of
+    /// the table and the number of characters it covers.
+    fn walk_up_case_table<F>(&mut self, mut f: F) -> Result<(u32, u32), ExFatError>
+        where F: FnMut(u32, u16) -> bool
+    {
+        let mut cursor = self.up_case_table.data.cursor();
+        let data_length = self.up_case_table.data_length;
+        let mut chunk = [0; 512];
+
+        let mut checksum = 0;
+        let mut index = 0;
+        let mut in_identity_run = false;
+
+        let mut position = 0;
+        while position < data_length {
+            let length = u64::min(data_length - position, chunk.len() as u64) as usize;
+            cursor.read(self, position, &mut chunk[..length])?;
+            position += length as u64;
+
+            for byte in chunk[..length].iter() {
+                checksum = add_to_checksum(checksum, *byte);
+            }
+
+            for unit in chunk[..length].chunks(2) {
+                let unit = little_endian_to_int(unit) as u16;
+                if in_identity_run {
+                    index += u32::from(unit);
+                    in_identity_run = false;
+                } else if unit == UP_CASE_IDENTITY_RUN {
+                    in_identity_run = true;
+                } else {
+                    if !f(index, unit) {
+                        return Ok((checksum, index));
+                    }
+                    index += 1;
+                }
+            }
+        }
+
+        Ok((checksum, index))
+    }
+
+    /// Map `units` to upper case with the volume's up-case table, walking
+    /// the part of the table past the cache at most once.
+    fn up_case(&mut self, units: &mut [u16]) {
+        let length = self.up_case_table.length;
+        let mut highest = None;
+        for unit in units.iter_mut() {
+            if usize::from(*unit) < UP_CASE_CACHE_SIZE {
+                *unit = self.up_case_table.cache[usize::from(*unit)];
+            } else if u32::from(*unit) < length {
+                highest = Some(u32::max(highest.unwrap_or(0), u32::from(*unit)));
+            }
+        }
+
+        let highest = match highest {
+            Some(highest) => highest,
+            None => return
+        };
+
+        // Mapped into a copy, so a unit isn't matched again at its new value
+        let mut upper = [None; MAX_NAME_LENGTH];
+        let searched = self.walk_up_case_table(|index, mapping| {
+            if index >= UP_CASE_CACHE_SIZE as u32 {
+                for (unit, upper) in units.iter().zip(upper.iter_mut()) {
+                    if u32::from(*unit) == index {
+                        *upper = Some(mapping);
+                    }
+                }
+            }
+            index < highest
+        });
+
+        // Characters stay as they are if the table can't be read
+        if searched.is_ok() {
+            for (unit, upper) in units.iter_mut().zip(upper.iter()) {
+                if let Some(upper) = *upper {
+                    *unit = upper;
+                }
+            }
+        }
+    }
+
+    /// Whether `cluster_num` is marked as in use in the allocation bitmap.
+    pub fn is_cluster_allocated(&mut self, cluster_num: u32) -> Result<bool, ExFatError> {
+        if !self.is_valid_cluster(cluster_num) {
+            return Err(ExFatError::InvalidCluster);
+        }
+
+        let bit = cluster_num - 2;
+        let mut byte = [0; 1];
+        let mut cursor = self.allocation_bitmap.cursor();
+        cursor.read(self, u64::from(bit / 8), &mut byte)?;
+        Ok(byte[0] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Count the clusters that aren't in use, according to the allocation
+    /// bitmap.
+    pub fn free_cluster_count(&mut self) -> Result<u32, ExFatError> {
+        let cluster_count = self.cluster_count();
+        let mut cursor = self.allocation_bitmap.cursor();
+        let mut chunk = [0; 512];
+        let mut used = 0;
+
+        let bitmap_length = u64::from(cluster_count.div_ceil(8));
+        let mut position = 0;
+        while position < bitmap_length {
+            let length = u64::min(bitmap_length - position, chunk.len() as u64) as usize;
+            cursor.read(self, position, &mut chunk[..length])?;
+
+            for (offset, byte) in chunk[..length].iter().enumerate() {
+                let first_bit = (position + offset as u64) * 8;
+                // Bits past the last cluster don't count
+                let byte = if first_bit + 8 > u64::from(cluster_count) {
+                    byte & ((1u16 << (u64::from(cluster_count) - first_bit)) - 1) as u8
+                } else {
+                    *byte
+                };
+                used += byte.count_ones();
+            }
+
+            position += length as u64;
+        }
+
+        Ok(cluster_count - used)
+    }
+
+    /// Get a handle to the root directory of the volume.
+    pub fn root_dir(&self) -> Directory {
+        Directory {
+            name: Name::new(),
+            cluster: self.boot_sector.root_directory_cluster,
+            size: 0,
+            contiguous: false,
+            location: None,
+            info: FileInfo {
+                attributes: DirectoryEntryFlags::SUBDIRECTORY,
+                created: None,
+                modified: None,
+                accessed: None
+            }
+        }
+    }
+
+    /// Iterate over the contents of `directory`.
+    pub fn iter_dir(&mut self, directory: &Directory) -> DirectoryIterator<B> {
+        // The root directory has no stream extension to give its size, so
+        // its chain is followed to the end
+        let size = if directory.location.is_some() {
+            Some(directory.size)
+        } else {
+            None
+        };
+
+        DirectoryIterator {
+            exfat: self,
+            cursor: ChainCursor::new(directory.cluster, directory.contiguous),
+            size,
+            entry_index: 0,
+            done: false,
+            name_hash: None
+        }
+    }
+
+    /// Iterate over the contents of `file` in chunks of up to 512 bytes.
+    /// A chain that ends before the file does gives one `CorruptChain`
+    /// error, and then nothing more.
+    pub fn iter_file(&mut self, file: &File) -> FileIterator<B> {
+        FileIterator::new(self.open_file(file))
+    }
+
+    /// Open `file` for random access reads. Data past the file's valid
+    /// length reads as zeros.
+    pub fn open_file(&mut self, file: &File) -> FileReader<B> {
+        FileReader::new(self, ChainCursor::new(file.cluster, file.contiguous), file.size, file.valid_size)
+    }
+
+    /// The volume's label, from the label entry in the root directory.
+    /// `None` if the volume has no label.
+    pub fn volume_label(&mut self) -> Option<Name> {
+        let root = self.root_dir();
+        let mut entries = self.iter_dir(&root);
+
+        while let Some((_, entry_bytes)) = entries.next_entry() {
+            match entry_bytes[0] {
+                ENTRY_END_OF_DIRECTORY => break,
+                ENTRY_VOLUME_LABEL => {
+                    let length = usize::min(usize::from(entry_bytes[1]), MAX_LABEL_LENGTH);
+                    if length == 0 {
+                        return None;
+                    }
+
+                    let mut units = [0; MAX_LABEL_LENGTH];
+                    for (index, unit) in units[..length].iter_mut().enumerate() {
+                        *unit = little_endian_to_int(&entry_bytes[2 + index*2..4 + index*2]) as u16;
+                    }
+
+                    let mut label = Name::new();
+                    decode_long_name(&units[..length], &mut label);
+                    return Some(label);
+                },
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    /// Look up the file or directory at `path`, ignoring case with the
+    /// volume's up-case table.
+    ///
+    /// Paths are resolved the same way as for `Fat32::item_info`, so `..`
+    /// after a file doesn't match anything. A path ending with a slash only
+    /// matches a directory, and an empty path is the root directory.
+    pub fn item_info(&mut self, path: &str) -> Option<DirectoryItem> {
+        self.resolve_path(path).ok()
+    }
+
+    /// Look up the directory at `path`.
+    pub fn directory_at(&mut self, path: &str) -> Result<Directory, ExFatError> {
+        match self.resolve_path(path)? {
+            DirectoryItem::Directory(d) => Ok(d),
+            DirectoryItem::File(_) => Err(ExFatError::NotADirectory)
+        }
+    }
+
+    /// Follow `path` from the root directory, one part at a time.
+    fn resolve_path(&mut self, path: &str) -> Result<DirectoryItem, ExFatError> {
+        let mut current = DirectoryItem::Directory(self.root_dir());
+
+        for (part, end) in path::Parts::new(path) {
+            let directory = match current {
+                DirectoryItem::Directory(d) => d,
+                DirectoryItem::File(_) => return Err(ExFatError::NotADirectory)
+            };
+
+            current = if part == ".." {
+                // Everything before the `..` has been looked up as a
+                // directory, so it's safe to cancel it out of the path
+                DirectoryItem::Directory(self.normalized_directory_at(&path[..end])?)
+            } else {
+                self.find_item(&directory, part.trim_end_matches('.')).ok_or(ExFatError::NotFound)?
+            };
+        }
+
+        match current {
+            DirectoryItem::File(_) if path::is_directory_path(path) => Err(ExFatError::NotADirectory),
+            item => Ok(item)
+        }
+    }
+
+    /// Look up the directory at `path` after normalizing it lexically.
+    fn normalized_directory_at(&mut self, path: &str) -> Result<Directory, ExFatError> {
+        let mut current = self.root_dir();
+
+        for name in path::Components::new(path) {
+            match self.find_item(&current, name) {
+                Some(DirectoryItem::Directory(d)) => current = d,
+                Some(DirectoryItem::File(_)) => return Err(ExFatError::NotADirectory),
+                None => return Err(ExFatError::NotFound)
+            }
+        }
+
+        Ok(current)
+    }
+
+    /// Find the item in `directory` called `name`, ignoring case.
+    ///
+    /// Entry sets store a hash of their up-cased name, so only the items
+    /// with a matching hash have their names compared.
+    fn find_item(&mut self, directory: &Directory, name: &str) -> Option<DirectoryItem> {
+        let mut up_cased = [0; MAX_NAME_LENGTH];
+        let length = encode_name(name, &mut up_cased)?;
+        let up_cased = &mut up_cased[..length];
+        self.up_case(up_cased);
+
+        let mut entries = self.iter_dir(directory);
+        entries.name_hash = Some(name_hash(up_cased));
+
+        while let Some(item) = entries.next() {
+            let mut item_units = [0; MAX_NAME_LENGTH];
+            let item_length = {
+                let item_name = match item {
+                    DirectoryItem::File(ref f) => &f.name,
+                    DirectoryItem::Directory(ref d) => &d.name
+                };
+                encode_name(item_name, &mut item_units)
+            };
+
+            if let Some(item_length) = item_length {
+                let item_units = &mut item_units[..item_length];
+                entries.exfat.up_case(item_units);
+                if item_units[..] == up_cased[..] {
+                    return Some(item);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExFatError {
+    /// The boot sector doesn't belong to an exFAT volume
+    NotExFat,
+    /// The boot sector describes a volume that can't exist
+    InvalidBootSector,
+    /// The boot region doesn't match its checksum
+    BootChecksumMismatch,
+    /// There's no allocation bitmap for the active FAT in the root directory
+    MissingAllocationBitmap,
+    /// There's no up-case table in the root directory
+    MissingUpCaseTable,
+    /// The up-case table doesn't match its checksum
+    UpCaseChecksumMismatch,
+    /// A cluster chain ended early, or pointed at a free, bad or
+    /// out-of-range cluster
+    CorruptChain,
+    /// Attempted to seek to a negative position
+    InvalidSeek,
+    /// A cluster number outside of the volume was given
+    InvalidCluster,
+    /// Nothing exists at the given path
+    NotFound,
+    /// Part of a path is a file
+    NotADirectory
+}
+
+impl From<ChainError> for ExFatError {
+    fn from(error: ChainError) -> ExFatError {
+        match error {
+            ChainError::CorruptChain => ExFatError::CorruptChain,
+            ChainError::InvalidSeek => ExFatError::InvalidSeek
+        }
+    }
+}
+
+/// The boot sector of an exFAT volume.
+#[derive(Debug, Clone, Copy)]
+pub struct BootSector {
+    /// Offset of the volume on its device, in sectors
+    pub partition_offset: u64,
+    /// Size of the volume, in sectors
+    pub volume_length: u64,
+    /// Offset of the first FAT, in sectors
+    pub fat_offset: u32,
+    /// Size of each FAT, in sectors
+    pub fat_length: u32,
+    /// Offset of cluster 2, in sectors
+    pub cluster_heap_offset: u32,
+    pub cluster_count: u32,
+    pub root_directory_cluster: u32,
+    pub serial_number: u32,
+    /// Major version in the high byte, minor version in the low byte
+    pub revision: u16,
+    pub volume_flags: u16,
+    pub bytes_per_sector_shift: u8,
+    pub sectors_per_cluster_shift: u8,
+    pub number_of_fats: u8,
+    /// Percentage of clusters in use, 0xFF if unknown
+    pub percent_in_use: u8
+}
+
+impl BootSector {
+    pub fn new(bytes: &[u8]) -> Result<BootSector, ExFatError> {
+        if &bytes[3..11] != b"EXFAT   " || bytes[510] != 0x55 || bytes[511] != 0xAA {
+            return Err(ExFatError::NotExFat);
+        }
+
+        // Where FAT keeps its BPB must be zero, so FAT drivers don't try to
+        // mount the volume
+        if bytes[11..64].iter().any(|b| *b != 0) {
+            return Err(ExFatError::NotExFat);
+        }
+
+        let boot_sector = BootSector {
+            partition_offset: u64_at(&bytes[64..72]),
+            volume_length: u64_at(&bytes[72..80]),
+            fat_offset: little_endian_to_int(&bytes[80..84]),
+            fat_length: little_endian_to_int(&bytes[84..88]),
+            cluster_heap_offset: little_endian_to_int(&bytes[88..92]),
+            cluster_count: little_endian_to_int(&bytes[92..96]),
+            root_directory_cluster: little_endian_to_int(&bytes[96..100]),
+            serial_number: little_endian_to_int(&bytes[100..104]),
+            revision: little_endian_to_int(&bytes[104..106]) as u16,
+            volume_flags: little_endian_to_int(&bytes[106..108]) as u16,
+            bytes_per_sector_shift: bytes[108],
+            sectors_per_cluster_shift: bytes[109],
+            number_of_fats: bytes[110],
+            percent_in_use: bytes[112]
+        };
+
+        // Sectors are 512 to 4096 bytes and clusters at most 32 MiB
+        if boot_sector.bytes_per_sector_shift < 9 || boot_sector.bytes_per_sector_shift > 12 ||
+           boot_sector.sectors_per_cluster_shift > 25 - boot_sector.bytes_per_sector_shift ||
+           boot_sector.number_of_fats < 1 || boot_sector.number_of_fats > 2 ||
+           boot_sector.cluster_count == 0 ||
+           boot_sector.root_directory_cluster < 2 ||
+           boot_sector.root_directory_cluster - 2 >= boot_sector.cluster_count
+        {
+            return Err(ExFatError::InvalidBootSector);
+        }
+
+        Ok(boot_sector)
+    }
+
+    pub fn bytes_per_sector(&self) -> u32 {
+        1 << self.bytes_per_sector_shift
+    }
+
+    pub fn bytes_per_cluster(&self) -> u32 {
+        1 << (self.bytes_per_sector_shift + self.sectors_per_cluster_shift)
+    }
+
+    /// Which FAT and allocation bitmap are in use, 0 or 1.
+    pub fn active_fat(&self) -> u8 {
+        if self.number_of_fats == 2 {
+            (self.volume_flags & 0x01) as u8
+        } else {
+            0
+        }
+    }
+}
+
+/// Decode a raw exFAT FAT entry. Unlike FAT32, all 32 bits of an entry are
+/// used.
+fn decode_fat_entry(raw_entry: u32, cluster_count: u32) -> FatEntry {
+    const BAD_CLUSTER: u32 = 0xFFFF_FFF7;
+    const END_OF_CHAIN: u32 = 0xFFFF_FFFF;
+
+    match raw_entry {
+        0 => FatEntry::Free,
+        BAD_CLUSTER => FatEntry::Bad,
+        END_OF_CHAIN => FatEntry::EndOfChain,
+        next_cluster if next_cluster >= 2 && next_cluster - 2 < cluster_count => FatEntry::Next(next_cluster),
+        _ => FatEntry::Reserved
+    }
+}
+
+/// Add `bytes` to a boot region checksum. In the first sector the volume
+/// flags and percent in use are skipped, since they change without the
+/// checksum being updated.
+fn add_to_boot_checksum(mut checksum: u32, first_sector: bool, bytes: &[u8]) -> u32 {
+    for (index, byte) in bytes.iter().enumerate() {
+        if first_sector && (index == 106 || index == 107 || index == 112) {
+            continue;
+        }
+        checksum = add_to_checksum(checksum, *byte);
+    }
+    checksum
+}
+
+fn add_to_checksum(checksum: u32, byte: u8) -> u32 {
+    checksum.rotate_right(1).wrapping_add(u32::from(byte))
+}
+
+/// The checksum of the main boot region, the first 11 sectors of a volume.
+pub fn boot_checksum(boot_region: &[u8]) -> u32 {
+    let bytes_per_sector = 1 << boot_region[108];
+    let (first_sector, rest) = boot_region.split_at(bytes_per_sector);
+    add_to_boot_checksum(add_to_boot_checksum(0, true, first_sector), false, rest)
+}
+
+/// The checksum of an up-case table, over its compressed form.
+pub fn up_case_table_checksum(table: &[u8]) -> u32 {
+    table.iter().fold(0, |checksum, byte| add_to_checksum(checksum, *byte))
+}
+
+/// The checksum of a directory entry set, which skips the field holding it
+/// in the first entry.
+pub fn entry_set_checksum(entries: &[u8]) -> u16 {
+    entries.iter().enumerate()
+        .filter(|&(index, _)| index != 2 && index != 3)
+        .fold(0, |checksum: u16, (_, byte)| checksum.rotate_right(1).wrapping_add(u16::from(*byte)))
+}
+
+/// Encode `name` as UTF-16 into `units`, returning its length. `None` if
+/// it's longer than any exFAT name.
+fn encode_name(name: &str, units: &mut [u16; MAX_NAME_LENGTH]) -> Option<usize> {
+    let mut length = 0;
+    for unit in name.encode_utf16() {
+        if length == MAX_NAME_LENGTH {
+            return None;
+        }
+        units[length] = unit;
+        length += 1;
+    }
+    Some(length)
+}
+
+/// The hash of an up-cased name stored in its stream extension entry.
+pub fn name_hash(up_cased: &[u16]) -> u16 {
+    let mut hash: u16 = 0;
+    for unit in up_cased {
+        hash = hash.rotate_right(1).wrapping_add(*unit & 0xFF);
+        hash = hash.rotate_right(1).wrapping_add(*unit >> 8);
+    }
+    hash
+}
+
+fn u64_at(bytes: &[u8]) -> u64 {
+    u64::from(little_endian_to_int(&bytes[0..4])) | u64::from(little_endian_to_int(&bytes[4..8])) << 32
+}
+
+/// Where some data is stored: a FAT chain, or consecutive clusters.
+#[derive(Debug, Clone, Copy)]
+struct Chain {
+    first_cluster: u32,
+    contiguous: bool
+}
+
+impl Chain {
+    fn new(first_cluster: u32, contiguous: bool) -> Chain {
+        Chain {
+            first_cluster,
+            contiguous
+        }
+    }
+
+    fn contiguous(first_cluster: u32) -> Chain {
+        Chain::new(first_cluster, true)
+    }
+
+    fn cursor(self) -> ChainCursor {
+        ChainCursor::new(self.first_cluster, self.contiguous)
+    }
+}
+
+struct UpCaseTable {
+    data: Chain,
+    data_length: u64,
+    checksum: u32,
+    /// Number of characters covered by the table once it's decompressed
+    length: u32,
+    cache: [u16; UP_CASE_CACHE_SIZE]
+}
+
+#[derive(Debug)]
+pub enum DirectoryItem {
+    File(File),
+    Directory(Directory)
+}
+
+impl DirectoryItem {
+    /// Where the item's file entry is, `None` for the root directory.
+    pub fn location(&self) -> Option<EntryPosition> {
+        match *self {
+            DirectoryItem::File(ref f) => Some(f.location),
+            DirectoryItem::Directory(ref d) => d.location
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct File {
+    pub name: Name,
+    pub cluster: u32,
+    pub size: u64,
+    /// How much of the file has been written, the rest reads as zeros
+    pub valid_size: u64,
+    /// Whether the file is stored in consecutive clusters without a FAT
+    /// chain
+    pub contiguous: bool,
+    /// The position of the file entry that starts the item's entry set
+    pub location: EntryPosition,
+    pub info: FileInfo
+}
+
+#[derive(Debug)]
+pub struct Directory {
+    pub name: Name,
+    pub cluster: u32,
+    /// 0 for the root directory, whose size isn't recorded
+    pub size: u64,
+    pub contiguous: bool,
+    /// `None` for the root directory, which has no entry set
+    pub location: Option<EntryPosition>,
+    pub info: FileInfo
+}
+
+pub struct DirectoryIterator<'a, B: 'a>
+    where B: BlockAccessor
+{
+    exfat: &'a mut ExFat<B>,
+    cursor: ChainCursor,
+    /// Size of the directory in bytes, `None` to follow its chain to the end
+    size: Option<u64>,
+    entry_index: u64,
+    done: bool,
+    /// Only decode entry sets whose name has this hash
+    name_hash: Option<u16>
+}
+
+impl<'a, B: BlockAccessor> DirectoryIterator<'a, B> {
+    /// Read the next raw entry of the directory along with its position.
+    fn next_entry(&mut self) -> Option<(EntryPosition, [u8; 32])> {
+        if self.done {
+            return None;
+        }
+
+        let offset = self.entry_index * BYTES_PER_DIRECTORY_ENTRY;
+        if let Some(size) = self.size {
+            if offset >= size {
+                self.done = true;
+                return None;
+            }
+        }
+
+        let bytes_per_cluster = u64::from(self.exfat.bytes_per_cluster());
+        let cluster_num = match self.cursor.cluster_at(self.exfat, (offset / bytes_per_cluster) as u32) {
+            Ok(cluster_num) => cluster_num,
+            Err(_) => {
+                self.done = true;
+                return None;
+            }
+        };
+        let offset_in_cluster = offset % bytes_per_cluster;
+
+        let mut entry_bytes = [0; 32];
+        let cluster_offset = self.exfat.cluster_offset(cluster_num);
+        self.exfat.read_volume(cluster_offset + offset_in_cluster, &mut entry_bytes);
+        self.entry_index += 1;
+
+        let position = EntryPosition {
+            cluster: cluster_num,
+            index: (offset_in_cluster / BYTES_PER_DIRECTORY_ENTRY) as u32
+        };
+        Some((position, entry_bytes))
+    }
+
+    /// Read the rest of the entry set started by `file_entry`. Sets that are
+    /// malformed or don't match their checksum are skipped, and iteration
+    /// carries on from the entry after `file_entry`.
+    fn read_entry_set(&mut self, position: EntryPosition, file_entry: &[u8; 32]) -> Option<DirectoryItem> {
+        let after_file_entry = self.entry_index;
+        let secondary_count = file_entry[1];
+
+        let mut checksum = entry_set_checksum(file_entry);
+        let mut stream = None;
+        let mut name = [0; MAX_NAME_LENGTH];
+        let mut name_length = 0;
+        let mut name_units_found = 0;
+        let mut valid = secondary_count >= 2;
+
+        for index in 0..secondary_count {
+            if !valid {
+                break;
+            }
+
+            let entry_bytes = match self.next_entry() {
+                Some((_, entry_bytes)) => entry_bytes,
+                None => {
+                    valid = false;
+                    break;
+                }
+            };
+
+            if entry_bytes[0] & (ENTRY_IN_USE | ENTRY_SECONDARY) != ENTRY_IN_USE | ENTRY_SECONDARY {
+                valid = false;
+                break;
+            }
+
+            for byte in entry_bytes.iter() {
+                checksum = checksum.rotate_right(1).wrapping_add(u16::from(*byte));
+            }
+
+            match (index, entry_bytes[0]) {
+                (0, ENTRY_STREAM_EXTENSION) => {
+                    name_length = usize::from(entry_bytes[3]);
+                    stream = Some(entry_bytes);
+                },
+                (0, _) => valid = false,
+                (_, ENTRY_FILE_NAME) if name_units_found < name_length => {
+                    let units = usize::min(name_length - name_units_found, NAME_CHARACTERS_PER_ENTRY);
+                    for unit in 0..units {
+                        name[name_units_found + unit] =
+                            little_endian_to_int(&entry_bytes[2 + unit*2..4 + unit*2]) as u16;
+                    }
+                    name_units_found += units;
+                },
+                // Other secondary entries are only covered by the checksum
+                _ => {}
+            }
+        }
+
+        let stored_checksum = little_endian_to_int(&file_entry[2..4]) as u16;
+        let stream = match stream {
+            Some(stream) if valid && name_length > 0 && name_units_found == name_length &&
+                            checksum == stored_checksum => stream,
+            _ => {
+                self.entry_index = after_file_entry;
+                return None;
+            }
+        };
+
+        if let Some(hash) = self.name_hash {
+            if little_endian_to_int(&stream[4..6]) as u16 != hash {
+                return None;
+            }
+        }
+
+        let mut item_name = Name::new();
+        decode_long_name(&name[..name_length], &mut item_name);
+
+        let attributes = DirectoryEntryFlags::from_bits_truncate(file_entry[4]);
+        let info = FileInfo {
+            attributes,
+            created: timestamp(&file_entry[8..12], file_entry[20]),
+            modified: timestamp(&file_entry[12..16], file_entry[21]),
+            accessed: timestamp(&file_entry[16..20], 0)
+        };
+
+        let flags = StreamFlags::from_bits_truncate(stream[1]);
+        let cluster = little_endian_to_int(&stream[20..24]);
+        let size = u64_at(&stream[24..32]);
+        let contiguous = flags.contains(StreamFlags::NO_FAT_CHAIN);
+
+        if attributes.contains(DirectoryEntryFlags::SUBDIRECTORY) {
+            Some(DirectoryItem::Directory(
+                Directory {
+                    name: item_name,
+                    cluster,
+                    size,
+                    contiguous,
+                    location: Some(position),
+                    info
+                }
+            ))
+        } else {
+            Some(DirectoryItem::File(
+                File {
+                    name: item_name,
+                    cluster,
+                    size,
+                    valid_size: u64::min(u64_at(&stream[8..16]), size),
+                    contiguous,
+                    location: position,
+                    info
+                }
+            ))
+        }
+    }
+}
+
+/// Decode a timestamp field, which holds a FAT time in its low half and a
+/// FAT date in its high half.
+fn timestamp(bytes: &[u8], fine: u8) -> Option<DateTime> {
+    let timestamp = little_endian_to_int(bytes);
+    DateTime::from_fat((timestamp >> 16) as u16, timestamp as u16, fine)
+}
+
+impl<'a, B: BlockAccessor> Iterator for DirectoryIterator<'a, B> {
+    type Item = DirectoryItem;
+
+    fn next(&mut self) -> Option<DirectoryItem> {
+        loop {
+            let (position, entry_bytes) = self.next_entry()?;
+
+            match entry_bytes[0] {
+                ENTRY_END_OF_DIRECTORY => {
+                    self.done = true;
+                    return None;
+                },
+                ENTRY_FILE => {
+                    if let Some(item) = self.read_entry_set(position, &entry_bytes) {
+                        return Some(item);
+                    }
+                },
+                // Deleted entries, the bitmap, up-case table and label, and
+                // any secondary entries without a file entry before them
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Iterator over the contents of a file, see `ExFat::iter_file`.
+pub type FileIterator<'a, B> = chain::FileIterator<'a, ExFat<B>>;
+
+/// Random access reader for the contents of a file, see `ExFat::open_file`.
+pub type FileReader<'a, B> = chain::FileReader<'a, ExFat<B>>;
+
+impl<B: BlockAccessor> ClusterChains for ExFat<B> {
+    type Error = ExFatError;
+
+    fn bytes_per_cluster(&self) -> u32 {
+        ExFat::bytes_per_cluster(self)
+    }
+
+    fn is_valid_cluster(&self, cluster_num: u32) -> bool {
+        ExFat::is_valid_cluster(self, cluster_num)
+    }
+
+    fn next_cluster(&mut self, cluster_num: u32) -> Option<u32> {
+        match self.fat_entry(cluster_num) {
+            Ok(FatEntry::Next(next_cluster)) => Some(next_cluster),
+            _ => None
+        }
+    }
+
+    fn read_cluster(&mut self, cluster_num: u32, offset: u64, buffer: &mut [u8]) {
+        let cluster_offset = self.cluster_offset(cluster_num);
+        self.read_volume(cluster_offset + offset, buffer);
+    }
+}
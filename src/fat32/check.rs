@@ -0,0 +1,843 @@
+use block_accessor::BlockAccessor;
+use byte_util::little_endian_to_int;
+
+use super::{Fat32, TimeSource, Entry, LfnEntry, DirectoryEntry, DirectoryEntryFlags, EntryPosition, FatEntry,
+            FatType, FsInfo, BOOT_SECTOR_SIZE, FAT_SCAN_CHUNK, FS_INFO_COUNTS_OFFSET, FS_INFO_UNKNOWN,
+            MAX_BLOCK_SIZE, MAX_LFN_ENTRIES};
+
+/// Bytes of the bitmaps `check` tracks clusters in, enough for 4096
+/// clusters per pass
+const CHECK_BUFFER_BYTES: usize = 3 * 512;
+/// How deeply nested directories can be followed
+const MAX_CHECK_DEPTH: usize = 32;
+
+/// A problem found by `check`.
+///
+/// Items are identified by the position of their 8.3 entry, with `None`
+/// standing for the root directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Finding {
+    /// After `length` good clusters the chain runs into a free, bad or out of
+    /// range cluster, or loops back on itself
+    BrokenChain { entry: Option<EntryPosition>, length: u32 },
+    /// A file's chain has a different number of clusters than its size needs
+    SizeMismatch { entry: EntryPosition, size: u32, clusters: u32 },
+    /// The chain of `entry` joins another chain at `cluster`
+    CrossLink { entry: Option<EntryPosition>, cluster: u32 },
+    /// A chain of `length` allocated clusters starting at `cluster` that no
+    /// directory entry leads to
+    LostChain { cluster: u32, length: u32 },
+    /// The `.` or `..` entry of a subdirectory, which should point at
+    /// `cluster`, is missing or wrong
+    BadDotEntry { position: EntryPosition, cluster: u32 },
+    /// A `.` or `..` entry somewhere other than the start of a subdirectory
+    MisplacedDotEntry { position: EntryPosition },
+    /// `count` LFN entries starting at `first` that don't belong to the 8.3
+    /// entry after them
+    OrphanLfn { first: EntryPosition, count: u32 },
+    /// Copy `fat` of the FAT differs from the first one, starting with the
+    /// entry for `cluster`
+    FatCopyMismatch { fat: u8, cluster: u32 },
+    /// The raw counts in the FSInfo sector don't match the `free_clusters`
+    /// counted in the FAT, or point outside of the volume
+    InvalidFsInfo { free_cluster_count: u32, next_free_cluster: u32, free_clusters: u32 },
+    /// The directory is nested too deeply for its contents to be checked
+    TooDeep { entry: EntryPosition }
+}
+
+/// Totals from a run of `check`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    pub files: u32,
+    /// Directories found, the root directory included
+    pub directories: u32,
+    pub free_clusters: u32,
+    /// Allocated clusters that no chain from a directory entry leads to
+    pub lost_clusters: u32,
+    /// Number of findings reported
+    pub findings: u32
+}
+
+impl CheckReport {
+    /// Whether no problems were found.
+    pub fn is_consistent(&self) -> bool {
+        self.findings == 0
+    }
+}
+
+/// Check that the volume is consistent, calling `report` with each problem
+/// that's found.
+///
+/// Nothing is written to the volume. Memory use doesn't depend on its size:
+/// clusters are tracked in fixed windows, and the FAT and directory tree are
+/// walked again for each window, so large volumes take several passes.
+/// `check_with_buffer` takes fewer.
+pub fn check<B, T, F>(fat32: &mut Fat32<B, T>, report: F) -> CheckReport
+    where B: BlockAccessor, T: TimeSource, F: FnMut(Finding)
+{
+    let mut buffer = [0; CHECK_BUFFER_BYTES];
+    check_with_buffer(fat32, &mut buffer, report)
+}
+
+/// Like `check`, but tracking clusters in `buffer`. Each pass covers
+/// `buffer.len() / 3 * 8` clusters, so a buffer of `3 * (cluster_count / 8
+/// + 1)` bytes checks the whole volume in one.
+///
+/// Panics if `buffer` is shorter than 3 bytes.
+pub fn check_with_buffer<B, T, F>(fat32: &mut Fat32<B, T>, buffer: &mut [u8], report: F) -> CheckReport
+    where B: BlockAccessor, T: TimeSource, F: FnMut(Finding)
+{
+    assert!(buffer.len() >= 3);
+
+    let mut checker = Checker {
+        fat32,
+        report,
+        window: Window::new(buffer),
+        fat_cache: FatCache::new(),
+        first_pass: true,
+        incomplete: false,
+        summary: CheckReport::default()
+    };
+
+    checker.check_fat_copies();
+
+    let window_clusters = checker.window.clusters();
+    let end_cluster = checker.fat32.cluster_count() + 2;
+    let mut start_cluster = 2;
+    while start_cluster < end_cluster {
+        let window_end = u32::min(start_cluster.saturating_add(window_clusters), end_cluster);
+        checker.window.move_to(start_cluster, window_end);
+        checker.link_fat_entries();
+        checker.walk_tree();
+        checker.find_lost_chains();
+
+        checker.first_pass = false;
+        start_cluster = window_end;
+    }
+
+    checker.check_fs_info();
+    checker.summary
+}
+
+struct Checker<'a, 'b, B: 'a, T: 'a, F>
+    where B: BlockAccessor
+{
+    fat32: &'a mut Fat32<B, T>,
+    report: F,
+    window: Window<'b>,
+    fat_cache: FatCache,
+    /// Problems that don't depend on the window are only reported on the
+    /// first pass
+    first_pass: bool,
+    /// Set when part of the directory tree couldn't be walked, so clusters
+    /// can't be called lost
+    incomplete: bool,
+    summary: CheckReport
+}
+
+impl<'a, 'b, B: BlockAccessor, T: TimeSource, F: FnMut(Finding)> Checker<'a, 'b, B, T, F> {
+    fn found(&mut self, finding: Finding) {
+        self.summary.findings += 1;
+        (self.report)(finding);
+    }
+
+    fn found_once(&mut self, finding: Finding) {
+        if self.first_pass {
+            self.found(finding);
+        }
+    }
+
+    /// Compare each copy of the FAT with the first one. Copies that aren't
+    /// kept in sync because mirroring is disabled are skipped.
+    fn check_fat_copies(&mut self) {
+        let bpb = &self.fat32.boot_sector.bpb;
+        if bpb.is_mirroring_disabled() {
+            return;
+        }
+        let number_of_fats = bpb.number_of_fats;
+
+        let fat_type = self.fat32.fat_type();
+        let fat_bytes = fat_type.entry_offset(self.fat32.cluster_count() + 1) + fat_type.entry_bytes() as u64;
+        let mut first = [0; FAT_SCAN_CHUNK];
+        let mut copy = [0; FAT_SCAN_CHUNK];
+
+        for fat_num in 1..number_of_fats {
+            let mut offset = 0;
+            while offset < fat_bytes {
+                let length = u64::min(FAT_SCAN_CHUNK as u64, fat_bytes - offset) as usize;
+                let first_offset = self.fat32.fat_copy_offset(0) + offset;
+                self.fat32.read_volume(first_offset, &mut first[..length]);
+                let copy_offset = self.fat32.fat_copy_offset(fat_num) + offset;
+                self.fat32.read_volume(copy_offset, &mut copy[..length]);
+
+                if let Some(position) = (0..length).position(|i| first[i] != copy[i]) {
+                    let byte = offset + position as u64;
+                    let cluster = match fat_type {
+                        FatType::Fat12 => byte * 2 / 3,
+                        FatType::Fat16 => byte / 2,
+                        FatType::Fat32 => byte / 4
+                    };
+                    self.found(Finding::FatCopyMismatch { fat: fat_num, cluster: cluster as u32 });
+                    break;
+                }
+
+                offset += length as u64;
+            }
+        }
+    }
+
+    /// Count how many FAT entries point at each cluster in the window, and
+    /// on the first pass the free clusters.
+    fn link_fat_entries(&mut self) {
+        let first_pass = self.first_pass;
+        let window = &mut self.window;
+        let free_clusters = &mut self.summary.free_clusters;
+
+        let end_cluster = self.fat32.cluster_count() + 2;
+        self.fat32.scan_fat(2, end_cluster, |_, entry| {
+            match entry {
+                FatEntry::Free if first_pass => *free_clusters += 1,
+                FatEntry::Next(next_cluster) => window.link(next_cluster),
+                _ => {}
+            }
+            true
+        });
+    }
+
+    /// Walk every directory, checking its entries and marking the clusters
+    /// of everything in it that fall in the window.
+    fn walk_tree(&mut self) {
+        let root = if self.fat32.fat_type() == FatType::Fat32 {
+            let root_cluster = self.fat32.boot_sector.bpb.root_directory_cluster;
+            let chain = self.follow_chain(root_cluster);
+            self.check_chain(None, root_cluster, &chain);
+            Frame::new(None, root_cluster, 0, chain.length)
+        } else {
+            // The fixed root directory region
+            Frame::new(None, 0, 0, 1)
+        };
+        if self.first_pass {
+            self.summary.directories += 1;
+        }
+
+        // The directories being walked, from the root down
+        let mut stack = [root; MAX_CHECK_DEPTH];
+        let mut depth = 1;
+
+        while depth > 0 {
+            let mut frame = stack[depth - 1];
+            depth -= 1;
+            let child = match self.walk_directory(&mut frame) {
+                Some(child) => child,
+                None => continue
+            };
+
+            // A directory that's its own ancestor is cross-linked, which is
+            // already reported, and would be walked forever
+            let is_ancestor = child.cluster == frame.cluster ||
+                              stack[..depth].iter().any(|ancestor| ancestor.cluster == child.cluster);
+            let too_deep = depth + 2 > MAX_CHECK_DEPTH;
+            if too_deep && !is_ancestor {
+                self.incomplete = true;
+                if let Some(entry) = child.entry {
+                    self.found_once(Finding::TooDeep { entry });
+                }
+            }
+
+            stack[depth] = frame;
+            depth += 1;
+            if !is_ancestor && !too_deep {
+                stack[depth] = child;
+                depth += 1;
+            }
+        }
+    }
+
+    /// Check the entries of a directory, continuing from where `frame` left
+    /// off. Returns the next subdirectory to walk into, leaving `frame` at
+    /// the entry after it, or `None` once the directory is done.
+    fn walk_directory(&mut self, frame: &mut Frame) -> Option<Frame> {
+        loop {
+            let position = match frame.position {
+                Some(position) => position,
+                None => {
+                    self.end_directory(frame);
+                    return None;
+                }
+            };
+            let bytes = self.fat32.read_entry(position);
+            frame.position = self.position_after(frame, position);
+            let index = frame.entries_read;
+            frame.entries_read += 1;
+
+            let entry = match Entry::with_type(&bytes, self.fat32.fat_type()) {
+                Entry::DirectoryEntry(entry) => entry,
+                Entry::Lfn(entry) => {
+                    frame.long_name.add(position, &entry);
+                    self.check_dot_entry(frame, index, None);
+                    continue;
+                },
+                Entry::Empty => {
+                    self.end_long_name(frame, false);
+                    self.check_dot_entry(frame, index, None);
+                    continue;
+                },
+                Entry::Last => {
+                    frame.position = None;
+                    self.end_directory(frame);
+                    return None;
+                }
+            };
+
+            let is_label = entry.flags.contains(DirectoryEntryFlags::VOLUME_LABEL);
+            let long_name_matches = !is_label && frame.long_name.matches(entry.checksum());
+            self.end_long_name(frame, long_name_matches);
+
+            self.check_dot_entry(frame, index, Some(&entry));
+            if entry.is_dot_entry() {
+                if frame.entry.is_none() || index >= 2 {
+                    self.found_once(Finding::MisplacedDotEntry { position });
+                }
+                continue;
+            }
+            if is_label {
+                continue;
+            }
+
+            let chain = self.follow_chain(entry.cluster_num);
+            if entry.flags.contains(DirectoryEntryFlags::SUBDIRECTORY) {
+                if self.first_pass {
+                    self.summary.directories += 1;
+                }
+                self.check_chain(Some(position), entry.cluster_num, &chain);
+                if chain.length > 0 {
+                    // `..` entries use 0 for the root directory
+                    let parent = if frame.entry.is_some() { frame.cluster } else { 0 };
+                    return Some(Frame::new(Some(position), entry.cluster_num, parent, chain.length));
+                }
+            } else {
+                if self.first_pass {
+                    self.summary.files += 1;
+                }
+                if entry.cluster_num != 0 {
+                    self.check_chain(Some(position), entry.cluster_num, &chain);
+                }
+
+                let bytes_per_cluster = self.fat32.bytes_per_cluster();
+                let clusters_needed = if entry.size == 0 { 0 } else { (entry.size - 1) / bytes_per_cluster + 1 };
+                let clusters = if entry.cluster_num == 0 { 0 } else { chain.length };
+                if clusters != clusters_needed {
+                    self.found_once(Finding::SizeMismatch { entry: position, size: entry.size, clusters });
+                }
+            }
+        }
+    }
+
+    /// Position of the entry after `position` in the directory of `frame`,
+    /// staying within the clusters its chain was found to have.
+    fn position_after(&mut self, frame: &mut Frame, position: EntryPosition) -> Option<EntryPosition> {
+        let next = self.fat32.entry_after(position)?;
+        if next.cluster != position.cluster {
+            if frame.clusters_left <= 1 {
+                return None;
+            }
+            frame.clusters_left -= 1;
+        }
+        Some(next)
+    }
+
+    /// Check that the first two entries of a subdirectory are its `.` and
+    /// `..` entries.
+    fn check_dot_entry(&mut self, frame: &Frame, index: u32, entry: Option<&DirectoryEntry>) {
+        if frame.entry.is_none() || index >= 2 {
+            return;
+        }
+
+        let (name, cluster) = if index == 0 {
+            (b".       ", frame.cluster)
+        } else {
+            (b"..      ", frame.parent)
+        };
+        let is_valid = match entry {
+            Some(entry) => entry.file_name_bytes == *name && entry.file_extension_bytes == *b"   " &&
+                           entry.flags.contains(DirectoryEntryFlags::SUBDIRECTORY) &&
+                           entry.cluster_num == cluster,
+            None => false
+        };
+
+        if !is_valid {
+            let position = EntryPosition { cluster: frame.cluster, index };
+            self.found_once(Finding::BadDotEntry { position, cluster });
+        }
+    }
+
+    fn end_long_name(&mut self, frame: &mut Frame, matches: bool) {
+        if let Some(finding) = frame.long_name.end(matches) {
+            self.found_once(finding);
+        }
+    }
+
+    fn end_directory(&mut self, frame: &mut Frame) {
+        self.end_long_name(frame, false);
+        // A subdirectory that ends before its dot entries is missing them
+        while frame.entries_read < 2 {
+            let index = frame.entries_read;
+            frame.entries_read += 1;
+            self.check_dot_entry(frame, index, None);
+        }
+    }
+
+    /// Report a chain that doesn't end properly, and mark its clusters in
+    /// the window.
+    fn check_chain(&mut self, entry: Option<EntryPosition>, first_cluster: u32, chain: &Chain) {
+        if chain.end != ChainEnd::EndOfChain {
+            self.found_once(Finding::BrokenChain { entry, length: chain.length });
+        }
+
+        // The entry where a looped chain joins itself is pointed at twice,
+        // but isn't a cross-link
+        let loop_start = match chain.end {
+            ChainEnd::Looped { start } => Some(start),
+            _ => None
+        };
+
+        let mut cluster_num = first_cluster;
+        for chain_index in 0..chain.length {
+            if let Some(bit) = self.window.bit(cluster_num) {
+                // Another chain leads here if this is the start of the chain
+                // and anything else points at it, or if more than one entry
+                // points at a later cluster
+                let is_cross_linked = if chain_index == 0 {
+                    self.window.used.get(bit) || self.window.linked.get(bit)
+                } else {
+                    self.window.linked_twice.get(bit)
+                };
+                if is_cross_linked && loop_start != Some(chain_index) {
+                    self.found(Finding::CrossLink { entry, cluster: cluster_num });
+                }
+                self.window.used.set(bit);
+            }
+
+            if chain_index + 1 < chain.length {
+                cluster_num = self.cluster_after(cluster_num).unwrap_or(cluster_num);
+            }
+        }
+    }
+
+    /// Report the allocated clusters in the window that weren't reached from
+    /// any directory entry, as the chains they start.
+    fn find_lost_chains(&mut self) {
+        if self.incomplete {
+            return;
+        }
+
+        let window = &mut self.window;
+        let start_cluster = window.start;
+        let mut lost_clusters = 0;
+
+        // Free and bad clusters are marked as used too, so the clusters left
+        // are the lost ones
+        self.fat32.scan_fat(window.start, window.end, |cluster_num, entry| {
+            let bit = cluster_num - start_cluster;
+            if entry == FatEntry::Free || entry == FatEntry::Bad {
+                window.used.set(bit);
+            } else if !window.used.get(bit) {
+                lost_clusters += 1;
+            }
+            true
+        });
+        self.summary.lost_clusters += lost_clusters;
+
+        // Chains start at lost clusters that nothing points at
+        for cluster_num in self.window.start..self.window.end {
+            let bit = cluster_num - start_cluster;
+            if !self.window.used.get(bit) && !self.window.linked.get(bit) {
+                let length = self.follow_chain(cluster_num).length;
+                self.found(Finding::LostChain { cluster: cluster_num, length });
+                self.mark_chain(cluster_num, length);
+            }
+        }
+
+        // The rest either belong to a chain that starts in another window,
+        // or go round in a loop that nothing leads into. Those loops are
+        // reported from their lowest cluster.
+        for cluster_num in self.window.start..self.window.end {
+            if self.window.used.get(cluster_num - start_cluster) {
+                continue;
+            }
+
+            let chain = self.follow_chain(cluster_num);
+            if chain.end == (ChainEnd::Looped { start: 0 }) && self.is_lone_loop(cluster_num, chain.length) {
+                self.found(Finding::LostChain { cluster: cluster_num, length: chain.length });
+            }
+            self.mark_chain(cluster_num, chain.length);
+        }
+    }
+
+    /// Whether the loop of `length` clusters through `first_cluster` starts
+    /// at its lowest cluster, and nothing else in the window leads into it.
+    fn is_lone_loop(&mut self, first_cluster: u32, length: u32) -> bool {
+        let mut cluster_num = first_cluster;
+        for _ in 0..length {
+            let joined = match self.window.bit(cluster_num) {
+                Some(bit) => self.window.linked_twice.get(bit),
+                None => false
+            };
+            if cluster_num < first_cluster || joined {
+                return false;
+            }
+            cluster_num = self.cluster_after(cluster_num).unwrap_or(cluster_num);
+        }
+        true
+    }
+
+    /// Mark `length` clusters of the chain starting at `first_cluster` in
+    /// the window as accounted for.
+    fn mark_chain(&mut self, first_cluster: u32, length: u32) {
+        let mut cluster_num = first_cluster;
+        for chain_index in 0..length {
+            if let Some(bit) = self.window.bit(cluster_num) {
+                self.window.used.set(bit);
+            }
+
+            if chain_index + 1 < length {
+                cluster_num = self.cluster_after(cluster_num).unwrap_or(cluster_num);
+            }
+        }
+    }
+
+    /// Compare the counts in the FSInfo sector with the FAT.
+    fn check_fs_info(&mut self) {
+        let offset = match self.fat32.fs_info_offset() {
+            Some(offset) => offset,
+            None => return
+        };
+
+        let mut bytes = [0; BOOT_SECTOR_SIZE];
+        self.fat32.read_volume(offset, &mut bytes);
+        if FsInfo::new(&bytes, self.fat32.cluster_count()).is_none() {
+            return;
+        }
+
+        let counts = FS_INFO_COUNTS_OFFSET as usize;
+        let free_cluster_count = little_endian_to_int(&bytes[counts..counts+4]);
+        let next_free_cluster = little_endian_to_int(&bytes[counts+4..counts+8]);
+        let free_clusters = self.summary.free_clusters;
+
+        let free_count_valid = free_cluster_count == FS_INFO_UNKNOWN || free_cluster_count == free_clusters;
+        let next_free_valid = next_free_cluster == FS_INFO_UNKNOWN || self.fat32.is_valid_cluster(next_free_cluster);
+        if !free_count_valid || !next_free_valid {
+            self.found(Finding::InvalidFsInfo { free_cluster_count, next_free_cluster, free_clusters });
+        }
+    }
+
+    /// Read the FAT entry of `cluster_num` through the cached FAT sector.
+    fn fat_entry(&mut self, cluster_num: u32) -> FatEntry {
+        self.fat_cache.entry(self.fat32, cluster_num)
+    }
+
+    fn cluster_after(&mut self, cluster_num: u32) -> Option<u32> {
+        match self.fat_entry(cluster_num) {
+            FatEntry::Next(next_cluster) => Some(next_cluster),
+            _ => None
+        }
+    }
+
+    /// The cluster after `cluster_num` in its chain, or how the chain ends
+    /// there and how many clusters that leaves it with from `cluster_num` on.
+    /// A free or bad cluster isn't part of the chain that runs into it, but
+    /// one that points out of range is.
+    fn next_in_chain(&mut self, cluster_num: u32) -> Result<u32, (ChainEnd, u32)> {
+        match self.fat_entry(cluster_num) {
+            FatEntry::Next(next_cluster) => Ok(next_cluster),
+            FatEntry::EndOfChain => Err((ChainEnd::EndOfChain, 1)),
+            FatEntry::Free | FatEntry::Bad => Err((ChainEnd::Broken, 0)),
+            _ => Err((ChainEnd::Broken, 1))
+        }
+    }
+
+    /// Follow the chain starting at `first_cluster`, finding loops with
+    /// Brent's algorithm so no memory is needed for the clusters already
+    /// seen.
+    fn follow_chain(&mut self, first_cluster: u32) -> Chain {
+        if !self.fat32.is_valid_cluster(first_cluster) {
+            return Chain { length: 0, end: ChainEnd::Broken };
+        }
+
+        let mut tortoise = first_cluster;
+        let mut hare = match self.next_in_chain(first_cluster) {
+            Ok(next_cluster) => next_cluster,
+            Err((end, clusters)) => return Chain { length: clusters, end }
+        };
+        // Clusters before `hare`
+        let mut length = 1;
+        let mut power = 1;
+        let mut loop_length = 1;
+
+        while tortoise != hare {
+            if power == loop_length {
+                tortoise = hare;
+                power *= 2;
+                loop_length = 0;
+            }
+            hare = match self.next_in_chain(hare) {
+                Ok(next_cluster) => next_cluster,
+                Err((end, clusters)) => return Chain { length: length + clusters, end }
+            };
+            length += 1;
+            loop_length += 1;
+        }
+
+        // Find where the loop starts by following the chain from the start
+        // and from `loop_length` clusters in at the same pace
+        let mut tortoise = first_cluster;
+        let mut hare = first_cluster;
+        for _ in 0..loop_length {
+            hare = self.cluster_after(hare).unwrap_or(hare);
+        }
+        let mut start = 0;
+        while tortoise != hare {
+            tortoise = self.cluster_after(tortoise).unwrap_or(tortoise);
+            hare = self.cluster_after(hare).unwrap_or(hare);
+            start += 1;
+        }
+
+        Chain { length: start + loop_length, end: ChainEnd::Looped { start } }
+    }
+}
+
+/// A directory being walked.
+#[derive(Clone, Copy)]
+struct Frame {
+    /// The directory's 8.3 entry, `None` for the root directory
+    entry: Option<EntryPosition>,
+    /// First cluster, 0 for the fixed root directory region
+    cluster: u32,
+    /// Cluster its `..` entry should point at
+    parent: u32,
+    /// The next entry to check
+    position: Option<EntryPosition>,
+    /// Clusters of the chain that can still be read, so looped chains end
+    clusters_left: u32,
+    entries_read: u32,
+    long_name: LongNameEntries
+}
+
+impl Frame {
+    fn new(entry: Option<EntryPosition>, cluster: u32, parent: u32, clusters: u32) -> Frame {
+        Frame {
+            entry,
+            cluster,
+            parent,
+            position: Some(EntryPosition { cluster, index: 0 }),
+            clusters_left: clusters,
+            entries_read: 0,
+            long_name: LongNameEntries::default()
+        }
+    }
+}
+
+/// The LFN entries seen since the last 8.3 entry, to find the ones that
+/// don't belong to it.
+#[derive(Clone, Copy, Default)]
+struct LongNameEntries {
+    /// The first entry and number of entries of a long name that's complete
+    /// so far
+    pending: Option<(EntryPosition, u32)>,
+    checksum: u8,
+    next_sequence_number: u8,
+    /// LFN entries that can't belong to anything
+    orphans: Option<(EntryPosition, u32)>
+}
+
+impl LongNameEntries {
+    fn add(&mut self, position: EntryPosition, entry: &LfnEntry) {
+        let sequence_number = entry.sequence_number();
+        let is_valid = sequence_number != 0 && usize::from(sequence_number) <= MAX_LFN_ENTRIES;
+
+        if is_valid && entry.is_last() {
+            self.orphan_pending();
+            self.pending = Some((position, 1));
+            self.checksum = entry.checksum;
+            self.next_sequence_number = sequence_number - 1;
+        } else if is_valid && entry.checksum == self.checksum && sequence_number == self.next_sequence_number {
+            if let Some((_, ref mut count)) = self.pending {
+                *count += 1;
+                self.next_sequence_number -= 1;
+                return;
+            }
+            self.add_orphans(position, 1);
+        } else {
+            self.orphan_pending();
+            self.add_orphans(position, 1);
+        }
+    }
+
+    /// Whether the pending long name is complete and belongs to the 8.3
+    /// name with `checksum`.
+    fn matches(&self, checksum: u8) -> bool {
+        self.pending.is_some() && self.next_sequence_number == 0 && self.checksum == checksum
+    }
+
+    /// Finish the run of LFN entries at anything that isn't one, returning
+    /// the orphans found.
+    fn end(&mut self, matches: bool) -> Option<Finding> {
+        if matches {
+            self.pending = None;
+        } else {
+            self.orphan_pending();
+        }
+
+        self.orphans.take().map(|(first, count)| Finding::OrphanLfn { first, count })
+    }
+
+    fn orphan_pending(&mut self) {
+        if let Some((first, count)) = self.pending.take() {
+            self.add_orphans(first, count);
+        }
+    }
+
+    fn add_orphans(&mut self, first: EntryPosition, count: u32) {
+        self.orphans = Some(match self.orphans {
+            Some((orphans_first, orphans)) => (orphans_first, orphans + count),
+            None => (first, count)
+        });
+    }
+}
+
+struct Bitmap<'b>(&'b mut [u8]);
+
+impl<'b> Bitmap<'b> {
+    fn clear(&mut self) {
+        for byte in self.0.iter_mut() {
+            *byte = 0;
+        }
+    }
+
+    fn get(&self, bit: u32) -> bool {
+        self.0[(bit / 8) as usize] & (1 << (bit % 8)) != 0
+    }
+
+    fn set(&mut self, bit: u32) {
+        self.0[(bit / 8) as usize] |= 1 << (bit % 8);
+    }
+}
+
+/// What's known about the clusters from `start` up to `end`.
+struct Window<'b> {
+    start: u32,
+    end: u32,
+    /// Reached from a directory entry. Once lost chains are being looked
+    /// for, anything that isn't lost.
+    used: Bitmap<'b>,
+    /// Pointed at by at least one FAT entry
+    linked: Bitmap<'b>,
+    /// Pointed at by more than one FAT entry
+    linked_twice: Bitmap<'b>
+}
+
+impl<'b> Window<'b> {
+    /// An empty window with its bitmaps in thirds of `buffer`.
+    fn new(buffer: &'b mut [u8]) -> Window<'b> {
+        let bitmap_bytes = buffer.len() / 3;
+        let (used, rest) = buffer.split_at_mut(bitmap_bytes);
+        let (linked, rest) = rest.split_at_mut(bitmap_bytes);
+
+        Window {
+            start: 0,
+            end: 0,
+            used: Bitmap(used),
+            linked: Bitmap(linked),
+            linked_twice: Bitmap(&mut rest[..bitmap_bytes])
+        }
+    }
+
+    /// Number of clusters the window can cover.
+    fn clusters(&self) -> u32 {
+        u64::min(self.used.0.len() as u64 * 8, u64::from(u32::MAX)) as u32
+    }
+
+    /// Cover the clusters from `start` up to `end`, with nothing known about
+    /// them yet.
+    fn move_to(&mut self, start: u32, end: u32) {
+        self.start = start;
+        self.end = end;
+        self.used.clear();
+        self.linked.clear();
+        self.linked_twice.clear();
+    }
+
+    /// The bit for `cluster_num`, if it's in the window.
+    fn bit(&self, cluster_num: u32) -> Option<u32> {
+        if cluster_num >= self.start && cluster_num < self.end {
+            Some(cluster_num - self.start)
+        } else {
+            None
+        }
+    }
+
+    fn link(&mut self, cluster_num: u32) {
+        if let Some(bit) = self.bit(cluster_num) {
+            if self.linked.get(bit) {
+                self.linked_twice.set(bit);
+            } else {
+                self.linked.set(bit);
+            }
+        }
+    }
+}
+
+/// The FAT sector that entries were last read from, so following a chain
+/// through nearby clusters doesn't read it again for each one.
+struct FatCache {
+    /// Byte offset of the sector in `bytes`
+    offset: Option<u64>,
+    bytes: [u8; MAX_BLOCK_SIZE]
+}
+
+impl FatCache {
+    fn new() -> FatCache {
+        FatCache {
+            offset: None,
+            bytes: [0; MAX_BLOCK_SIZE]
+        }
+    }
+
+    fn entry<B: BlockAccessor, T: TimeSource>(&mut self, fat32: &mut Fat32<B, T>, cluster_num: u32) -> FatEntry {
+        let fat_type = fat32.fat_type();
+        let entry_bytes = fat_type.entry_bytes();
+        let sector_bytes = u64::min(u64::from(fat32.bytes_per_sector()), MAX_BLOCK_SIZE as u64);
+        let entry_offset = fat32.fat_offset() + fat_type.entry_offset(cluster_num);
+        let offset = entry_offset - entry_offset % sector_bytes;
+        let position = (entry_offset - offset) as usize;
+
+        // FAT12 entries can straddle two sectors
+        if position + entry_bytes > sector_bytes as usize {
+            return fat32.fat_entry(cluster_num);
+        }
+
+        if self.offset != Some(offset) {
+            fat32.read_volume(offset, &mut self.bytes[..sector_bytes as usize]);
+            self.offset = Some(offset);
+        }
+
+        let raw_entry = fat_type.decode_entry(cluster_num, &self.bytes[position..position+entry_bytes]);
+        FatEntry::with_type(raw_entry, fat_type, fat32.cluster_count())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChainEnd {
+    EndOfChain,
+    /// A free, bad or out of range cluster
+    Broken,
+    /// The last cluster points back at the one at index `start`
+    Looped { start: u32 }
+}
+
+/// The shape of a cluster chain: how many distinct clusters it has before
+/// it ends.
+struct Chain {
+    length: u32,
+    end: ChainEnd
+}
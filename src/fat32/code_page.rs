@@ -0,0 +1,33 @@
+/// An OEM code page, used to decode the bytes of 8.3 names. Bytes below 0x80
+/// are always ASCII.
+#[derive(Debug, Clone, Copy)]
+pub struct CodePage {
+    /// The characters for bytes 0x80 to 0xFF
+    pub high_characters: &'static [char; 128]
+}
+
+impl CodePage {
+    pub fn decode(&self, byte: u8) -> char {
+        if byte < 0x80 {
+            byte as char
+        } else {
+            self.high_characters[usize::from(byte - 0x80)]
+        }
+    }
+}
+
+/// The original IBM PC code page, which DOS and most cameras and loggers use
+pub const CP437: CodePage = CodePage {
+    high_characters: &CP437_HIGH_CHARACTERS
+};
+
+const CP437_HIGH_CHARACTERS: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{A0}'
+];
@@ -0,0 +1,425 @@
+use block_accessor::{BlockAccessor, BlockAccessError};
+
+use super::{DirectoryEntryFlags, FatType, BACKUP_BOOT_SECTOR, BOOT_SECTOR_SIZE, BYTES_PER_DIRECTORY_ENTRY,
+            FS_INFO_LEAD_SIGNATURE, FS_INFO_STRUCT_SIGNATURE, FS_INFO_TRAIL_SIGNATURE, MAX_BLOCK_SIZE};
+use super::write::{is_short_name_character, to_little_endian};
+
+const NUMBER_OF_FATS: u32 = 2;
+/// The usual size of a FAT16 root directory region
+const FAT16_ROOT_ENTRIES: u32 = 512;
+/// Room for the boot sector, FSInfo sector and their backups on FAT32
+const FAT32_RESERVED_SECTORS: u32 = 32;
+const FAT16_RESERVED_SECTORS: u32 = 1;
+const FAT32_ROOT_CLUSTER: u32 = 2;
+const FAT32_INFORMATION_SECTOR: u32 = 1;
+/// Larger clusters aren't supported by most drivers, including Windows
+const MAX_CLUSTER_SIZE: u32 = 64 << 10;
+const MEDIA_DESCRIPTOR: u8 = 0xF8;
+/// Disk geometry for storage that has none, as used for SD cards
+const SECTORS_PER_TRACK: u16 = 63;
+const HEADS: u16 = 255;
+/// Cards reach 2 GiB before the SD specification moves from FAT16 to FAT32
+const MAX_FAT16_CAPACITY: u64 = 2 << 30;
+
+/// The cluster size and boundary unit the SD card file system specification
+/// gives for cards up to each capacity, all in bytes. Larger cards use the
+/// last row.
+const SD_CLUSTER_SIZES: [(u64, u32, u32); 6] = [
+    (8 << 20, 8 << 10, 8 << 10),
+    (64 << 20, 16 << 10, 16 << 10),
+    (256 << 20, 16 << 10, 32 << 10),
+    (1 << 30, 16 << 10, 64 << 10),
+    (2 << 30, 32 << 10, 64 << 10),
+    (32 << 30, 32 << 10, 4 << 20)
+];
+
+/// How `format` lays out a volume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatOptions<'a> {
+    /// First block of the volume, as given to `Fat32::mount`
+    pub physical_start_block: u32,
+    /// Size of the volume in blocks. Each block becomes a sector.
+    pub block_count: u32,
+    /// `None` to choose like the SD specification: FAT16 up to 2 GiB, and
+    /// FAT32 above that
+    pub fat_type: Option<FatType>,
+    /// Bytes per cluster, `None` to use the size from the SD specification,
+    /// made smaller or larger if the FAT type needs more or fewer clusters
+    pub cluster_size: Option<u32>,
+    /// Up to 11 characters allowed in 8.3 names, or spaces. Lowercase
+    /// letters are made uppercase.
+    pub label: Option<&'a str>,
+    pub serial_number: u32,
+    /// The data region starts on a multiple of this many bytes from the
+    /// start of the storage, so clusters don't straddle erase blocks.
+    /// `None` to use the boundary unit from the SD specification.
+    pub erase_block_size: Option<u32>
+}
+
+impl<'a> FormatOptions<'a> {
+    /// Options for a volume of `block_count` blocks at
+    /// `physical_start_block`, with everything else chosen for its size.
+    pub fn new(physical_start_block: u32, block_count: u32) -> FormatOptions<'a> {
+        FormatOptions {
+            physical_start_block,
+            block_count,
+            fat_type: None,
+            cluster_size: None,
+            label: None,
+            serial_number: 0,
+            erase_block_size: None
+        }
+    }
+}
+
+/// Why a volume couldn't be formatted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatError {
+    /// The storage's blocks are smaller than a boot sector or larger than
+    /// `MAX_BLOCK_SIZE`
+    UnsupportedBlockSize,
+    /// Only FAT16 and FAT32 volumes can be formatted
+    UnsupportedFatType,
+    /// The cluster size isn't a power of two from one block up to 64 KiB
+    InvalidClusterSize,
+    /// The erase block size isn't a multiple of the block size, or is too
+    /// large to align to
+    InvalidEraseBlockSize,
+    /// The label is empty, too long or has characters labels can't have
+    InvalidLabel,
+    /// There's no room for enough clusters for the FAT type
+    VolumeTooSmall,
+    /// The FAT type can't have as many clusters as would fit
+    VolumeTooLarge,
+    /// The underlying storage failed to write a block
+    BlockAccess(BlockAccessError)
+}
+
+impl From<BlockAccessError> for FormatError {
+    fn from(error: BlockAccessError) -> FormatError {
+        FormatError::BlockAccess(error)
+    }
+}
+
+/// Write a new, empty FAT16 or FAT32 volume: the boot sector, the FSInfo
+/// sector and backups of both on FAT32, the FATs and an empty root
+/// directory. Anything else on the volume is left as it was, but can't be
+/// found any more.
+///
+/// Only the volume is written, a partition table has to be written
+/// separately.
+pub fn format<B: BlockAccessor>(block_storage: &mut B, options: FormatOptions) -> Result<(), FormatError> {
+    let bytes_per_sector = block_storage.block_size() as u32;
+    let block_size = bytes_per_sector as usize;
+    if !(BOOT_SECTOR_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) || !block_size.is_multiple_of(BOOT_SECTOR_SIZE) {
+        return Err(FormatError::UnsupportedBlockSize);
+    }
+
+    let capacity = u64::from(options.block_count) * u64::from(bytes_per_sector);
+    let fat_type = match options.fat_type {
+        Some(FatType::Fat12) => return Err(FormatError::UnsupportedFatType),
+        Some(fat_type) => fat_type,
+        None if capacity <= MAX_FAT16_CAPACITY => FatType::Fat16,
+        None => FatType::Fat32
+    };
+
+    let &(_, sd_cluster_size, sd_boundary_unit) = SD_CLUSTER_SIZES.iter()
+        .find(|&&(max_capacity, _, _)| capacity <= max_capacity)
+        .unwrap_or(&SD_CLUSTER_SIZES[SD_CLUSTER_SIZES.len() - 1]);
+
+    let max_sectors_per_cluster = MAX_CLUSTER_SIZE / bytes_per_sector;
+    let mut sectors_per_cluster = match options.cluster_size {
+        Some(cluster_size) => {
+            let sectors_per_cluster = cluster_size / bytes_per_sector;
+            if !cluster_size.is_power_of_two() || sectors_per_cluster == 0 ||
+               sectors_per_cluster > max_sectors_per_cluster
+            {
+                return Err(FormatError::InvalidClusterSize);
+            }
+            sectors_per_cluster
+        },
+        None => u32::max(sd_cluster_size / bytes_per_sector, 1)
+    };
+
+    let erase_block_size = options.erase_block_size.unwrap_or(sd_boundary_unit);
+    if erase_block_size == 0 || !erase_block_size.is_multiple_of(bytes_per_sector) {
+        return Err(FormatError::InvalidEraseBlockSize);
+    }
+    let erase_block_sectors = u32::max(erase_block_size / bytes_per_sector, 1);
+
+    let label = match options.label {
+        Some(label) => encode_label(label)?,
+        None => *b"NO NAME    "
+    };
+
+    // A cluster size from the table is only a starting point, moved until
+    // the number of clusters suits the FAT type
+    let (min_clusters, max_clusters) = match fat_type {
+        FatType::Fat16 => (4085, 65524),
+        _ => (65525, 0x0FFF_FFF5)
+    };
+    let is_chosen = options.cluster_size.is_none();
+    let layout = loop {
+        let layout = Layout::new(fat_type, &options, bytes_per_sector, sectors_per_cluster, erase_block_sectors)?;
+        if layout.cluster_count < min_clusters {
+            if is_chosen && sectors_per_cluster > 1 {
+                sectors_per_cluster /= 2;
+                continue;
+            }
+            return Err(FormatError::VolumeTooSmall);
+        }
+        if layout.cluster_count > max_clusters {
+            if is_chosen && sectors_per_cluster < max_sectors_per_cluster {
+                sectors_per_cluster *= 2;
+                continue;
+            }
+            return Err(FormatError::VolumeTooLarge);
+        }
+        break layout;
+    };
+
+    let mut writer = SectorWriter {
+        block_storage,
+        start_block: u64::from(options.physical_start_block),
+        block_size
+    };
+
+    // Clear the old boot sector before anything else, so a format that's
+    // cut short doesn't leave something that looks like a volume
+    let zeros = [0; MAX_BLOCK_SIZE];
+    for sector in 0..layout.data_start() {
+        writer.write(sector, &zeros[..block_size])?;
+    }
+    for sector in 0..layout.root_directory_sectors() {
+        writer.write(layout.root_directory_start() + sector, &zeros[..block_size])?;
+    }
+
+    let mut sector = [0; MAX_BLOCK_SIZE];
+    let sector = &mut sector[..block_size];
+    layout.write_fat_start(sector);
+    for fat in 0..NUMBER_OF_FATS {
+        writer.write(layout.reserved_sectors + fat * layout.sectors_per_fat, sector)?;
+    }
+
+    if options.label.is_some() {
+        let mut sector = [0; MAX_BLOCK_SIZE];
+        sector[0..11].copy_from_slice(&label);
+        sector[0x0B] = DirectoryEntryFlags::VOLUME_LABEL.bits();
+        writer.write(layout.root_directory_start(), &sector[..block_size])?;
+    }
+
+    let mut boot_sector = [0; MAX_BLOCK_SIZE];
+    layout.write_boot_sector(&options, &label, &mut boot_sector);
+    if fat_type == FatType::Fat32 {
+        let mut fs_info = [0; MAX_BLOCK_SIZE];
+        layout.write_fs_info(&mut fs_info);
+        // The third boot sector only holds boot code, which there isn't any
+        // of beyond the signature
+        let mut boot_code = [0; MAX_BLOCK_SIZE];
+        boot_code[510] = 0x55;
+        boot_code[511] = 0xAA;
+
+        // The backup first, so the primary boot sector is the last thing
+        // written
+        for &first_sector in &[BACKUP_BOOT_SECTOR as u32, 0] {
+            writer.write(first_sector + 2, &boot_code[..block_size])?;
+            writer.write(first_sector + FAT32_INFORMATION_SECTOR, &fs_info[..block_size])?;
+            writer.write(first_sector, &boot_sector[..block_size])?;
+        }
+    } else {
+        writer.write(0, &boot_sector[..block_size])?;
+    }
+
+    Ok(())
+}
+
+/// Check a label and pad it with spaces.
+fn encode_label(label: &str) -> Result<[u8; 11], FormatError> {
+    let is_valid = label.bytes().all(|ch| ch == b' ' || is_short_name_character(ch));
+    if label.trim().is_empty() || label.len() > 11 || !is_valid {
+        return Err(FormatError::InvalidLabel);
+    }
+
+    let mut encoded = [b' '; 11];
+    for (byte, ch) in encoded.iter_mut().zip(label.bytes()) {
+        *byte = ch.to_ascii_uppercase();
+    }
+    Ok(encoded)
+}
+
+/// Where everything goes on a volume being formatted, in sectors from its
+/// start.
+struct Layout {
+    fat_type: FatType,
+    bytes_per_sector: u32,
+    sector_count: u32,
+    sectors_per_cluster: u32,
+    reserved_sectors: u32,
+    sectors_per_fat: u32,
+    root_region_sectors: u32,
+    cluster_count: u32
+}
+
+impl Layout {
+    fn new(fat_type: FatType, options: &FormatOptions, bytes_per_sector: u32, sectors_per_cluster: u32,
+           erase_block_sectors: u32) -> Result<Layout, FormatError>
+    {
+        let sector_count = options.block_count;
+        let (min_reserved_sectors, root_region_sectors) = match fat_type {
+            FatType::Fat32 => (FAT32_RESERVED_SECTORS, 0),
+            _ => (FAT16_RESERVED_SECTORS, FAT16_ROOT_ENTRIES * BYTES_PER_DIRECTORY_ENTRY / bytes_per_sector)
+        };
+
+        // Sized as if every sector was a cluster, which is a little more
+        // than needed but never too little
+        let max_cluster_count = u64::from(sector_count / sectors_per_cluster);
+        let fat_bytes = (max_cluster_count + 2) * fat_type.entry_bytes() as u64;
+        let sectors_per_fat = if fat_bytes == 0 { 0 } else { (fat_bytes - 1) / u64::from(bytes_per_sector) + 1 };
+
+        // Extra reserved sectors move the data region onto an erase block
+        // boundary
+        let data_start = u64::from(min_reserved_sectors) + u64::from(NUMBER_OF_FATS) * sectors_per_fat +
+                         u64::from(root_region_sectors);
+        let misalignment = (u64::from(options.physical_start_block) + data_start) % u64::from(erase_block_sectors);
+        let padding = if misalignment == 0 { 0 } else { u64::from(erase_block_sectors) - misalignment };
+        let reserved_sectors = u64::from(min_reserved_sectors) + padding;
+        if reserved_sectors > u64::from(u16::MAX) {
+            return Err(FormatError::InvalidEraseBlockSize);
+        }
+
+        let data_start = data_start + padding;
+        if data_start >= u64::from(sector_count) {
+            return Err(FormatError::VolumeTooSmall);
+        }
+
+        Ok(Layout {
+            fat_type,
+            bytes_per_sector,
+            sector_count,
+            sectors_per_cluster,
+            reserved_sectors: reserved_sectors as u32,
+            sectors_per_fat: sectors_per_fat as u32,
+            root_region_sectors,
+            cluster_count: (sector_count - data_start as u32) / sectors_per_cluster
+        })
+    }
+
+    fn data_start(&self) -> u32 {
+        self.reserved_sectors + NUMBER_OF_FATS * self.sectors_per_fat + self.root_region_sectors
+    }
+
+    /// The first sector of the root directory, in the root region on FAT16
+    /// or its first cluster on FAT32.
+    fn root_directory_start(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat32 => self.data_start() + (FAT32_ROOT_CLUSTER - 2) * self.sectors_per_cluster,
+            _ => self.reserved_sectors + NUMBER_OF_FATS * self.sectors_per_fat
+        }
+    }
+
+    /// Sectors of the root directory that need clearing which aren't
+    /// before the data region, and so already cleared along with the FATs.
+    fn root_directory_sectors(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat32 => self.sectors_per_cluster,
+            _ => 0
+        }
+    }
+
+    /// Fill in the reserved entries at the start of a FAT: the media
+    /// descriptor, the volume flags marked clean and on FAT32 the end of the
+    /// root directory's chain.
+    fn write_fat_start(&self, sector: &mut [u8]) {
+        match self.fat_type {
+            FatType::Fat32 => {
+                sector[0..4].copy_from_slice(&to_little_endian(0x0FFF_FF00 | u32::from(MEDIA_DESCRIPTOR)));
+                sector[4..8].copy_from_slice(&to_little_endian(0x0FFF_FFFF));
+                sector[8..12].copy_from_slice(&to_little_endian(0x0FFF_FFFF));
+            },
+            _ => {
+                sector[0..2].copy_from_slice(&[MEDIA_DESCRIPTOR, 0xFF]);
+                sector[2..4].copy_from_slice(&[0xFF, 0xFF]);
+            }
+        }
+    }
+
+    /// Fill in the FSInfo sector, with every cluster free but the root
+    /// directory's.
+    fn write_fs_info(&self, sector: &mut [u8]) {
+        sector[0..4].copy_from_slice(&to_little_endian(FS_INFO_LEAD_SIGNATURE));
+        sector[484..488].copy_from_slice(&to_little_endian(FS_INFO_STRUCT_SIGNATURE));
+        sector[488..492].copy_from_slice(&to_little_endian(self.cluster_count - 1));
+        sector[492..496].copy_from_slice(&to_little_endian(FAT32_ROOT_CLUSTER + 1));
+        sector[508..512].copy_from_slice(&to_little_endian(FS_INFO_TRAIL_SIGNATURE));
+    }
+
+    fn write_boot_sector(&self, options: &FormatOptions, label: &[u8; 11], sector: &mut [u8]) {
+        let is_fat32 = self.fat_type == FatType::Fat32;
+        // Jump over the BPB to boot code that gives up and tries the next
+        // device, with `int 18h`
+        let boot_code = if is_fat32 { 0x5A } else { 0x3E };
+        sector[0..3].copy_from_slice(&[0xEB, boot_code - 2, 0x90]);
+        sector[boot_code as usize..boot_code as usize + 2].copy_from_slice(&[0xCD, 0x18]);
+        sector[3..11].copy_from_slice(b"MSWIN4.1");
+
+        // DOS 2.0 BPB
+        set_u16(sector, 11, self.bytes_per_sector);
+        sector[13] = self.sectors_per_cluster as u8;
+        set_u16(sector, 14, self.reserved_sectors);
+        sector[16] = NUMBER_OF_FATS as u8;
+        if !is_fat32 {
+            set_u16(sector, 17, FAT16_ROOT_ENTRIES);
+            set_u16(sector, 22, self.sectors_per_fat);
+        }
+        let small_sector_count = !is_fat32 && self.sector_count <= u32::from(u16::MAX);
+        if small_sector_count {
+            set_u16(sector, 19, self.sector_count);
+        }
+        sector[21] = MEDIA_DESCRIPTOR;
+
+        // DOS 3.31 BPB
+        set_u16(sector, 24, u32::from(SECTORS_PER_TRACK));
+        set_u16(sector, 26, u32::from(HEADS));
+        sector[28..32].copy_from_slice(&to_little_endian(options.physical_start_block));
+        if !small_sector_count {
+            sector[32..36].copy_from_slice(&to_little_endian(self.sector_count));
+        }
+
+        // The DOS 7.1 EBPB on FAT32, which is followed by the same fields as
+        // the DOS 4.0 EBPB on FAT16
+        let ebpb = if is_fat32 {
+            sector[36..40].copy_from_slice(&to_little_endian(self.sectors_per_fat));
+            sector[44..48].copy_from_slice(&to_little_endian(FAT32_ROOT_CLUSTER));
+            set_u16(sector, 48, FAT32_INFORMATION_SECTOR);
+            set_u16(sector, 50, BACKUP_BOOT_SECTOR as u32);
+            64
+        } else {
+            36
+        };
+        sector[ebpb] = 0x80;
+        sector[ebpb + 2] = 0x29;
+        sector[ebpb + 3..ebpb + 7].copy_from_slice(&to_little_endian(options.serial_number));
+        sector[ebpb + 7..ebpb + 18].copy_from_slice(label);
+        sector[ebpb + 18..ebpb + 26].copy_from_slice(if is_fat32 { b"FAT32   " } else { b"FAT16   " });
+
+        sector[510] = 0x55;
+        sector[511] = 0xAA;
+    }
+}
+
+fn set_u16(sector: &mut [u8], offset: usize, value: u32) {
+    sector[offset..offset + 2].copy_from_slice(&to_little_endian(value)[..2]);
+}
+
+/// Writes whole sectors of the volume, which are the storage's blocks.
+struct SectorWriter<'a, B: 'a> {
+    block_storage: &'a mut B,
+    start_block: u64,
+    block_size: usize
+}
+
+impl<'a, B: BlockAccessor> SectorWriter<'a, B> {
+    fn write(&mut self, sector: u32, bytes: &[u8]) -> Result<(), BlockAccessError> {
+        self.block_storage.write_block(self.start_block + u64::from(sector), &bytes[..self.block_size])
+    }
+}
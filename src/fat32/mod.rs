@@ -0,0 +1,1817 @@
+use heapless::{String};
+use heapless::consts::U765;
+use byte_util::{little_endian_to_int, take_from_slice, taken_from_slice};
+use block_accessor::{BlockAccessor, BlockAccessError};
+use chain;
+use path;
+
+mod check;
+mod code_page;
+mod format;
+mod repair;
+mod time;
+mod write;
+
+pub use chain::{ChainError, ClusterChains, SeekFrom};
+pub use self::check::{check, check_with_buffer, CheckReport, Finding};
+pub use self::repair::{repair, Change, RepairOptions};
+pub use self::code_page::{CodePage, CP437};
+pub use self::format::{format, FormatError, FormatOptions};
+pub use self::time::{DateTime, TimeSource, FixedTime};
+pub use self::write::FileWriter;
+
+/// The sector size this crate used to assume for every volume
+#[deprecated(note = "volumes can have other sector sizes, use `Fat32::bytes_per_sector`")]
+pub const BYTES_PER_BLOCK: u32 = 512;
+/// Largest device block size that can be used as backing storage
+pub const MAX_BLOCK_SIZE: usize = 4096;
+const BOOT_SECTOR_SIZE: usize = 512;
+const BYTES_PER_DIRECTORY_ENTRY: u32 = 32;
+/// Number of FAT entries checked per read when scanning the FAT
+const FAT_SCAN_CHUNK: usize = 512;
+
+/// The most UTF-16 code units a long file name can have
+const MAX_LFN_LENGTH: usize = 255;
+const LFN_CHARACTERS_PER_ENTRY: usize = 13;
+/// Enough LFN entries to hold the longest name
+const MAX_LFN_ENTRIES: usize = 20;
+
+/// The name of a file or directory. Long names are up to 255 UTF-16 code
+/// units, which take at most 765 bytes as UTF-8.
+pub type Name = String<U765>;
+
+pub struct Fat32<B, T = FixedTime> where B: BlockAccessor {
+    pub block_storage: B,
+    pub physical_start_block: u32,
+    pub boot_sector: BootSector,
+    /// The code page 8.3 names are decoded with
+    pub code_page: CodePage,
+    /// The clock used to timestamp items that are created or changed
+    pub time_source: T,
+    /// Where to start looking for a free cluster when allocating
+    free_cluster_hint: u32,
+    /// The FSInfo sector as it should be on disk, `None` if the volume
+    /// doesn't have a valid one
+    fs_info: Option<FsInfo>,
+    mounted_from_backup: bool,
+    /// The volume flags in FAT[1] when the volume was mounted
+    cleanly_unmounted: bool,
+    hard_error: bool,
+    /// Whether the clean shutdown flag is cleared on disk, either because
+    /// it was at mount or because something has been written since
+    marked_dirty: bool
+}
+
+impl<B: BlockAccessor> Fat32<B> {
+    /// Mount the volume starting at `physical_start_block`. Anything written
+    /// is timestamped 1980-01-01, use `mount_with_time_source` to give a
+    /// clock.
+    pub fn mount(block_storage: B, physical_start_block: u32) -> Result<Fat32<B>, MountError> {
+        Fat32::mount_with_time_source(block_storage, physical_start_block, FixedTime::default())
+    }
+
+    /// Like `mount`, but panics if the volume can't be mounted.
+    pub fn new(block_storage: B, physical_start_block: u32) -> Fat32<B> {
+        Fat32::with_time_source(block_storage, physical_start_block, FixedTime::default())
+    }
+}
+
+impl<B: BlockAccessor, T: TimeSource> Fat32<B, T> {
+    /// Mount the volume starting at `physical_start_block`, checking that
+    /// its boot sector describes a volume that can exist.
+    pub fn mount_with_time_source(mut block_storage: B, physical_start_block: u32, time_source: T)
+        -> Result<Fat32<B, T>, MountError>
+    {
+        let block_size = block_storage.block_size() as usize;
+        if !(BOOT_SECTOR_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) || !block_size.is_multiple_of(BOOT_SECTOR_SIZE) {
+            return Err(MountError::UnsupportedBlockSize);
+        }
+
+        let (boot_sector, mounted_from_backup) =
+            match read_boot_sector(&mut block_storage, physical_start_block, 0) {
+                Ok(boot_sector) => (boot_sector, false),
+                Err(error) => match find_backup_boot_sector(&mut block_storage, physical_start_block) {
+                    Some(boot_sector) => (boot_sector, true),
+                    None => return Err(error)
+                }
+            };
+
+        let mut fat32 = Fat32 {
+            block_storage,
+            physical_start_block,
+            boot_sector,
+            code_page: CP437,
+            time_source,
+            free_cluster_hint: 2,
+            fs_info: None,
+            mounted_from_backup,
+            cleanly_unmounted: true,
+            hard_error: false,
+            marked_dirty: false
+        };
+
+        if let Some((clean_bit, error_bit)) = fat32.fat_type().volume_flag_bits() {
+            let flags = fat32.volume_flags();
+            fat32.cleanly_unmounted = flags & clean_bit != 0;
+            fat32.hard_error = flags & error_bit == 0;
+            fat32.marked_dirty = !fat32.cleanly_unmounted;
+        }
+
+        fat32.fs_info = fat32.read_fs_info();
+        if let Some(next_free_cluster) = fat32.fs_info.and_then(|info| info.next_free_cluster) {
+            fat32.free_cluster_hint = next_free_cluster;
+        }
+        Ok(fat32)
+    }
+
+    /// Like `mount_with_time_source`, but panics if the volume can't be
+    /// mounted.
+    pub fn with_time_source(block_storage: B, physical_start_block: u32, time_source: T)
+        -> Fat32<B, T>
+    {
+        match Fat32::mount_with_time_source(block_storage, physical_start_block, time_source) {
+            Ok(fat32) => fat32,
+            Err(error) => panic!("Couldn't mount the volume: {:?}", error)
+        }
+    }
+
+    /// Whether the primary boot sector was damaged, so the volume was
+    /// mounted using the backup boot sector instead. Copying the backup over
+    /// the primary boot sector goes back to using the primary.
+    pub fn mounted_from_backup(&self) -> bool {
+        self.mounted_from_backup
+    }
+
+    /// Whether the volume was unmounted cleanly the last time it was used,
+    /// according to the flag in FAT[1]. When it wasn't, writes may have been
+    /// cut short and the volume should be checked. FAT12 has no flag, so
+    /// it's always reported as clean.
+    pub fn was_cleanly_unmounted(&self) -> bool {
+        self.cleanly_unmounted
+    }
+
+    /// Whether the last driver to use the volume flagged it as having had a
+    /// disk I/O error.
+    pub fn had_hard_error(&self) -> bool {
+        self.hard_error
+    }
+
+    /// The raw contents of FAT[1], whose top bits hold the volume flags.
+    fn volume_flags(&mut self) -> u32 {
+        let fat_type = self.fat_type();
+        let mut entry = [0; 4];
+        let entry = &mut entry[..fat_type.entry_bytes()];
+        self.read_volume(self.fat_offset() + fat_type.entry_offset(1), entry);
+        little_endian_to_int(entry)
+    }
+
+    /// The sector holding the backup boot sector, `None` if the volume
+    /// doesn't have one. Only FAT32 volumes do.
+    fn backup_boot_sector(&self) -> Option<u32> {
+        let bpb = &self.boot_sector.bpb;
+        let sector = u32::from(bpb.backup_information_sector);
+        // 0 and 0xFFFF both mean there isn't one
+        if self.fat_type() == FatType::Fat32 && sector != 0 &&
+           sector + BOOT_REGION_SECTORS <= u32::from(bpb.reserved_logical_sectors)
+        {
+            Some(sector)
+        } else {
+            None
+        }
+    }
+
+    /// Byte offset of the FSInfo sector that goes with the boot sector in
+    /// use, which only FAT32 volumes have. After mounting from the backup
+    /// boot sector, that's the backup FSInfo sector the same distance after
+    /// it.
+    fn fs_info_offset(&self) -> Option<u64> {
+        let sector = u32::from(self.boot_sector.bpb.information_sector);
+        // 0 and 0xFFFF both mean there isn't one
+        if self.fat_type() != FatType::Fat32 || sector == 0 ||
+           sector >= u32::from(self.boot_sector.bpb.reserved_logical_sectors)
+        {
+            return None;
+        }
+
+        let sector = if self.mounted_from_backup {
+            if sector >= BOOT_REGION_SECTORS {
+                return None;
+            }
+            self.backup_boot_sector()? + sector
+        } else {
+            sector
+        };
+        Some(u64::from(sector) * u64::from(self.bytes_per_sector()))
+    }
+
+    /// Read the FSInfo sector, if the volume has one.
+    ///
+    /// After mounting from the backup boot sector, the backup FSInfo sector
+    /// is read. Its free count is from whenever the backup was made, so it
+    /// isn't trusted.
+    fn read_fs_info(&mut self) -> Option<FsInfo> {
+        let offset = self.fs_info_offset()?;
+
+        let mut bytes = [0; BOOT_SECTOR_SIZE];
+        self.read_volume(offset, &mut bytes);
+        let info = FsInfo::new(&bytes, self.cluster_count())?;
+        if self.mounted_from_backup {
+            Some(FsInfo { free_cluster_count: None, ..info })
+        } else {
+            Some(info)
+        }
+    }
+
+    /// The contents of the FSInfo sector, `None` if the volume doesn't have
+    /// one or its signatures are wrong.
+    pub fn fs_info(&self) -> Option<FsInfo> {
+        self.fs_info
+    }
+
+    /// The number of bytes in clusters that aren't in use.
+    ///
+    /// This trusts the free cluster count from the FSInfo sector when there
+    /// is one, and otherwise counts the free entries in the FAT.
+    pub fn free_space(&mut self) -> u64 {
+        let free_clusters = match self.fs_info.and_then(|info| info.free_cluster_count) {
+            Some(free_clusters) => free_clusters,
+            None => {
+                let free_clusters = self.count_free_clusters();
+                // Written out with the next allocation or free
+                if let Some(ref mut info) = self.fs_info {
+                    info.free_cluster_count = Some(free_clusters);
+                }
+                free_clusters
+            }
+        };
+
+        u64::from(free_clusters) * u64::from(self.bytes_per_cluster())
+    }
+
+    /// The number of bytes in all of the volume's clusters.
+    pub fn total_space(&self) -> u64 {
+        u64::from(self.cluster_count()) * u64::from(self.bytes_per_cluster())
+    }
+
+    fn count_free_clusters(&mut self) -> u32 {
+        let mut free_clusters = 0;
+        let end_cluster = self.cluster_count() + 2;
+        self.scan_fat(2, end_cluster, |_, entry| {
+            if entry == FatEntry::Free {
+                free_clusters += 1;
+            }
+            true
+        });
+        free_clusters
+    }
+
+    /// Call `f` with the FAT entries of the clusters from `start_cluster` up
+    /// to `end_cluster`, until it returns false. The FAT is read in chunks
+    /// rather than an entry at a time.
+    fn scan_fat<F>(&mut self, start_cluster: u32, end_cluster: u32, mut f: F)
+        where F: FnMut(u32, FatEntry) -> bool
+    {
+        let fat_type = self.fat_type();
+        let entry_bytes = fat_type.entry_bytes();
+        let cluster_count = self.cluster_count();
+        let mut chunk = [0; FAT_SCAN_CHUNK];
+
+        let mut cluster_num = start_cluster;
+        while cluster_num < end_cluster {
+            // Sized for FAT32, so the smaller entries of FAT12 and FAT16
+            // always fit too
+            let entries = u32::min(FAT_SCAN_CHUNK as u32 / 4, end_cluster - cluster_num);
+            let chunk_start = fat_type.entry_offset(cluster_num);
+            let chunk_length = fat_type.entry_offset(cluster_num + entries - 1) - chunk_start +
+                               entry_bytes as u64;
+            let bytes = &mut chunk[..chunk_length as usize];
+            let fat_offset = self.fat_offset();
+            self.read_volume(fat_offset + chunk_start, bytes);
+
+            for entry_cluster in cluster_num..cluster_num + entries {
+                let position = (fat_type.entry_offset(entry_cluster) - chunk_start) as usize;
+                let raw_entry = fat_type.decode_entry(entry_cluster, &bytes[position..position+entry_bytes]);
+                if !f(entry_cluster, FatEntry::with_type(raw_entry, fat_type, cluster_count)) {
+                    return;
+                }
+            }
+
+            cluster_num += entries;
+        }
+    }
+
+    /// Size of a logical sector, as given by the BPB. This doesn't have to
+    /// match the block size of the underlying storage.
+    pub fn bytes_per_sector(&self) -> u32 {
+        u32::from(self.boot_sector.bpb.bytes_per_logical_sector)
+    }
+
+    pub fn bytes_per_cluster(&self) -> u32 {
+        self.bytes_per_sector() * u32::from(self.boot_sector.bpb.sectors_per_cluster)
+    }
+
+    /// Byte offset of the FAT that's read from. That's the first one, unless
+    /// mirroring is disabled and only the active FAT is used.
+    fn fat_offset(&self) -> u64 {
+        let bpb = &self.boot_sector.bpb;
+        if bpb.is_mirroring_disabled() {
+            self.fat_copy_offset(bpb.active_fat())
+        } else {
+            self.fat_copy_offset(0)
+        }
+    }
+
+    /// Byte offset of copy `fat_num` of the FAT.
+    fn fat_copy_offset(&self, fat_num: u8) -> u64 {
+        let bpb = &self.boot_sector.bpb;
+        (u64::from(bpb.reserved_logical_sectors) + u64::from(fat_num) * u64::from(bpb.sectors_per_fat)) *
+        u64::from(self.bytes_per_sector())
+    }
+
+    /// The variant of FAT used by the volume, which only depends on its
+    /// number of clusters.
+    pub fn fat_type(&self) -> FatType {
+        self.boot_sector.bpb.fat_type()
+    }
+
+    /// Byte offset of the fixed root directory region used by FAT12 and
+    /// FAT16, which comes straight after the FATs.
+    fn root_region_offset(&self) -> u64 {
+        let root_region_sector: u64 =
+            u64::from(self.boot_sector.bpb.reserved_logical_sectors) +
+            u64::from(self.boot_sector.bpb.number_of_fats) *
+            u64::from(self.boot_sector.bpb.sectors_per_fat);
+
+        root_region_sector * u64::from(self.bytes_per_sector())
+    }
+
+    /// Number of sectors taken by the fixed root directory region, always 0
+    /// on FAT32.
+    fn root_region_sectors(&self) -> u32 {
+        self.boot_sector.bpb.root_region_sectors()
+    }
+
+    /// Byte offset of `cluster_num` from the start of the volume.
+    fn cluster_offset(&self, cluster_num: u32) -> u64 {
+        self.root_region_offset() +
+        u64::from(self.root_region_sectors()) * u64::from(self.bytes_per_sector()) +
+        u64::from(cluster_num - 2) * u64::from(self.bytes_per_cluster())
+    }
+
+    /// Whether `cluster_num` refers to the fixed root directory region. The
+    /// BPB gives it as cluster 0 on FAT12 and FAT16.
+    fn is_root_region(&self, cluster_num: u32) -> bool {
+        cluster_num == 0 && self.fat_type() != FatType::Fat32
+    }
+
+    /// Number of directory entries in `cluster_num`, or in the whole fixed
+    /// root directory region.
+    fn entries_in_cluster(&self, cluster_num: u32) -> u32 {
+        if self.is_root_region(cluster_num) {
+            u32::from(self.boot_sector.bpb.root_directory_entries)
+        } else {
+            self.bytes_per_cluster() / BYTES_PER_DIRECTORY_ENTRY
+        }
+    }
+
+    /// Byte offset of a directory entry from the start of the volume.
+    fn entry_offset(&self, position: EntryPosition) -> u64 {
+        let start = if self.is_root_region(position.cluster) {
+            self.root_region_offset()
+        } else {
+            self.cluster_offset(position.cluster)
+        };
+
+        start + u64::from(position.index * BYTES_PER_DIRECTORY_ENTRY)
+    }
+
+    /// Fill `result` with bytes starting at `volume_offset` bytes from the
+    /// start of the volume.
+    ///
+    /// Whole blocks are read straight into `result`, only the partial blocks
+    /// at either end go through a temporary block buffer.
+    fn read_volume(&mut self, volume_offset: u64, result: &mut [u8]) {
+        chain::read_volume(&mut self.block_storage, self.physical_start_block, volume_offset, result);
+    }
+
+    /// Get data from the specified cluster, returning the number of bytes
+    /// read.
+    ///
+    /// The length of `result` defines the maximum number of bytes that will
+    /// be read, starting from the byte in the position `byte_offset`.
+    ///
+    /// Cluster numbers only start at 2. This will panic on cluster numbers
+    /// 0 and 1.
+    ///
+    /// `fat32.bytes_per_cluster()` defines the maximum number of bytes that
+    /// can be returned from this function.
+    pub fn get_cluster(&mut self, cluster_num: u32, byte_offset: usize, result: &mut [u8]) -> usize {
+        assert!(cluster_num >= 2);
+
+        let bytes_per_cluster = self.bytes_per_cluster() as usize;
+        if byte_offset >= bytes_per_cluster {
+            return 0;
+        }
+
+        let count = usize::min(result.len(), bytes_per_cluster - byte_offset);
+        let cluster_offset = self.cluster_offset(cluster_num);
+        self.read_volume(cluster_offset + byte_offset as u64, &mut result[..count]);
+
+        count
+    }
+
+    /// Get the next cluster number from the file allocation table.
+    /// Returns None when the provided cluster number is the last cluster in
+    /// the chain, or when the chain is broken (free, bad or out of range
+    /// entries).
+    pub fn cluster_number_after(&mut self, cluster_num: u32) -> Option<u32> {
+        match self.fat_entry(cluster_num) {
+            FatEntry::Next(next_cluster) => Some(next_cluster),
+            _ => None
+        }
+    }
+
+    /// Read and decode the file allocation table entry for a cluster.
+    pub fn fat_entry(&mut self, cluster_num: u32) -> FatEntry {
+        assert!(cluster_num >= 2);
+
+        let fat_type = self.fat_type();
+        let entry_offset = self.fat_offset() + fat_type.entry_offset(cluster_num);
+
+        let mut entry = [0; 4];
+        let entry = &mut entry[..fat_type.entry_bytes()];
+        self.read_volume(entry_offset, entry);
+
+        let raw_entry = fat_type.decode_entry(cluster_num, entry);
+
+        FatEntry::with_type(raw_entry, fat_type, self.cluster_count())
+    }
+
+    /// The number of data clusters in the volume. Valid cluster numbers are
+    /// `2..cluster_count()+2`.
+    pub fn cluster_count(&self) -> u32 {
+        self.boot_sector.bpb.cluster_count()
+    }
+
+    /// Whether `cluster_num` is a data cluster of the volume.
+    fn is_valid_cluster(&self, cluster_num: u32) -> bool {
+        cluster_num >= 2 && cluster_num - 2 < self.cluster_count()
+    }
+
+    pub fn iter_contents_of_directory_cluster(&mut self, cluster_num: u32) -> DirectoryIterator<B, T> {
+        DirectoryIterator::new(self, cluster_num)
+    }
+
+    /// Get a handle to the root directory of the volume.
+    ///
+    /// The root directory's cluster comes from the BPB, so this works for
+    /// volumes whose root directory doesn't start at cluster 2.
+    pub fn root_dir(&self) -> Directory {
+        Directory {
+            name: String::new(),
+            cluster: self.boot_sector.bpb.root_directory_cluster,
+            location: None,
+            info: FileInfo {
+                attributes: DirectoryEntryFlags::SUBDIRECTORY,
+                created: None,
+                modified: None,
+                accessed: None
+            }
+        }
+    }
+
+    /// Iterate over the contents of `directory`.
+    ///
+    /// A `..` entry in a directory just below the root points at cluster 0,
+    /// so cluster 0 is treated as the root directory.
+    pub fn iter_dir(&mut self, directory: &Directory) -> DirectoryIterator<B, T> {
+        let cluster = self.directory_cluster(directory.cluster);
+        DirectoryIterator::new(self, cluster)
+    }
+
+    fn directory_cluster(&self, cluster_num: u32) -> u32 {
+        if cluster_num == 0 {
+            self.boot_sector.bpb.root_directory_cluster
+        } else {
+            cluster_num
+        }
+    }
+
+    /// Iterate over the contents of `file` in chunks of up to 512 bytes.
+    /// A chain that ends before the file does gives one `CorruptChain`
+    /// error, and then nothing more.
+    pub fn iter_file<'a>(&'a mut self, file: &File) -> FileIterator<B, T> {
+        FileIterator::new(self.open_file(file))
+    }
+
+    /// Open `file` for random access reads.
+    pub fn open_file(&mut self, file: &File) -> FileReader<B, T> {
+        let size = u64::from(file.size);
+        FileReader::new(self, chain::ChainCursor::new(file.cluster, false), size, size)
+    }
+
+    /// The volume's label, from the label entry in the root directory or
+    /// else the boot sector. `None` if the volume has no label.
+    pub fn volume_label(&mut self) -> Option<Name> {
+        let root = self.root_dir();
+        let code_page = self.code_page;
+        let fat_type = self.fat_type();
+
+        {
+            let mut entries = self.iter_dir(&root);
+            while let Some((_, entry_bytes)) = entries.next_entry() {
+                match Entry::with_type(&entry_bytes, fat_type) {
+                    Entry::DirectoryEntry(ref e) if e.flags.contains(DirectoryEntryFlags::VOLUME_LABEL) => {
+                        let mut label = [0; 11];
+                        label.copy_from_slice(&entry_bytes[0..11]);
+                        return decode_label(&label, &code_page);
+                    },
+                    Entry::Last => break,
+                    _ => {}
+                }
+            }
+        }
+
+        self.boot_sector_label()
+    }
+
+    /// The label recorded in the boot sector, without its padding. `None` if
+    /// it's blank or the `NO NAME` placeholder.
+    ///
+    /// Most tools only update the label entry in the root directory, so
+    /// `volume_label` is usually the one to use.
+    pub fn boot_sector_label(&self) -> Option<Name> {
+        // Formatting tools write this when there's no label
+        if self.boot_sector.bpb.label == *b"NO NAME    " {
+            return None;
+        }
+        decode_label(&self.boot_sector.bpb.label, &self.code_page)
+    }
+
+    /// Look up the file or directory at `path`, ignoring case. Names match
+    /// either the long name or the 8.3 name of an item.
+    ///
+    /// `.` and `..` are resolved as the path is followed, so `..` after a
+    /// file doesn't match anything. A path ending with a slash only matches
+    /// a directory, and an empty path is the root directory.
+    pub fn item_info(&mut self, path: &str) -> Option<DirectoryItem> {
+        self.resolve_path(path).ok()
+    }
+
+    /// Look up the directory at `path`.
+    pub fn directory_at(&mut self, path: &str) -> Result<Directory, Fat32Error> {
+        match self.resolve_path(path)? {
+            DirectoryItem::Directory(d) => Ok(d),
+            DirectoryItem::File(_) => Err(Fat32Error::NotADirectory)
+        }
+    }
+
+    /// Follow `path` from the root directory, one part at a time.
+    fn resolve_path(&mut self, path: &str) -> Result<DirectoryItem, Fat32Error> {
+        let mut current = DirectoryItem::Directory(self.root_dir());
+
+        for (part, end) in path::Parts::new(path) {
+            let directory = match current {
+                DirectoryItem::Directory(d) => d,
+                DirectoryItem::File(_) => return Err(Fat32Error::NotADirectory)
+            };
+
+            current = if part == ".." {
+                // Everything before the `..` has been looked up as a
+                // directory, so it's safe to cancel it out of the path
+                DirectoryItem::Directory(self.normalized_directory_at(&path[..end])?)
+            } else {
+                self.find_item(&directory, part.trim_end_matches('.')).ok_or(Fat32Error::NotFound)?
+            };
+        }
+
+        match current {
+            DirectoryItem::File(_) if path::is_directory_path(path) => Err(Fat32Error::NotADirectory),
+            item => Ok(item)
+        }
+    }
+
+    /// Look up the directory at `path` after normalizing it lexically.
+    fn normalized_directory_at(&mut self, path: &str) -> Result<Directory, Fat32Error> {
+        let mut current = self.root_dir();
+
+        for name in path::Components::new(path) {
+            match self.find_item(&current, name) {
+                Some(DirectoryItem::Directory(d)) => current = d,
+                Some(DirectoryItem::File(_)) => return Err(Fat32Error::NotADirectory),
+                None => return Err(Fat32Error::NotFound)
+            }
+        }
+
+        Ok(current)
+    }
+
+    /// Find the item in `directory` called `name`, ignoring case and
+    /// matching either its long name or its 8.3 name.
+    fn find_item(&mut self, directory: &Directory, name: &str) -> Option<DirectoryItem> {
+        let mut entries = self.iter_dir(directory);
+
+        while let Some(item) = entries.next() {
+            let (item_name, location) = match item {
+                DirectoryItem::File(ref f) => (&f.name, Some(f.location)),
+                DirectoryItem::Directory(ref d) => (&d.name, d.location)
+            };
+
+            if path::names_match(item_name, name) {
+                return Some(item);
+            }
+
+            // Items with a long name can also be found by their alias
+            if let Some(location) = location {
+                if location.first != location.short {
+                    let mut entry_bytes = [0; 32];
+                    let entry_offset = entries.fat32.entry_offset(location.short);
+                    entries.fat32.read_volume(entry_offset, &mut entry_bytes);
+
+                    let mut short_name = Name::new();
+                    let short_entry = DirectoryEntry::new(&entry_bytes, entries.fat32.fat_type());
+                    short_entry.decode_name(&entries.fat32.code_page, &mut short_name);
+                    if path::names_match(&short_name, name) {
+                        return Some(item);
+                    }
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Iterator over the contents of a file, see `Fat32::iter_file`.
+pub type FileIterator<'a, B, T = FixedTime> = chain::FileIterator<'a, Fat32<B, T>>;
+
+/// Random access reader for the contents of a file, see `Fat32::open_file`.
+pub type FileReader<'a, B, T = FixedTime> = chain::FileReader<'a, Fat32<B, T>>;
+
+impl<B: BlockAccessor, T: TimeSource> ClusterChains for Fat32<B, T> {
+    type Error = Fat32Error;
+
+    fn bytes_per_cluster(&self) -> u32 {
+        Fat32::bytes_per_cluster(self)
+    }
+
+    fn is_valid_cluster(&self, cluster_num: u32) -> bool {
+        Fat32::is_valid_cluster(self, cluster_num)
+    }
+
+    fn next_cluster(&mut self, cluster_num: u32) -> Option<u32> {
+        self.cluster_number_after(cluster_num)
+    }
+
+    fn read_cluster(&mut self, cluster_num: u32, offset: u64, buffer: &mut [u8]) {
+        let cluster_offset = self.cluster_offset(cluster_num);
+        self.read_volume(cluster_offset + offset, buffer);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fat32Error {
+    /// A cluster chain ended early, or pointed at a free, bad or
+    /// out-of-range cluster
+    CorruptChain,
+    /// Attempted to seek to a negative position
+    InvalidSeek,
+    /// Nothing exists at the given path
+    NotFound,
+    /// Something already exists at the given path
+    AlreadyExists,
+    /// A directory was expected, but a file was found
+    NotADirectory,
+    /// A file was expected, but a directory was found
+    NotAFile,
+    /// The name can't be stored on the volume
+    InvalidName,
+    /// The path can't be used for this operation, like moving a directory
+    /// into itself
+    InvalidPath,
+    /// There are no free clusters left on the volume
+    VolumeFull,
+    /// There are no free entries left in the directory
+    DirectoryFull,
+    /// A directory can't be removed while it still has contents
+    DirectoryNotEmpty,
+    /// The file would grow beyond the 4 GiB FAT limit
+    FileTooLarge,
+    /// A FAT entry can't be written for a cluster outside the volume, or
+    /// point at one, and reserved entries can't be written at all
+    InvalidFatEntry,
+    /// Only FAT32 volumes have a backup boot sector
+    NoBackupBootSector,
+    /// The underlying storage failed to write a block
+    BlockAccess(BlockAccessError)
+}
+
+/// Sector a FAT32 backup boot sector is at when the primary boot sector
+/// can't be read to find it
+const BACKUP_BOOT_SECTOR: u64 = 6;
+/// Sectors copied between the primary and backup boot sectors: the boot
+/// sector, the FSInfo sector and one more of boot code
+const BOOT_REGION_SECTORS: u32 = 3;
+
+/// Read the boot sector `offset` bytes into the volume, and check that it
+/// describes a volume that can exist.
+fn read_boot_sector<B: BlockAccessor>(block_storage: &mut B, physical_start_block: u32, offset: u64)
+    -> Result<BootSector, MountError>
+{
+    let block_size = block_storage.block_size();
+    let mut block = [0; MAX_BLOCK_SIZE];
+    block_storage.read_block(u64::from(physical_start_block) + offset / block_size,
+                             &mut block[..block_size as usize]);
+
+    let offset_in_block = (offset % block_size) as usize;
+    let boot_sector = BootSector::new(&block[offset_in_block..offset_in_block+BOOT_SECTOR_SIZE])?;
+    boot_sector.bpb.check()?;
+    Ok(boot_sector)
+}
+
+/// Look for a FAT32 backup boot sector. The damaged primary boot sector
+/// can't be trusted for the sector size, so each one is tried.
+fn find_backup_boot_sector<B: BlockAccessor>(block_storage: &mut B, physical_start_block: u32)
+    -> Option<BootSector>
+{
+    for &bytes_per_sector in &[512, 1024, 2048, 4096] {
+        let offset = BACKUP_BOOT_SECTOR * u64::from(bytes_per_sector);
+        if let Ok(boot_sector) = read_boot_sector(block_storage, physical_start_block, offset) {
+            if boot_sector.bpb.bytes_per_logical_sector == bytes_per_sector &&
+               boot_sector.bpb.fat_type() == FatType::Fat32
+            {
+                return Some(boot_sector);
+            }
+        }
+    }
+
+    None
+}
+
+/// One of the two copies of a FAT32 volume's boot sectors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootSectorCopy {
+    Primary,
+    Backup
+}
+
+/// Why a volume couldn't be mounted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountError {
+    /// The storage's blocks are smaller than a boot sector or larger than
+    /// `MAX_BLOCK_SIZE`
+    UnsupportedBlockSize,
+    /// The boot sector doesn't end in 0x55AA, so there's no file system
+    MissingSignature,
+    /// The extended boot signature is neither 0x28 nor 0x29
+    UnsupportedBootSignature,
+    /// Sectors aren't 512, 1024, 2048 or 4096 bytes
+    InvalidBytesPerSector,
+    /// The sectors per cluster isn't a power of two
+    InvalidClusterSize,
+    /// There are no reserved sectors, which would leave no room for the boot
+    /// sector
+    InvalidReservedSectors,
+    /// There are no FATs, or more than two
+    InvalidFatCount,
+    /// Mirroring is disabled in favour of a FAT that doesn't exist
+    InvalidActiveFat,
+    /// The FAT32 version is newer than 0.0
+    UnsupportedVersion,
+    /// The volume has no sectors
+    InvalidSectorCount,
+    /// The FATs are too small for the number of clusters, or leave no room
+    /// for any
+    InvalidFatSize,
+    /// The root directory cluster is outside the volume, or FAT12 and FAT16
+    /// volumes have no room for root directory entries
+    InvalidRootDirectory
+}
+
+impl From<BlockAccessError> for Fat32Error {
+    fn from(error: BlockAccessError) -> Fat32Error {
+        Fat32Error::BlockAccess(error)
+    }
+}
+
+impl From<ChainError> for Fat32Error {
+    fn from(error: ChainError) -> Fat32Error {
+        match error {
+            ChainError::CorruptChain => Fat32Error::CorruptChain,
+            ChainError::InvalidSeek => Fat32Error::InvalidSeek
+        }
+    }
+}
+
+/// The three variants of FAT, named after the width of their entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32
+}
+
+impl FatType {
+    /// Pick the FAT type for a volume with `cluster_count` clusters, using
+    /// the limits from Microsoft's FAT specification.
+    pub fn from_cluster_count(cluster_count: u32) -> FatType {
+        if cluster_count < 4085 {
+            FatType::Fat12
+        } else if cluster_count < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        }
+    }
+
+    /// Byte offset of a cluster's entry from the start of a FAT. FAT12
+    /// entries take one and a half bytes, so they share a byte with the
+    /// entry next to them.
+    fn entry_offset(self, cluster_num: u32) -> u64 {
+        let cluster_num = u64::from(cluster_num);
+        match self {
+            FatType::Fat12 => cluster_num + cluster_num / 2,
+            FatType::Fat16 => cluster_num * 2,
+            FatType::Fat32 => cluster_num * 4
+        }
+    }
+
+    /// Number of bytes to read to get a whole entry.
+    fn entry_bytes(self) -> usize {
+        match self {
+            FatType::Fat12 | FatType::Fat16 => 2,
+            FatType::Fat32 => 4
+        }
+    }
+
+    /// Get the raw value of `cluster_num`'s entry out of the `entry_bytes()`
+    /// bytes starting at its `entry_offset()`.
+    fn decode_entry(self, cluster_num: u32, bytes: &[u8]) -> u32 {
+        let raw_entry = little_endian_to_int(bytes);
+        match self {
+            FatType::Fat12 if cluster_num % 2 == 1 => raw_entry >> 4,
+            FatType::Fat12 => raw_entry & 0x0FFF,
+            FatType::Fat16 => raw_entry,
+            FatType::Fat32 => raw_entry & 0x0FFF_FFFF
+        }
+    }
+
+    /// The clean shutdown and no hard error bits in FAT[1], `None` on FAT12
+    /// which doesn't have them.
+    fn volume_flag_bits(self) -> Option<(u32, u32)> {
+        match self {
+            FatType::Fat12 => None,
+            FatType::Fat16 => Some((0x8000, 0x4000)),
+            FatType::Fat32 => Some((0x0800_0000, 0x0400_0000))
+        }
+    }
+
+    /// The largest value an entry can hold, which marks the end of a chain.
+    fn entry_mask(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0x0FFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF
+        }
+    }
+}
+
+/// A decoded file allocation table entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatEntry {
+    /// The cluster is not allocated
+    Free,
+    /// The chain continues with this cluster
+    Next(u32),
+    /// The cluster has been marked as unusable
+    Bad,
+    /// The cluster is the last one in its chain
+    EndOfChain,
+    /// A reserved value, or a cluster number outside of the volume
+    Reserved
+}
+
+impl FatEntry {
+    /// Decode a raw FAT32 entry. Only the low 28 bits are meaningful, the
+    /// top 4 bits are reserved and must be ignored.
+    pub fn new(raw_entry: u32, cluster_count: u32) -> FatEntry {
+        const BAD_CLUSTER: u32 = 0x0FFF_FFF7;
+        const END_OF_CHAIN_MIN: u32 = 0x0FFF_FFF8;
+
+        let entry = raw_entry & 0x0FFF_FFFF;
+
+        if entry == 0 {
+            FatEntry::Free
+        } else if entry >= END_OF_CHAIN_MIN {
+            FatEntry::EndOfChain
+        } else if entry == BAD_CLUSTER {
+            FatEntry::Bad
+        } else if entry >= 2 && entry - 2 < cluster_count {
+            FatEntry::Next(entry)
+        } else {
+            FatEntry::Reserved
+        }
+    }
+
+    /// Decode a raw entry from a FAT of any type. The special values at the
+    /// top of the FAT12 and FAT16 ranges mean the same as their FAT32
+    /// counterparts.
+    pub fn with_type(raw_entry: u32, fat_type: FatType, cluster_count: u32) -> FatEntry {
+        let mask = fat_type.entry_mask();
+        let raw_entry = raw_entry & mask;
+
+        if raw_entry >= mask - 8 {
+            FatEntry::new(raw_entry | (0x0FFF_FFFF & !mask), cluster_count)
+        } else {
+            FatEntry::new(raw_entry, cluster_count)
+        }
+    }
+}
+
+/// The attributes and timestamps of a file or directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileInfo {
+    pub attributes: DirectoryEntryFlags,
+    pub created: Option<DateTime>,
+    pub modified: Option<DateTime>,
+    /// Only the date of the last access is recorded
+    pub accessed: Option<DateTime>
+}
+
+impl FileInfo {
+    pub fn is_read_only(&self) -> bool {
+        self.attributes.contains(DirectoryEntryFlags::READ_ONLY)
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.attributes.contains(DirectoryEntryFlags::HIDDEN)
+    }
+
+    pub fn is_system(&self) -> bool {
+        self.attributes.contains(DirectoryEntryFlags::SYSTEM)
+    }
+
+    /// Whether this is the entry holding the volume's label, rather than a
+    /// real file.
+    pub fn is_volume_label(&self) -> bool {
+        self.attributes.contains(DirectoryEntryFlags::VOLUME_LABEL)
+    }
+
+    /// Whether the item has changed since the archive flag was last cleared
+    /// by a backup.
+    pub fn is_archive(&self) -> bool {
+        self.attributes.contains(DirectoryEntryFlags::ARCHIVE)
+    }
+}
+
+pub struct DirectoryIterator<'a, B: 'a, T: 'a = FixedTime>
+    where B: BlockAccessor
+{
+    fat32: &'a mut Fat32<B, T>,
+    cluster: Option<u32>,
+    entry_in_cluster: u32,
+    /// Clusters of the chain left behind, so a chain that loops ends after
+    /// as many clusters as the volume has
+    clusters_read: u32,
+    /// Set when the chain was cut off for being longer than the volume
+    looped: bool,
+    include_special_entries: bool
+}
+
+impl<'a, B: BlockAccessor, T: TimeSource> DirectoryIterator<'a, B, T> {
+    fn new(fat32: &'a mut Fat32<B, T>, cluster: u32) -> 
+        DirectoryIterator<'a, B, T>
+    {
+        let cluster = if fat32.is_valid_cluster(cluster) || fat32.is_root_region(cluster) {
+            Some(cluster)
+        } else {
+            None
+        };
+
+        DirectoryIterator {
+            fat32,
+            cluster,
+            entry_in_cluster: 0,
+            clusters_read: 0,
+            looped: false,
+            include_special_entries: false
+        }
+    }
+
+    /// Also yield the volume label and the `.` and `..` entries, which are
+    /// skipped by default.
+    pub fn include_special_entries(mut self) -> Self {
+        self.include_special_entries = true;
+        self
+    }
+}
+
+#[derive(Debug)]
+pub enum DirectoryItem {
+    File(File),
+    Directory(Directory)
+}
+
+impl DirectoryItem {
+    /// Where the item's directory entries are, `None` for the root directory.
+    pub fn location(&self) -> Option<EntryLocation> {
+        match *self {
+            DirectoryItem::File(ref f) => Some(f.location),
+            DirectoryItem::Directory(ref d) => d.location
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct File {
+    pub name: Name,
+    pub cluster: u32,
+    pub size: u32,
+    pub location: EntryLocation,
+    pub info: FileInfo
+}
+
+#[derive(Debug)]
+pub struct Directory {
+    pub name: Name,
+    pub cluster: u32,
+    /// `None` for the root directory, which has no directory entry
+    pub location: Option<EntryLocation>,
+    pub info: FileInfo
+}
+
+/// The position of a 32 byte entry within a directory's cluster chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryPosition {
+    pub cluster: u32,
+    pub index: u32
+}
+
+/// Where the directory entries for an item are stored. Items with a long
+/// file name start with their LFN entries, followed by the 8.3 entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryLocation {
+    pub first: EntryPosition,
+    pub short: EntryPosition
+}
+
+impl<'a, B: BlockAccessor, T: TimeSource> DirectoryIterator<'a, B, T> {
+    /// Read the next raw entry of the directory along with its position,
+    /// following the directory's cluster chain.
+    fn next_entry(&mut self) -> Option<(EntryPosition, [u8; 32])> {
+        let cluster_num = self.cluster?;
+        let entries_per_cluster = self.fat32.entries_in_cluster(cluster_num);
+        let position = EntryPosition {
+            cluster: cluster_num,
+            index: self.entry_in_cluster
+        };
+
+        self.entry_in_cluster += 1;
+
+        // Directories are cluster chains just like files, so once this
+        // cluster has been used up we continue with the next one. The fixed
+        // root directory region has nothing after it.
+        if self.entry_in_cluster >= entries_per_cluster {
+            self.clusters_read += 1;
+            self.looped = self.clusters_read >= self.fat32.cluster_count();
+            self.cluster = if self.fat32.is_root_region(cluster_num) || self.looped {
+                None
+            } else {
+                self.fat32.cluster_number_after(cluster_num)
+            };
+            self.entry_in_cluster = 0;
+        }
+
+        let mut entry_bytes = [0; 32];
+        let entry_offset = self.fat32.entry_offset(position);
+        self.fat32.read_volume(entry_offset, &mut entry_bytes);
+
+        Some((position, entry_bytes))
+    }
+}
+
+impl<'a, B: BlockAccessor, T: TimeSource> Iterator for DirectoryIterator<'a, B, T> {
+    type Item = DirectoryItem;
+
+    fn next(&mut self) -> Option<DirectoryItem> {
+        let mut long_name = [0; MAX_LFN_LENGTH];
+        let mut long_name_length = 0;
+        let mut first_position = None;
+        // Checksum of the LFN entries seen so far, and the sequence number
+        // expected in the next one
+        let mut lfn_checksum = None;
+        let mut next_sequence_number = 0;
+
+        loop {
+            let (position, entry_bytes) = self.next_entry()?;
+
+            match Entry::with_type(&entry_bytes, self.fat32.fat_type()) {
+                Entry::Lfn(e) => {
+                    let sequence_number = usize::from(e.sequence_number());
+                    if sequence_number == 0 || sequence_number > MAX_LFN_ENTRIES {
+                        first_position = None;
+                        lfn_checksum = None;
+                        continue;
+                    }
+
+                    // Entries hold 13 code units each, in reverse order
+                    let start = (sequence_number - 1) * LFN_CHARACTERS_PER_ENTRY;
+
+                    if e.is_last() {
+                        // The start of a new long name, anything collected
+                        // before it was an orphan
+                        first_position = Some(position);
+                        lfn_checksum = Some(e.checksum);
+                        long_name_length = usize::min(start + e.length(), MAX_LFN_LENGTH);
+                    } else if lfn_checksum != Some(e.checksum) ||
+                              sequence_number != next_sequence_number
+                    {
+                        first_position = None;
+                        lfn_checksum = None;
+                        continue;
+                    }
+                    next_sequence_number = sequence_number - 1;
+
+                    for (offset, unit) in e.file_name.iter().enumerate() {
+                        if start + offset < long_name_length {
+                            long_name[start + offset] = *unit;
+                        }
+                    }
+                },
+                Entry::DirectoryEntry(e) => {
+                    if !self.include_special_entries &&
+                       (e.flags.contains(DirectoryEntryFlags::VOLUME_LABEL) || e.is_dot_entry())
+                    {
+                        first_position = None;
+                        lfn_checksum = None;
+                        continue;
+                    }
+
+                    // A long name only belongs to this entry if all of its
+                    // parts were found and it was made for this short name
+                    let lfn_complete = next_sequence_number == 0 &&
+                                       lfn_checksum == Some(e.checksum());
+
+                    let mut item_name = Name::new();
+                    if lfn_complete {
+                        decode_long_name(&long_name[..long_name_length], &mut item_name);
+                    } else {
+                        first_position = None;
+                        e.decode_name(&self.fat32.code_page, &mut item_name);
+                    }
+
+                    let location = EntryLocation {
+                        first: first_position.unwrap_or(position),
+                        short: position
+                    };
+
+                    if e.flags.contains(DirectoryEntryFlags::SUBDIRECTORY) {
+                        return Some(DirectoryItem::Directory(
+                            Directory {
+                                name: item_name,
+                                cluster: e.cluster_num,
+                                location: Some(location),
+                                info: e.info()
+                            }
+                        ));
+                    } else {
+                        return Some(DirectoryItem::File(
+                            File {
+                                name: item_name,
+                                cluster: e.cluster_num,
+                                size: e.size,
+                                location,
+                                info: e.info()
+                            }
+                        ));
+                    }
+                },
+                Entry::Empty => {
+                    first_position = None;
+                    lfn_checksum = None;
+                },
+                Entry::Last => {
+                    self.cluster = None;
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+pub struct BootSector {
+    pub jump_instruction: u32,
+    pub oem_name: [u8; 8],
+    pub bpb: EBPB,
+    pub drive_number: u8
+}
+
+impl BootSector {
+    pub fn new(bytes: &[u8]) -> Result<BootSector, MountError> {
+        if bytes.len() != BOOT_SECTOR_SIZE || bytes[510] != 0x55 || bytes[511] != 0xAA {
+            return Err(MountError::MissingSignature);
+        }
+
+        let jump_instruction: u32 = (u32::from(bytes[2]) << 16) +
+                                    (u32::from(bytes[1]) <<  8) +
+                                     u32::from(bytes[0]);
+        let oem_name = [
+            bytes[3],
+            bytes[4],
+            bytes[5],
+            bytes[6],
+            bytes[7],
+            bytes[8],
+            bytes[9],
+            bytes[10],
+        ];
+        let bpb = EBPB::new(&bytes[11..509])?;
+        let drive_number = bytes[509];
+
+        Ok(BootSector {
+            jump_instruction,
+            oem_name,
+            bpb,
+            drive_number
+        })
+    }
+}
+
+pub struct EBPB {
+        // DOS 2.0 BPB
+        pub bytes_per_logical_sector: u16,
+        pub sectors_per_cluster: u8,
+        pub reserved_logical_sectors: u16,
+        pub number_of_fats: u8,
+        pub root_directory_entries: u16,
+        pub total_logical_sectors: u16,
+        pub media_descriptor: u8,
+
+        // logical_sectors_per_fat is moved into sectors_per_fat on FAT12 and
+        // FAT16, and 0 on FAT32
+
+        // DOS 3.31 BPB
+        pub sectors_per_track: u16,
+        pub heads_per_disk: u16,
+        pub hidden_sectors: u32,
+        pub sector_count: u32,
+
+        // DOS 7.1 EBPB, the fields up to drive_number are 0 for FAT12 and
+        // FAT16, which use the shorter DOS 4.0 EBPB
+        pub sectors_per_fat: u32,
+        /// See `active_fat` and `is_mirroring_disabled`
+        pub flags: u16,
+        /// Major version in the high byte, minor version in the low byte
+        pub version: u16,
+        pub root_directory_cluster: u32,
+        pub information_sector: u16,
+        /// Sector of the backup boot sector, which is followed by a backup
+        /// of the FSInfo sector
+        pub backup_information_sector: u16,
+        pub drive_number: u8,
+        // Flags ignored for Fat32
+        // more flags?
+        pub boot_signature: u8,
+        pub serial_number: u32,
+        pub label: [u8; 11],
+        pub file_system_type: [u8; 8],
+}
+
+/// Set in the EBPB flags when only the active FAT is used, rather than
+/// keeping every copy the same
+const MIRRORING_DISABLED: u16 = 0x0080;
+const ACTIVE_FAT_MASK: u16 = 0x000F;
+
+impl EBPB {
+    /// The variant of FAT used by the volume, which only depends on its
+    /// number of clusters.
+    pub fn fat_type(&self) -> FatType {
+        FatType::from_cluster_count(self.cluster_count())
+    }
+
+    /// Number of data clusters on the volume.
+    pub fn cluster_count(&self) -> u32 {
+        let data_sectors = self.total_sectors().saturating_sub(self.data_start_sector());
+        // Fits, since it's at most the volume's 32 bit sector count
+        (data_sectors / u64::from(self.sectors_per_cluster)) as u32
+    }
+
+    /// Number of sectors on the volume, from whichever field holds it.
+    fn total_sectors(&self) -> u64 {
+        if self.total_logical_sectors != 0 {
+            u64::from(self.total_logical_sectors)
+        } else {
+            u64::from(self.sector_count)
+        }
+    }
+
+    /// The sector cluster 2 starts at, after the reserved sectors, FATs and
+    /// root directory region. It's worked out in 64 bits so a bogus FAT size
+    /// can't overflow it.
+    fn data_start_sector(&self) -> u64 {
+        u64::from(self.reserved_logical_sectors) +
+        u64::from(self.number_of_fats) * u64::from(self.sectors_per_fat) +
+        u64::from(self.root_region_sectors())
+    }
+
+    /// Number of sectors taken by the fixed root directory region, always 0
+    /// on FAT32.
+    fn root_region_sectors(&self) -> u32 {
+        let bytes_per_sector = u32::from(self.bytes_per_logical_sector);
+        let root_region_bytes = u32::from(self.root_directory_entries) * BYTES_PER_DIRECTORY_ENTRY;
+
+        // A partly used last sector still belongs to the region
+        if root_region_bytes.is_multiple_of(bytes_per_sector) {
+            root_region_bytes / bytes_per_sector
+        } else {
+            root_region_bytes / bytes_per_sector + 1
+        }
+    }
+
+    /// Check that the BPB's sizes make sense together, so nothing past the
+    /// end of the volume or the FAT gets used.
+    fn check(&self) -> Result<(), MountError> {
+        match self.bytes_per_logical_sector {
+            512 | 1024 | 2048 | 4096 => {},
+            _ => return Err(MountError::InvalidBytesPerSector)
+        }
+        if !self.sectors_per_cluster.is_power_of_two() {
+            return Err(MountError::InvalidClusterSize);
+        }
+        if self.reserved_logical_sectors == 0 {
+            return Err(MountError::InvalidReservedSectors);
+        }
+        if self.number_of_fats == 0 || self.number_of_fats > 2 {
+            return Err(MountError::InvalidFatCount);
+        }
+        if self.is_mirroring_disabled() && self.active_fat() >= self.number_of_fats {
+            return Err(MountError::InvalidActiveFat);
+        }
+
+        let total_sectors = self.total_sectors();
+        if total_sectors == 0 {
+            return Err(MountError::InvalidSectorCount);
+        }
+
+        // The FATs and root directory region have to leave room for data,
+        // which is checked before anything works out the FAT type from it
+        if self.data_start_sector() >= total_sectors {
+            return Err(MountError::InvalidFatSize);
+        }
+
+        // Each FAT needs an entry for every cluster
+        let fat_type = self.fat_type();
+        let last_entry_end = fat_type.entry_offset(self.cluster_count() + 1) + fat_type.entry_bytes() as u64;
+        let fat_bytes = u64::from(self.sectors_per_fat) * u64::from(self.bytes_per_logical_sector);
+        if self.cluster_count() == 0 || last_entry_end > fat_bytes {
+            return Err(MountError::InvalidFatSize);
+        }
+
+        // Later versions may change the layout in ways that writing to
+        // the volume would damage
+        if fat_type == FatType::Fat32 && self.version != 0 {
+            return Err(MountError::UnsupportedVersion);
+        }
+
+        let root_directory_valid = match fat_type {
+            FatType::Fat32 => self.root_directory_cluster >= 2 &&
+                              self.root_directory_cluster - 2 < self.cluster_count(),
+            FatType::Fat12 | FatType::Fat16 => self.root_directory_entries != 0
+        };
+        if !root_directory_valid {
+            return Err(MountError::InvalidRootDirectory);
+        }
+
+        Ok(())
+    }
+
+    /// The FAT that's used when mirroring is disabled, counting from 0.
+    pub fn active_fat(&self) -> u8 {
+        (self.flags & ACTIVE_FAT_MASK) as u8
+    }
+
+    /// Whether only the active FAT is kept up to date. Otherwise every copy
+    /// of the FAT is written, and the first one is read.
+    pub fn is_mirroring_disabled(&self) -> bool {
+        self.flags & MIRRORING_DISABLED != 0
+    }
+
+    /// The file system type string, like `FAT32`, without its padding. It's
+    /// only informational, the type really depends on the number of
+    /// clusters.
+    pub fn file_system_type_str(&self) -> &str {
+        ::core::str::from_utf8(&self.file_system_type)
+            .unwrap_or("")
+            .trim_end_matches(&[' ', '\0'][..])
+    }
+}
+
+fn get(bytes: &mut &[u8]) -> u8 {
+    take_from_slice(bytes)
+}
+
+fn getn(bytes: &mut &[u8], n: usize) -> u32 {
+    if n > 4 {
+        panic!("Can't get more than four bytes into a u32");
+    }
+    little_endian_to_int(taken_from_slice(bytes, n))
+}
+
+impl EBPB {
+    /// Parse the BPB from the 498 bytes of the boot sector after the OEM
+    /// name.
+    fn new(mut bytes: &[u8]) -> Result<EBPB, MountError> {
+        // DOS 2.0 BPB
+        let bytes_per_logical_sector = getn(&mut bytes, 2) as u16;
+        let sectors_per_cluster = get(&mut bytes);
+        let reserved_logical_sectors = getn(&mut bytes, 2) as u16;
+        let number_of_fats = get(&mut bytes);
+        let root_directory_entries = getn(&mut bytes, 2) as u16;
+        let total_logical_sectors = getn(&mut bytes, 2) as u16;
+        let media_descriptor = get(&mut bytes);
+        let logical_sectors_per_fat = getn(&mut bytes, 2);
+
+        // DOS 3.31 BPB
+        let sectors_per_track = getn(&mut bytes, 2) as u16;
+        let heads_per_disk = getn(&mut bytes, 2) as u16;
+        let hidden_sectors = getn(&mut bytes, 4);
+        let sector_count = getn(&mut bytes, 4);
+
+        // FAT32 volumes always leave the 16 bit sectors per FAT as 0 and
+        // give it in the DOS 7.1 EBPB instead
+        let mut sectors_per_fat = logical_sectors_per_fat;
+        let mut flags = 0;
+        let mut version = 0;
+        let mut root_directory_cluster = 0;
+        let mut information_sector = 0;
+        let mut backup_information_sector = 0;
+
+        if logical_sectors_per_fat == 0 {
+            // DOS 7.1 EBPB
+            sectors_per_fat = getn(&mut bytes, 4);
+            flags = getn(&mut bytes, 2) as u16;
+
+            version = getn(&mut bytes, 2) as u16;
+            root_directory_cluster = getn(&mut bytes, 4);
+            information_sector = getn(&mut bytes, 2) as u16;
+            backup_information_sector = getn(&mut bytes, 2) as u16;
+            // Reserved section ignored
+            taken_from_slice(&mut bytes, 12);
+        }
+
+        // The rest matches the DOS 4.0 EBPB, wherever it starts
+        let drive_number = get(&mut bytes);
+        // Flags ignored for Fat32
+        get(&mut bytes);
+        let boot_signature = get(&mut bytes);
+        let serial_number = getn(&mut bytes, 4);
+
+        // Boot signature 0x28 is an older EBPB that ends after the serial
+        // number
+        let mut label = *b"NO NAME    ";
+        let mut file_system_type = [b' '; 8];
+        match boot_signature {
+            0x29 => {
+                label.copy_from_slice(taken_from_slice(&mut bytes, 11));
+                file_system_type.copy_from_slice(taken_from_slice(&mut bytes, 8));
+            },
+            0x28 => {},
+            _ => return Err(MountError::UnsupportedBootSignature)
+        }
+
+        Ok(EBPB {
+            bytes_per_logical_sector,
+            sectors_per_cluster,
+            reserved_logical_sectors,
+            number_of_fats,
+            root_directory_entries,
+            total_logical_sectors,
+            media_descriptor,
+            sectors_per_track,
+            heads_per_disk,
+            hidden_sectors,
+            sector_count,
+            sectors_per_fat,
+            flags,
+            version,
+            root_directory_cluster,
+            information_sector,
+            backup_information_sector,
+            drive_number,
+            boot_signature,
+            serial_number,
+            label,
+            file_system_type
+        })
+    }
+}
+
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FS_INFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FS_INFO_TRAIL_SIGNATURE: u32 = 0xAA55_0000;
+/// Offset of the free cluster count in the FSInfo sector, which is followed
+/// by the next free cluster
+const FS_INFO_COUNTS_OFFSET: u64 = 488;
+/// Stored in either field of the FSInfo sector when it isn't known
+const FS_INFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+/// The FSInfo sector of a FAT32 volume, which records how much space is
+/// free so the FAT doesn't have to be scanned for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsInfo {
+    /// `None` when it isn't known
+    pub free_cluster_count: Option<u32>,
+    /// Where to start looking for a free cluster, `None` when it isn't known
+    pub next_free_cluster: Option<u32>
+}
+
+impl FsInfo {
+    /// Parse an FSInfo sector, `None` if any of its signatures are wrong.
+    /// Values that can't be right for a volume with `cluster_count` clusters
+    /// are treated as unknown.
+    pub fn new(bytes: &[u8], cluster_count: u32) -> Option<FsInfo> {
+        if little_endian_to_int(&bytes[0..4]) != FS_INFO_LEAD_SIGNATURE ||
+           little_endian_to_int(&bytes[484..488]) != FS_INFO_STRUCT_SIGNATURE ||
+           little_endian_to_int(&bytes[508..512]) != FS_INFO_TRAIL_SIGNATURE
+        {
+            return None;
+        }
+
+        let free_cluster_count = little_endian_to_int(&bytes[488..492]);
+        let next_free_cluster = little_endian_to_int(&bytes[492..496]);
+
+        Some(FsInfo {
+            free_cluster_count: if free_cluster_count <= cluster_count {
+                Some(free_cluster_count)
+            } else {
+                None
+            },
+            next_free_cluster: if next_free_cluster >= 2 && next_free_cluster - 2 < cluster_count {
+                Some(next_free_cluster)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum Entry {
+    DirectoryEntry(DirectoryEntry),
+    Lfn(LfnEntry),
+    Last,
+    Empty,
+}
+
+impl Entry {
+    /// Decode an entry of a FAT32 directory.
+    pub fn new(bytes: &[u8]) -> Self {
+        Entry::with_type(bytes, FatType::Fat32)
+    }
+
+    /// Decode an entry of a directory on a volume with `fat_type`.
+    pub fn with_type(bytes: &[u8], fat_type: FatType) -> Self {
+        if bytes.len() != 32 {
+            panic!("Fat32 entry length must be 32 bytes!");
+        }
+
+        match bytes[0x00] {
+            0x00 => Entry::Last,
+            0xE5 => Entry::Empty,
+            // The top two attribute bits aren't part of the LFN marker
+            _ => match bytes[0x0B] & 0x3F {
+                0x0F => Entry::Lfn(LfnEntry::new(bytes)),
+                _ => Entry::DirectoryEntry(DirectoryEntry::new(bytes, fat_type))
+            }
+        }
+    }
+}
+
+/// Offsets of the 13 UTF-16 code units stored in an LFN entry
+const LFN_CHARACTER_OFFSETS: [usize; 13] = [
+    0x01, 0x03, 0x05, 0x07, 0x09,
+    0x0E, 0x10, 0x12, 0x14, 0x16, 0x18,
+    0x1C, 0x1E
+];
+
+/// Set on the sequence number of the LFN entry holding the end of a name,
+/// which is the first entry of the set on disk.
+const LFN_LAST_ENTRY: u8 = 0x40;
+
+#[derive(Debug)]
+pub struct LfnEntry {
+    pub sequence_number: u8,
+    pub file_name: [u16; 13],
+    /// Checksum of the 8.3 name this long name belongs to
+    pub checksum: u8
+}
+
+impl LfnEntry {
+    fn new(bytes: &[u8]) -> Self {
+        assert!(bytes.len() == 32);
+        assert_eq!(bytes[0x0B] & 0x3F, 0x0F);
+
+        let mut file_name = [0; 13];
+        // Grab little-endian UTF-16 code units from three slices in the LFN
+        // entry
+        for (filename_position, input_position) in LFN_CHARACTER_OFFSETS.iter().enumerate() {
+            file_name[filename_position] = u16::from(bytes[*input_position]) +
+                                           (u16::from(bytes[input_position+1]) << 8);
+        }
+
+        Self {
+            sequence_number: bytes[0x00],
+            file_name,
+            checksum: bytes[0x0D]
+        }
+    }
+
+    /// Position of this entry's part of the name, starting at 1.
+    fn sequence_number(&self) -> u8 {
+        self.sequence_number & !LFN_LAST_ENTRY
+    }
+
+    fn is_last(&self) -> bool {
+        self.sequence_number & LFN_LAST_ENTRY != 0
+    }
+
+    /// Number of code units of the name in this entry, which stops early at
+    /// a null character in the entry holding the end of the name.
+    fn length(&self) -> usize {
+        self.file_name.iter().position(|unit| *unit == 0).unwrap_or(LFN_CHARACTERS_PER_ENTRY)
+    }
+}
+
+/// Decode the UTF-16 `units` of a long name into `name`. Unpaired surrogates
+/// become U+FFFD.
+pub(crate) fn decode_long_name(units: &[u16], name: &mut Name) {
+    for ch in ::core::char::decode_utf16(units.iter().cloned()) {
+        let ch = ch.unwrap_or(::core::char::REPLACEMENT_CHARACTER);
+        if name.push(ch).is_err() {
+            break;
+        }
+    }
+}
+
+bitflags! {
+    pub struct DirectoryEntryFlags: u8 {
+        const READ_ONLY    = 0b0000_0001;
+        const HIDDEN       = 0b0000_0010;
+        const SYSTEM       = 0b0000_0100;
+        const VOLUME_LABEL = 0b0000_1000;
+        const SUBDIRECTORY = 0b0001_0000;
+        const ARCHIVE      = 0b0010_0000;
+        const DEVICE       = 0b0100_0000;
+        const RESERVED     = 0b1000_0000;
+    }
+}
+
+bitflags! {
+    /// How Windows NT records the case of an 8.3 name that is entirely
+    /// lowercase in its base or extension, at offset 0x0C.
+    pub struct NameCaseFlags: u8 {
+        const LOWERCASE_BASE      = 0b0000_1000;
+        const LOWERCASE_EXTENSION = 0b0001_0000;
+    }
+}
+
+/// A first byte of 0x05 in an 8.3 name stands for 0xE5, which would
+/// otherwise mark the entry as deleted.
+const ESCAPED_DELETED_MARKER: u8 = 0x05;
+
+#[derive(Debug)]
+pub struct DirectoryEntry {
+    pub file_name_bytes: [u8; 8],
+    pub file_extension_bytes: [u8; 3],
+    pub flags: DirectoryEntryFlags,
+    pub case_flags: NameCaseFlags,
+    pub created: Option<DateTime>,
+    pub modified: Option<DateTime>,
+    pub accessed: Option<DateTime>,
+    pub cluster_num: u32,
+    pub size: u32
+}
+
+/// The checksum of an 8.3 name stored in each of the LFN entries that belong
+/// to it.
+pub fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, byte| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(*byte)
+    })
+}
+
+impl DirectoryEntry {
+    fn checksum(&self) -> u8 {
+        let mut short_name = [0; 11];
+        short_name[0..8].copy_from_slice(&self.file_name_bytes);
+        short_name[8..11].copy_from_slice(&self.file_extension_bytes);
+        short_name_checksum(&short_name)
+    }
+
+    fn new(bytes: &[u8], fat_type: FatType) -> Self {
+        assert!(bytes.len() == 32);
+
+        let mut file_name_bytes = [0; 8];
+        for (idx, byte) in bytes[0..8].iter().enumerate() {
+            file_name_bytes[idx] = *byte;
+        }
+
+        let mut file_extension_bytes = [0; 3];
+        for (idx, byte) in bytes[0x08..0x0B].iter().enumerate() {
+            file_extension_bytes[idx] = *byte;
+        }
+
+        let flags = DirectoryEntryFlags { bits: bytes[0x0B] };
+        let case_flags = NameCaseFlags::from_bits_truncate(bytes[0x0C]);
+
+        let low_cluster_num  = little_endian_to_int(&bytes[0x1A..0x1C]);
+        // Only FAT32 has a high word, FAT12 and FAT16 reserve the field and
+        // OS/2 kept extended attributes there
+        let high_cluster_num = if fat_type == FatType::Fat32 {
+            little_endian_to_int(&bytes[0x14..0x16])
+        } else {
+            0
+        };
+
+        let cluster_num = (high_cluster_num << 16) + low_cluster_num;
+        let size = little_endian_to_int(&bytes[0x1C..0x20]);
+
+        let date_or_time = |offset: usize| little_endian_to_int(&bytes[offset..offset+2]) as u16;
+        let created = DateTime::from_fat(date_or_time(0x10), date_or_time(0x0E), bytes[0x0D]);
+        let modified = DateTime::from_fat(date_or_time(0x18), date_or_time(0x16), 0);
+        let accessed = DateTime::from_fat(date_or_time(0x12), 0, 0);
+
+        Self {
+            file_name_bytes,
+            file_extension_bytes,
+            flags,
+            case_flags,
+            created,
+            modified,
+            accessed,
+            cluster_num,
+            size
+        }
+    }
+
+    /// Whether this is the `.` or `..` entry at the start of a subdirectory.
+    fn is_dot_entry(&self) -> bool {
+        self.file_extension_bytes == *b"   " &&
+        (self.file_name_bytes == *b".       " || self.file_name_bytes == *b"..      ")
+    }
+
+    fn info(&self) -> FileInfo {
+        FileInfo {
+            attributes: self.flags,
+            created: self.created,
+            modified: self.modified,
+            accessed: self.accessed
+        }
+    }
+
+    /// Write the 8.3 name into `name` the way it's usually shown, such as
+    /// `README.TXT` or `FOO`, without the padding.
+    fn decode_name(&self, code_page: &CodePage, name: &mut Name) {
+        let mut base = self.file_name_bytes;
+        if base[0] == ESCAPED_DELETED_MARKER {
+            base[0] = 0xE5;
+        }
+
+        let base_length = base.iter().rposition(|b| *b != b' ').map_or(0, |p| p + 1);
+        let extension_length = self.file_extension_bytes.iter()
+            .rposition(|b| *b != b' ').map_or(0, |p| p + 1);
+
+        let lowercase_base = self.case_flags.contains(NameCaseFlags::LOWERCASE_BASE);
+        for byte in &base[..base_length] {
+            push_short_name_byte(*byte, lowercase_base, code_page, name);
+        }
+
+        if extension_length > 0 {
+            name.push('.').unwrap();
+
+            let lowercase_extension = self.case_flags.contains(NameCaseFlags::LOWERCASE_EXTENSION);
+            for byte in &self.file_extension_bytes[..extension_length] {
+                push_short_name_byte(*byte, lowercase_extension, code_page, name);
+            }
+        }
+    }
+}
+
+/// Decode a volume label, which unlike an 8.3 name is all one part.
+fn decode_label(label: &[u8; 11], code_page: &CodePage) -> Option<Name> {
+    let length = label.iter().rposition(|b| *b != b' ' && *b != 0).map_or(0, |p| p + 1);
+    if length == 0 {
+        return None;
+    }
+
+    let mut name = Name::new();
+    for byte in &label[..length] {
+        name.push(code_page.decode(*byte)).unwrap();
+    }
+    Some(name)
+}
+
+fn push_short_name_byte(byte: u8, lowercase: bool, code_page: &CodePage, name: &mut Name) {
+    let byte = if lowercase { byte.to_ascii_lowercase() } else { byte };
+    name.push(code_page.decode(byte)).unwrap();
+}
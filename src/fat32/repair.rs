@@ -0,0 +1,585 @@
+use core::str;
+
+use block_accessor::BlockAccessor;
+
+use super::{Fat32, Fat32Error, TimeSource, DirectoryEntryFlags, EntryPosition, FatEntry, FAT_SCAN_CHUNK,
+            MAX_BLOCK_SIZE};
+use super::check::{check, CheckReport, Finding};
+use super::write::{directory_entry, entry_cluster, set_entry_cluster, to_little_endian};
+
+/// Kinds of problems, fixed in order of `repair_phase`
+const REPAIR_PHASES: usize = 8;
+/// Most problems fixed from one check of the volume
+const REPAIR_BATCH: usize = 32;
+/// Recovered lost chains are named `FOUND.000/FILEnnnn.CHK`
+const FOUND_DIRECTORY: &str = "FOUND.000";
+const CHK_FILE_PATH: &[u8; 22] = b"FOUND.000/FILE0000.CHK";
+const CHK_NUMBER_OFFSET: usize = 14;
+const MAX_CHK_NUMBER: u32 = 9999;
+
+/// How `repair` goes about fixing a volume.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RepairOptions {
+    /// Log the changes that would be made without writing anything
+    pub dry_run: bool,
+    /// Keep lost chains as `FOUND.000/FILEnnnn.CHK` files rather than
+    /// freeing them
+    pub recover_lost_chains: bool
+}
+
+/// A change made by `repair`, in the terms of `Finding`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// The chain of `entry` now ends after `clusters` clusters. Clusters
+    /// that were after the end are freed, and a file left without clusters
+    /// points at cluster 0.
+    TruncatedChain { entry: Option<EntryPosition>, clusters: u32 },
+    /// The size of the file at `entry` was set to `size`
+    SetSize { entry: EntryPosition, size: u32 },
+    /// The chain of `entry` from `cluster` onwards was replaced with a copy,
+    /// so no other chain shares it
+    CopiedChain { entry: Option<EntryPosition>, cluster: u32 },
+    FreedLostChain { cluster: u32, length: u32 },
+    /// The lost chain became the file `FOUND.000/FILEnnnn.CHK`, with `number`
+    /// as nnnn
+    RecoveredLostChain { cluster: u32, length: u32, number: u32 },
+    /// `count` entries starting at `first` were marked as deleted
+    RemovedEntries { first: EntryPosition, count: u32 },
+    /// The `.` or `..` entry at `position` now points at `cluster`
+    FixedDotEntry { position: EntryPosition, cluster: u32 },
+    /// Copy `fat` of the FAT was overwritten with the first copy
+    SyncedFat { fat: u8 },
+    /// The FSInfo sector was rewritten with the free cluster count from the
+    /// FAT
+    WroteFsInfo { free_cluster_count: u32 }
+}
+
+/// Fix the problems `check` finds, calling `log` with each change.
+///
+/// Problems are fixed a kind at a time, all of them from one check of the
+/// volume. It's checked again before the next kind, so problems that earlier
+/// changes turn up are followed through. Chains are made whole before
+/// cross-links are broken, and both happen before anything is freed.
+/// Problems that can't be fixed, such as a `.` entry whose slot holds a
+/// file, are left alone.
+///
+/// With `dry_run` nothing is written, and each change is logged as it would
+/// be made to the volume as it is. Changes that would only be needed after
+/// earlier ones aren't shown.
+///
+/// Returns what a check finds afterwards. A volume that's left consistent
+/// counts as cleanly unmounted again, so `flush` marks it as clean.
+pub fn repair<B, T, F>(fat32: &mut Fat32<B, T>, options: RepairOptions, mut log: F)
+    -> Result<CheckReport, Fat32Error>
+    where B: BlockAccessor, T: TimeSource, F: FnMut(Change)
+{
+    let mut repairer = Repairer {
+        fat32,
+        options,
+        next_chk_number: 0
+    };
+
+    let mut phase = 0;
+    // The number of problems of this kind skipped because they can't be
+    // fixed, or were already logged in a dry run
+    let mut skipped = 0;
+    let mut previous = Batch::new(REPAIR_PHASES);
+
+    loop {
+        let batch = next_batch(repairer.fat32, phase, skipped);
+        if batch.len == 0 {
+            break;
+        }
+        if batch.phase != phase {
+            phase = batch.phase;
+            skipped = 0;
+        }
+
+        // Problems that are still there after they were fixed would come
+        // back forever
+        let repeated = batch == previous;
+        for (index, finding) in batch.findings[..batch.len].iter().filter_map(|finding| *finding).enumerate() {
+            // The first chain into a shared cluster keeps it, and the later
+            // ones get copies. It's no longer cross-linked once they have.
+            if batch.keeps_shared_cluster(index) {
+                if options.dry_run {
+                    skipped += 1;
+                }
+                continue;
+            }
+
+            let change = if repeated { None } else { repairer.fix(finding)? };
+            match change {
+                Some(change) => {
+                    log(change);
+                    if options.dry_run {
+                        skipped += 1;
+                    }
+                },
+                None => skipped += 1
+            }
+        }
+
+        // Only checked again for this kind if there were too many to fix at
+        // once
+        if !batch.more {
+            phase += 1;
+            skipped = 0;
+        }
+        previous = batch;
+    }
+
+    let report = check(repairer.fat32, |_| {});
+    if !options.dry_run && report.is_consistent() {
+        repairer.fat32.cleanly_unmounted = true;
+    }
+    Ok(report)
+}
+
+/// When a kind of problem is fixed, `None` for the ones that can't be.
+fn repair_phase(finding: &Finding) -> Option<usize> {
+    match *finding {
+        Finding::BrokenChain { .. } => Some(0),
+        Finding::CrossLink { .. } => Some(1),
+        Finding::SizeMismatch { .. } => Some(2),
+        Finding::LostChain { .. } => Some(3),
+        Finding::OrphanLfn { .. } | Finding::MisplacedDotEntry { .. } => Some(4),
+        Finding::BadDotEntry { .. } => Some(5),
+        Finding::FatCopyMismatch { .. } => Some(6),
+        Finding::InvalidFsInfo { .. } => Some(7),
+        Finding::TooDeep { .. } => None
+    }
+}
+
+/// Problems of one kind found by a check, to be fixed together.
+#[derive(PartialEq)]
+struct Batch {
+    phase: usize,
+    findings: [Option<Finding>; REPAIR_BATCH],
+    len: usize,
+    /// Set when there were more than fit
+    more: bool
+}
+
+impl Batch {
+    fn new(phase: usize) -> Batch {
+        Batch {
+            phase,
+            findings: [None; REPAIR_BATCH],
+            len: 0,
+            more: false
+        }
+    }
+
+    fn push(&mut self, finding: Finding) {
+        if self.len == REPAIR_BATCH {
+            self.more = true;
+            return;
+        }
+        self.findings[self.len] = Some(finding);
+        self.len += 1;
+    }
+
+    /// Whether the finding at `index` is a cross-link that a later one in
+    /// the batch shares its cluster with.
+    fn keeps_shared_cluster(&self, index: usize) -> bool {
+        let cluster = match self.findings[index] {
+            Some(Finding::CrossLink { cluster, .. }) => cluster,
+            _ => return false
+        };
+        self.findings[index + 1..self.len].iter().any(|finding| match *finding {
+            Some(Finding::CrossLink { cluster: later, .. }) => later == cluster,
+            _ => false
+        })
+    }
+}
+
+/// Check the volume and collect the problems to fix next: those of the
+/// earliest kind from `phase` on, after the `skipped` ones of `phase`.
+fn next_batch<B: BlockAccessor, T: TimeSource>(fat32: &mut Fat32<B, T>, phase: usize, skipped: u32)
+    -> Batch
+{
+    let mut seen = 0;
+    let mut batch = Batch::new(REPAIR_PHASES);
+
+    check(fat32, |finding| {
+        let finding_phase = match repair_phase(&finding) {
+            Some(finding_phase) if finding_phase >= phase => finding_phase,
+            _ => return
+        };
+
+        if finding_phase == phase {
+            seen += 1;
+            if seen <= skipped {
+                return;
+            }
+        }
+        if finding_phase < batch.phase {
+            batch = Batch::new(finding_phase);
+        }
+        if finding_phase == batch.phase {
+            batch.push(finding);
+        }
+    });
+
+    batch
+}
+
+struct Repairer<'a, B: 'a, T: 'a>
+    where B: BlockAccessor
+{
+    fat32: &'a mut Fat32<B, T>,
+    options: RepairOptions,
+    /// Where to start looking for an unused `FILEnnnn.CHK` name
+    next_chk_number: u32
+}
+
+impl<'a, B: BlockAccessor, T: TimeSource> Repairer<'a, B, T> {
+    /// Work out the change that fixes `finding` and make it, unless this is
+    /// a dry run. `None` if it can't be fixed.
+    fn fix(&mut self, finding: Finding) -> Result<Option<Change>, Fat32Error> {
+        match finding {
+            Finding::BrokenChain { entry, length } => self.fix_broken_chain(entry, length),
+            Finding::CrossLink { entry, cluster } => self.fix_cross_link(entry, cluster),
+            Finding::SizeMismatch { entry, size, clusters } => self.fix_size(entry, size, clusters),
+            Finding::LostChain { cluster, length } => self.fix_lost_chain(cluster, length),
+            Finding::OrphanLfn { first, count } => self.remove_entries(first, count),
+            Finding::MisplacedDotEntry { position } => self.remove_entries(position, 1),
+            Finding::BadDotEntry { position, cluster } => self.fix_dot_entry(position, cluster),
+            Finding::FatCopyMismatch { fat, .. } => self.sync_fat(fat),
+            Finding::InvalidFsInfo { free_clusters, .. } => self.write_fs_info(free_clusters),
+            Finding::TooDeep { .. } => Ok(None)
+        }
+    }
+
+    fn writing(&self) -> bool {
+        !self.options.dry_run
+    }
+
+    /// End the chain at its last good cluster, leaving the free or bad one
+    /// it ran into alone. A directory without any is removed, as nothing of
+    /// it can be found.
+    fn fix_broken_chain(&mut self, entry: Option<EntryPosition>, length: u32)
+        -> Result<Option<Change>, Fat32Error>
+    {
+        if length > 0 {
+            if self.writing() {
+                let first_cluster = self.first_cluster(entry);
+                let last_cluster = self.cluster_in_chain(first_cluster, length - 1);
+                self.fat32.set_fat_entry(last_cluster, FatEntry::EndOfChain)?;
+            }
+            return Ok(Some(Change::TruncatedChain { entry, clusters: length }));
+        }
+
+        let position = match entry {
+            Some(position) => position,
+            None => return Ok(None)
+        };
+        let mut bytes = self.fat32.read_entry(position);
+        if bytes[0x0B] & DirectoryEntryFlags::SUBDIRECTORY.bits() != 0 {
+            return self.remove_entries(position, 1);
+        }
+
+        if self.writing() {
+            set_entry_cluster(&mut bytes, 0);
+            self.fat32.write_entry(position, &bytes)?;
+        }
+        Ok(Some(Change::TruncatedChain { entry, clusters: 0 }))
+    }
+
+    /// Give `entry` its own copy of its chain from `cluster` onwards.
+    fn fix_cross_link(&mut self, entry: Option<EntryPosition>, cluster: u32)
+        -> Result<Option<Change>, Fat32Error>
+    {
+        let first_cluster = self.first_cluster(entry);
+        let mut previous = None;
+        let mut cluster_num = first_cluster;
+        for _ in 0..self.fat32.cluster_count() {
+            if cluster_num == cluster {
+                break;
+            }
+            previous = Some(cluster_num);
+            cluster_num = match self.fat32.cluster_number_after(cluster_num) {
+                Some(next_cluster) => next_cluster,
+                None => return Ok(None)
+            };
+        }
+
+        let position = match (previous, entry) {
+            (_, _) if cluster_num != cluster => return Ok(None),
+            // The root directory's first cluster is fixed by the BPB
+            (None, None) => return Ok(None),
+            (_, position) => position
+        };
+
+        if self.writing() {
+            let copy = match self.copy_chain(cluster) {
+                Ok(copy) => copy,
+                // Left shared when there's no room for a copy
+                Err(Fat32Error::VolumeFull) => return Ok(None),
+                Err(error) => return Err(error)
+            };
+            match (previous, position) {
+                (Some(previous), _) => self.fat32.set_fat_entry(previous, FatEntry::Next(copy))?,
+                (None, Some(position)) => {
+                    let mut bytes = self.fat32.read_entry(position);
+                    set_entry_cluster(&mut bytes, copy);
+                    self.fat32.write_entry(position, &bytes)?;
+                },
+                (None, None) => {}
+            }
+        }
+        Ok(Some(Change::CopiedChain { entry, cluster }))
+    }
+
+    /// Cut a chain that's longer than its file down to size, or shrink a file
+    /// to fit its chain.
+    fn fix_size(&mut self, entry: EntryPosition, size: u32, clusters: u32) -> Result<Option<Change>, Fat32Error> {
+        let bytes_per_cluster = self.fat32.bytes_per_cluster();
+        let clusters_needed = if size == 0 { 0 } else { (size - 1) / bytes_per_cluster + 1 };
+
+        if clusters > clusters_needed {
+            if self.writing() {
+                self.truncate_chain(entry, clusters_needed)?;
+            }
+            return Ok(Some(Change::TruncatedChain { entry: Some(entry), clusters: clusters_needed }));
+        }
+
+        // Less than `size`, so it fits
+        let size = clusters * bytes_per_cluster;
+        if self.writing() {
+            let mut bytes = self.fat32.read_entry(entry);
+            bytes[0x1C..0x20].copy_from_slice(&to_little_endian(size));
+            self.fat32.write_entry(entry, &bytes)?;
+        }
+        Ok(Some(Change::SetSize { entry, size }))
+    }
+
+    fn truncate_chain(&mut self, entry: EntryPosition, clusters: u32) -> Result<(), Fat32Error> {
+        let mut bytes = self.fat32.read_entry(entry);
+        let first_cluster = entry_cluster(&bytes, self.fat32.fat_type());
+
+        if clusters == 0 {
+            set_entry_cluster(&mut bytes, 0);
+            self.fat32.write_entry(entry, &bytes)?;
+            return self.fat32.free_chain(first_cluster);
+        }
+
+        let last_cluster = self.cluster_in_chain(first_cluster, clusters - 1);
+        let rest = self.fat32.cluster_number_after(last_cluster);
+        self.fat32.set_fat_entry(last_cluster, FatEntry::EndOfChain)?;
+        match rest {
+            Some(rest) => self.fat32.free_chain(rest),
+            None => Ok(())
+        }
+    }
+
+    /// Free a lost chain, or keep it as a file in `FOUND.000`.
+    fn fix_lost_chain(&mut self, cluster: u32, length: u32) -> Result<Option<Change>, Fat32Error> {
+        if !self.options.recover_lost_chains {
+            if self.writing() {
+                self.free_clusters(cluster, length)?;
+            }
+            return Ok(Some(Change::FreedLostChain { cluster, length }));
+        }
+
+        let number = match self.unused_chk_number() {
+            Some(number) => number,
+            None => return Ok(None)
+        };
+        self.next_chk_number = number + 1;
+
+        if self.writing() {
+            // The chain may run into a free cluster or loop, so it's ended
+            // where it was found to
+            let last_cluster = self.cluster_in_chain(cluster, length - 1);
+            self.fat32.set_fat_entry(last_cluster, FatEntry::EndOfChain)?;
+
+            match self.fat32.directory_at(FOUND_DIRECTORY) {
+                Ok(_) => {},
+                Err(Fat32Error::NotFound) => { self.fat32.create_dir(FOUND_DIRECTORY)?; },
+                Err(error) => return Err(error)
+            }
+
+            let mut path = *CHK_FILE_PATH;
+            let file = self.fat32.create_file(chk_file_path(number, &mut path))?;
+            let mut bytes = self.fat32.read_entry(file.location.short);
+            set_entry_cluster(&mut bytes, cluster);
+            let size = length.saturating_mul(self.fat32.bytes_per_cluster());
+            bytes[0x1C..0x20].copy_from_slice(&to_little_endian(size));
+            self.fat32.write_entry(file.location.short, &bytes)?;
+        }
+        Ok(Some(Change::RecoveredLostChain { cluster, length, number }))
+    }
+
+    /// The lowest `FILEnnnn.CHK` number that's not in use, from where the
+    /// last recovered chain left off.
+    fn unused_chk_number(&mut self) -> Option<u32> {
+        let mut path = *CHK_FILE_PATH;
+        (self.next_chk_number..MAX_CHK_NUMBER + 1).find(|number| {
+            self.fat32.item_info(chk_file_path(*number, &mut path)).is_none()
+        })
+    }
+
+    /// Free `length` clusters of the chain starting at `first_cluster`,
+    /// stopping early if it ends first.
+    fn free_clusters(&mut self, first_cluster: u32, length: u32) -> Result<(), Fat32Error> {
+        let mut cluster_num = first_cluster;
+        for _ in 0..length {
+            let next_cluster = self.fat32.cluster_number_after(cluster_num);
+            self.fat32.set_fat_entry(cluster_num, FatEntry::Free)?;
+            cluster_num = match next_cluster {
+                Some(next_cluster) => next_cluster,
+                None => break
+            };
+        }
+
+        self.fat32.free_cluster_hint = u32::min(self.fat32.free_cluster_hint, first_cluster);
+        self.fat32.write_fs_info()
+    }
+
+    fn remove_entries(&mut self, first: EntryPosition, count: u32) -> Result<Option<Change>, Fat32Error> {
+        if self.writing() {
+            let mut position = Some(first);
+            for _ in 0..count {
+                let current = match position {
+                    Some(current) => current,
+                    None => break
+                };
+                let mut bytes = self.fat32.read_entry(current);
+                bytes[0] = 0xE5;
+                self.fat32.write_entry(current, &bytes)?;
+                position = self.fat32.entry_after(current);
+            }
+        }
+        Ok(Some(Change::RemovedEntries { first, count }))
+    }
+
+    /// Point a `.` or `..` entry at the right cluster, or write one into its
+    /// slot if that's been deleted. Slots holding anything else are left.
+    fn fix_dot_entry(&mut self, position: EntryPosition, cluster: u32) -> Result<Option<Change>, Fat32Error> {
+        let name = if position.index == 0 { b".          " } else { b"..         " };
+        let mut bytes = self.fat32.read_entry(position);
+
+        if bytes[0..11] == name[..] {
+            bytes[0x0B] = DirectoryEntryFlags::SUBDIRECTORY.bits();
+            set_entry_cluster(&mut bytes, cluster);
+        } else if bytes[0] == 0xE5 {
+            bytes = directory_entry(name, cluster, self.fat32.time_source.now());
+        } else {
+            return Ok(None);
+        }
+
+        if self.writing() {
+            self.fat32.write_entry(position, &bytes)?;
+        }
+        Ok(Some(Change::FixedDotEntry { position, cluster }))
+    }
+
+    /// Overwrite copy `fat` of the FAT with the first copy.
+    fn sync_fat(&mut self, fat: u8) -> Result<Option<Change>, Fat32Error> {
+        if self.writing() {
+            let fat_bytes = u64::from(self.fat32.boot_sector.bpb.sectors_per_fat) *
+                            u64::from(self.fat32.bytes_per_sector());
+            let mut chunk = [0; FAT_SCAN_CHUNK];
+            let mut offset = 0;
+            while offset < fat_bytes {
+                let length = u64::min(FAT_SCAN_CHUNK as u64, fat_bytes - offset) as usize;
+                let first_offset = self.fat32.fat_copy_offset(0) + offset;
+                self.fat32.read_volume(first_offset, &mut chunk[..length]);
+                let copy_offset = self.fat32.fat_copy_offset(fat) + offset;
+                self.fat32.write_volume(copy_offset, &chunk[..length])?;
+                offset += length as u64;
+            }
+        }
+        Ok(Some(Change::SyncedFat { fat }))
+    }
+
+    fn write_fs_info(&mut self, free_clusters: u32) -> Result<Option<Change>, Fat32Error> {
+        if self.writing() {
+            if self.fat32.fs_info.is_none() {
+                self.fat32.fs_info = self.fat32.read_fs_info();
+            }
+            if let Some(ref mut info) = self.fat32.fs_info {
+                info.free_cluster_count = Some(free_clusters);
+            }
+            self.fat32.write_fs_info()?;
+        }
+        Ok(Some(Change::WroteFsInfo { free_cluster_count: free_clusters }))
+    }
+
+    /// First cluster of the item at `entry`, or of the root directory.
+    fn first_cluster(&mut self, entry: Option<EntryPosition>) -> u32 {
+        match entry {
+            Some(position) => {
+                let bytes = self.fat32.read_entry(position);
+                entry_cluster(&bytes, self.fat32.fat_type())
+            },
+            None => self.fat32.boot_sector.bpb.root_directory_cluster
+        }
+    }
+
+    /// The cluster `index` clusters into a chain that's known to be at least
+    /// that long.
+    fn cluster_in_chain(&mut self, first_cluster: u32, index: u32) -> u32 {
+        let mut cluster_num = first_cluster;
+        for _ in 0..index {
+            cluster_num = self.fat32.cluster_number_after(cluster_num).unwrap_or(cluster_num);
+        }
+        cluster_num
+    }
+
+    /// Copy the chain starting at `first_cluster` into newly allocated
+    /// clusters, returning the first one. If the volume fills up part way,
+    /// the clusters copied so far are freed again.
+    fn copy_chain(&mut self, first_cluster: u32) -> Result<u32, Fat32Error> {
+        let bytes_per_cluster = u64::from(self.fat32.bytes_per_cluster());
+        let mut block = [0; MAX_BLOCK_SIZE];
+        let mut source = first_cluster;
+        let mut previous = None;
+        let mut copy_first = 0;
+
+        for _ in 0..self.fat32.cluster_count() {
+            let copy = match self.fat32.allocate_cluster(previous) {
+                Ok(copy) => copy,
+                Err(error) => {
+                    if previous.is_some() {
+                        self.fat32.free_chain(copy_first)?;
+                    }
+                    return Err(error);
+                }
+            };
+            if previous.is_none() {
+                copy_first = copy;
+            }
+            previous = Some(copy);
+
+            let mut position = 0;
+            while position < bytes_per_cluster {
+                let length = u64::min(bytes_per_cluster - position, block.len() as u64) as usize;
+                let source_offset = self.fat32.cluster_offset(source) + position;
+                self.fat32.read_volume(source_offset, &mut block[..length]);
+                let copy_offset = self.fat32.cluster_offset(copy) + position;
+                self.fat32.write_volume(copy_offset, &block[..length])?;
+                position += length as u64;
+            }
+
+            source = match self.fat32.cluster_number_after(source) {
+                Some(next_cluster) => next_cluster,
+                None => break
+            };
+        }
+
+        Ok(copy_first)
+    }
+}
+
+/// Fill the number into `path`, which starts out as `CHK_FILE_PATH`.
+fn chk_file_path(number: u32, path: &mut [u8; 22]) -> &str {
+    let mut number = number;
+    for digit in path[CHK_NUMBER_OFFSET..CHK_NUMBER_OFFSET+4].iter_mut().rev() {
+        *digit = b'0' + (number % 10) as u8;
+        number /= 10;
+    }
+    str::from_utf8(path).unwrap_or(FOUND_DIRECTORY)
+}
@@ -0,0 +1,116 @@
+/// The earliest time FAT can store, 1980-01-01 00:00:00
+const FAT_EPOCH: DateTime = DateTime {
+    year: 1980,
+    month: 1,
+    day: 1,
+    hour: 0,
+    minute: 0,
+    second: 0,
+    millisecond: 0
+};
+/// The latest time FAT can store, 2107-12-31 23:59:58
+const FAT_LATEST: DateTime = DateTime {
+    year: 2107,
+    month: 12,
+    day: 31,
+    hour: 23,
+    minute: 59,
+    second: 58,
+    millisecond: 0
+};
+
+/// A date and time as stored in a directory entry. FAT doesn't record a
+/// time zone, so these are usually local time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    /// 1980 to 2107
+    pub year: u16,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    /// 0 to 23
+    pub hour: u8,
+    /// 0 to 59
+    pub minute: u8,
+    /// 0 to 59
+    pub second: u8,
+    /// 0 to 999. Only creation times are more precise than 2 seconds, to
+    /// 10 ms
+    pub millisecond: u16
+}
+
+impl DateTime {
+    /// Decode a FAT date and time. `fine` is the count of 10 ms units stored
+    /// with creation times, 0 for the others.
+    ///
+    /// A date of 0 means the time was never set, which gives `None`.
+    pub fn from_fat(date: u16, time: u16, fine: u8) -> Option<DateTime> {
+        if date == 0 {
+            return None;
+        }
+
+        // Only 0 to 199 are valid, covering the two seconds of a FAT time
+        let fine = if fine < 200 { fine } else { 0 };
+
+        Some(DateTime {
+            year: 1980 + (date >> 9),
+            month: ((date >> 5) & 0x0F) as u8,
+            day: (date & 0x1F) as u8,
+            hour: (time >> 11) as u8,
+            minute: ((time >> 5) & 0x3F) as u8,
+            second: (time & 0x1F) as u8 * 2 + fine / 100,
+            millisecond: u16::from(fine % 100) * 10
+        })
+    }
+
+    /// Encode as a FAT date, time and count of 10 ms units. Times outside
+    /// the years FAT can store become the earliest or latest time it can,
+    /// and each other field is clamped to its range so it can't spill into
+    /// its neighbours.
+    pub fn to_fat(&self) -> (u16, u16, u8) {
+        if self.year < 1980 {
+            return FAT_EPOCH.to_fat();
+        }
+        if self.year > 2107 {
+            return FAT_LATEST.to_fat();
+        }
+        let year = self.year - 1980;
+        let month = u16::from(self.month.clamp(1, 12));
+        let day = u16::from(self.day.clamp(1, 31));
+        let hour = u16::from(self.hour.min(23));
+        let minute = u16::from(self.minute.min(59));
+        let second = self.second.min(59);
+        let centiseconds = (self.millisecond.min(999) / 10) as u8;
+
+        let date = (year << 9) | (month << 5) | day;
+        let time = (hour << 11) | (minute << 5) | u16::from(second / 2);
+        let fine = (second % 2) * 100 + centiseconds;
+
+        (date, time, fine)
+    }
+}
+
+/// A clock used to timestamp the files and directories that are created or
+/// changed, such as an RTC chip or GPS receiver.
+pub trait TimeSource {
+    fn now(&self) -> DateTime;
+}
+
+/// A time source that always gives the same time, for devices without a
+/// clock.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedTime(pub DateTime);
+
+impl Default for FixedTime {
+    /// The earliest time FAT can store
+    fn default() -> Self {
+        FixedTime(FAT_EPOCH)
+    }
+}
+
+impl TimeSource for FixedTime {
+    fn now(&self) -> DateTime {
+        self.0
+    }
+}
@@ -0,0 +1,1150 @@
+use core::ops::Range;
+
+use block_accessor::BlockAccessor;
+use byte_util::little_endian_to_int;
+
+use super::{Fat32, Fat32Error, BootSectorCopy, FatEntry, File, Directory, DirectoryItem, DirectoryEntry, DateTime,
+            FixedTime, TimeSource,
+            DirectoryEntryFlags, EntryLocation, EntryPosition, short_name_checksum,
+            FatType, FsInfo, NameCaseFlags, BOOT_REGION_SECTORS, BYTES_PER_DIRECTORY_ENTRY, FS_INFO_COUNTS_OFFSET, FS_INFO_UNKNOWN, LFN_CHARACTER_OFFSETS,
+            LFN_CHARACTERS_PER_ENTRY, LFN_LAST_ENTRY, MAX_BLOCK_SIZE, MAX_LFN_LENGTH};
+use path::split_path;
+
+/// Tails from `~1` up to this are tried for an 8.3 alias before moving on to
+/// a hashed basis
+const ALIAS_TAILS: usize = 256;
+
+impl<B: BlockAccessor, T: TimeSource> Fat32<B, T> {
+    /// Write `data` starting at `volume_offset` bytes from the start of the
+    /// volume.
+    ///
+    /// Whole blocks are written straight from `data`, partial blocks are read
+    /// first so the bytes around `data` are preserved.
+    ///
+    /// The volume is marked as dirty before its first write.
+    pub(super) fn write_volume(&mut self, volume_offset: u64, data: &[u8]) -> Result<(), Fat32Error> {
+        if !self.marked_dirty {
+            self.marked_dirty = true;
+            if let Err(error) = self.set_clean_flag(false) {
+                self.marked_dirty = false;
+                return Err(error);
+            }
+        }
+
+        let block_size = self.block_storage.block_size();
+        let mut block = [0; MAX_BLOCK_SIZE];
+
+        let mut position = 0;
+        while position < data.len() {
+            let address = volume_offset + position as u64;
+            let block_num = u64::from(self.physical_start_block) + address / block_size;
+            let offset_in_block = (address % block_size) as usize;
+            let length = usize::min(block_size as usize - offset_in_block,
+                                    data.len() - position);
+
+            if length == block_size as usize {
+                self.block_storage.write_block(block_num, &data[position..position+length])?;
+            } else {
+                let block = &mut block[..block_size as usize];
+                self.block_storage.read_block(block_num, block);
+                block[offset_in_block..offset_in_block+length]
+                    .copy_from_slice(&data[position..position+length]);
+                self.block_storage.write_block(block_num, block)?;
+            }
+
+            position += length;
+        }
+
+        Ok(())
+    }
+
+    /// Update the entry for `cluster_num` in every copy of the file
+    /// allocation table, or only the active one when mirroring is disabled.
+    /// The bits around the entry are preserved, which are the reserved top 4
+    /// bits on FAT32 and half of a neighbouring entry on FAT12.
+    ///
+    /// Changes to the number of free clusters are recorded for the FSInfo
+    /// sector, which is written out by the calls that allocate and free
+    /// clusters.
+    ///
+    /// Fails with `InvalidFatEntry` if either cluster is outside the volume,
+    /// or the entry is `Reserved`.
+    pub fn set_fat_entry(&mut self, cluster_num: u32, entry: FatEntry) -> Result<(), Fat32Error> {
+        if !self.is_valid_cluster(cluster_num) {
+            return Err(Fat32Error::InvalidFatEntry);
+        }
+
+        let fat_type = self.fat_type();
+        let mask = fat_type.entry_mask();
+        let value: u32 = match entry {
+            FatEntry::Free => 0,
+            FatEntry::Next(next_cluster) if self.is_valid_cluster(next_cluster) => next_cluster,
+            FatEntry::Bad => mask - 8,
+            FatEntry::EndOfChain => mask,
+            FatEntry::Next(_) | FatEntry::Reserved => return Err(Fat32Error::InvalidFatEntry)
+        };
+
+        let entry_offset = self.fat_offset() + fat_type.entry_offset(cluster_num);
+        let entry_bytes = fat_type.entry_bytes();
+        let mut raw_entry = [0; 4];
+        self.read_volume(entry_offset, &mut raw_entry[..entry_bytes]);
+        let was_free = FatEntry::with_type(fat_type.decode_entry(cluster_num, &raw_entry[..entry_bytes]),
+                                           fat_type, self.cluster_count()) == FatEntry::Free;
+        let raw_entry = little_endian_to_int(&raw_entry[..entry_bytes]);
+
+        let raw_entry = match fat_type {
+            FatType::Fat12 if cluster_num % 2 == 1 => (raw_entry & 0x000F) | (value << 4),
+            FatType::Fat12 => (raw_entry & 0xF000) | value,
+            FatType::Fat16 => value,
+            FatType::Fat32 => (raw_entry & 0xF000_0000) | value
+        };
+
+        for fat_num in self.written_fats() {
+            let copy_offset = self.fat_copy_offset(fat_num) + fat_type.entry_offset(cluster_num);
+            self.write_volume(copy_offset, &to_little_endian(raw_entry)[..entry_bytes])?;
+        }
+
+        let is_free = entry == FatEntry::Free;
+        if was_free != is_free {
+            let cluster_count = self.cluster_count();
+            if let Some(ref mut info) = self.fs_info {
+                info.free_cluster_count = info.free_cluster_count.and_then(|count| {
+                    if is_free { count.checked_add(1) } else { count.checked_sub(1) }
+                }).filter(|count| *count <= cluster_count);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The copies of the FAT that are written to. With mirroring disabled
+    /// the other copies are left alone.
+    fn written_fats(&self) -> Range<u8> {
+        let bpb = &self.boot_sector.bpb;
+        if bpb.is_mirroring_disabled() {
+            bpb.active_fat()..bpb.active_fat() + 1
+        } else {
+            0..bpb.number_of_fats
+        }
+    }
+
+    /// Set or clear the clean shutdown flag in FAT[1].
+    fn set_clean_flag(&mut self, clean: bool) -> Result<(), Fat32Error> {
+        let fat_type = self.fat_type();
+        let clean_bit = match fat_type.volume_flag_bits() {
+            Some((clean_bit, _)) => clean_bit,
+            None => return Ok(())
+        };
+
+        let flags = self.volume_flags();
+        let flags = if clean { flags | clean_bit } else { flags & !clean_bit };
+        for fat_num in self.written_fats() {
+            let offset = self.fat_copy_offset(fat_num) + fat_type.entry_offset(1);
+            self.write_volume(offset, &to_little_endian(flags)[..fat_type.entry_bytes()])?;
+        }
+        Ok(())
+    }
+
+    /// Mark the volume as cleanly unmounted again once everything written
+    /// so far is on disk. Writing to it afterwards marks it as dirty again.
+    ///
+    /// A volume that wasn't clean when it was mounted is left dirty, so it
+    /// still gets checked.
+    pub fn flush(&mut self) -> Result<(), Fat32Error> {
+        if self.marked_dirty && self.cleanly_unmounted {
+            self.set_clean_flag(true)?;
+            self.marked_dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Flush the volume and give back its storage.
+    pub fn unmount(mut self) -> Result<B, Fat32Error> {
+        self.flush()?;
+        Ok(self.block_storage)
+    }
+
+    /// Write the free cluster count and the next free cluster to the FSInfo
+    /// sector that goes with the boot sector in use, if the volume has one.
+    pub(super) fn write_fs_info(&mut self) -> Result<(), Fat32Error> {
+        let next_free_cluster = if self.is_valid_cluster(self.free_cluster_hint) {
+            Some(self.free_cluster_hint)
+        } else {
+            None
+        };
+
+        let info = match self.fs_info {
+            Some(ref mut info) => {
+                info.next_free_cluster = next_free_cluster;
+                *info
+            },
+            None => return Ok(())
+        };
+
+        let FsInfo { free_cluster_count, next_free_cluster } = info;
+        let mut counts = [0; 8];
+        counts[0..4].copy_from_slice(&to_little_endian(free_cluster_count.unwrap_or(FS_INFO_UNKNOWN)));
+        counts[4..8].copy_from_slice(&to_little_endian(next_free_cluster.unwrap_or(FS_INFO_UNKNOWN)));
+
+        match self.fs_info_offset() {
+            Some(offset) => self.write_volume(offset + FS_INFO_COUNTS_OFFSET, &counts),
+            None => Ok(())
+        }
+    }
+
+    /// Overwrite one copy of the boot sectors with the other, `from` being
+    /// the copy that's kept. The boot sector, FSInfo sector and the boot code
+    /// after them are copied.
+    ///
+    /// Use this to repair the primary boot sector after mounting from the
+    /// backup, or to refresh the backup after changing the boot sector.
+    pub fn copy_boot_sector(&mut self, from: BootSectorCopy) -> Result<(), Fat32Error> {
+        let backup_sector = self.backup_boot_sector().ok_or(Fat32Error::NoBackupBootSector)?;
+        let (source, destination) = match from {
+            BootSectorCopy::Primary => (0, backup_sector),
+            BootSectorCopy::Backup => (backup_sector, 0)
+        };
+
+        let bytes_per_sector = self.bytes_per_sector();
+        let mut sector = [0; MAX_BLOCK_SIZE];
+        let sector = &mut sector[..bytes_per_sector as usize];
+        for i in 0..BOOT_REGION_SECTORS {
+            self.read_volume(u64::from(source + i) * u64::from(bytes_per_sector), sector);
+            self.write_volume(u64::from(destination + i) * u64::from(bytes_per_sector), sector)?;
+        }
+
+        if from == BootSectorCopy::Backup {
+            // The primary boot sector is good again, so its FSInfo sector
+            // is the one kept up to date
+            self.mounted_from_backup = false;
+
+            // The backup's counts are from whenever it was made. Keep the
+            // ones in use, and when there are none don't trust its free count.
+            if self.fs_info.is_none() {
+                self.fs_info = self.read_fs_info().map(|info| FsInfo { free_cluster_count: None, ..info });
+                if let Some(FsInfo { next_free_cluster: Some(cluster), .. }) = self.fs_info {
+                    self.free_cluster_hint = cluster;
+                }
+            }
+            self.write_fs_info()?;
+        }
+
+        Ok(())
+    }
+
+    /// Find a free cluster, mark it as the end of a chain and append it to
+    /// the chain ending in `previous`, if there is one.
+    pub(super) fn allocate_cluster(&mut self, previous: Option<u32>) -> Result<u32, Fat32Error> {
+        let cluster_num = self.find_free_cluster().ok_or(Fat32Error::VolumeFull)?;
+
+        // The new cluster is terminated before it's linked, so the chain is
+        // never left pointing at a free cluster
+        self.set_fat_entry(cluster_num, FatEntry::EndOfChain)?;
+        if let Some(previous) = previous {
+            self.set_fat_entry(previous, FatEntry::Next(cluster_num))?;
+        }
+
+        self.free_cluster_hint = cluster_num + 1;
+        self.write_fs_info()?;
+        Ok(cluster_num)
+    }
+
+    /// Scan the FAT for a free cluster, starting from where the last cluster
+    /// was allocated and wrapping around to the start of the volume.
+    fn find_free_cluster(&mut self) -> Option<u32> {
+        let first_cluster = 2;
+        let end_cluster = self.cluster_count() + 2;
+        let hint = if self.free_cluster_hint >= first_cluster && self.free_cluster_hint < end_cluster {
+            self.free_cluster_hint
+        } else {
+            first_cluster
+        };
+
+        self.find_free_cluster_in(hint, end_cluster)
+            .or_else(|| self.find_free_cluster_in(first_cluster, hint))
+    }
+
+    fn find_free_cluster_in(&mut self, start_cluster: u32, end_cluster: u32) -> Option<u32> {
+        let mut free_cluster = None;
+        self.scan_fat(start_cluster, end_cluster, |cluster_num, entry| {
+            if entry == FatEntry::Free {
+                free_cluster = Some(cluster_num);
+            }
+            free_cluster.is_none()
+        });
+        free_cluster
+    }
+
+    pub(super) fn read_entry(&mut self, position: EntryPosition) -> [u8; 32] {
+        let mut entry = [0; 32];
+        let offset = self.entry_offset(position);
+        self.read_volume(offset, &mut entry);
+        entry
+    }
+
+    pub(super) fn write_entry(&mut self, position: EntryPosition, entry: &[u8; 32]) -> Result<(), Fat32Error> {
+        let offset = self.entry_offset(position);
+        self.write_volume(offset, entry)
+    }
+
+    /// Find `count` consecutive unused entries in `directory`, returning the
+    /// position of the first one. The directory grows by a cluster at a time
+    /// if it doesn't have enough room.
+    fn find_free_entries(&mut self, directory: &Directory, count: u32) -> Result<EntryPosition, Fat32Error> {
+        let mut run_start = None;
+        let mut run_length = 0;
+        let mut last_cluster = None;
+
+        {
+            let mut entries = self.iter_dir(directory);
+            while let Some((position, entry)) = entries.next_entry() {
+                last_cluster = Some(position.cluster);
+
+                match entry[0] {
+                    0x00 | 0xE5 => {
+                        if run_start.is_none() {
+                            run_start = Some(position);
+                        }
+                        run_length += 1;
+
+                        if run_length == count {
+                            return Ok(run_start.unwrap());
+                        }
+                    },
+                    _ => {
+                        run_start = None;
+                        run_length = 0;
+                    }
+                }
+            }
+
+            // A looped chain has no last cluster to grow from
+            if entries.looped {
+                return Err(Fat32Error::CorruptChain);
+            }
+        }
+
+        // Not enough room, so the directory gets new clusters. A run of free
+        // entries at the end of the last cluster carries on into them.
+        let entries_per_cluster = self.bytes_per_cluster() / BYTES_PER_DIRECTORY_ENTRY;
+        let mut last_cluster = last_cluster.ok_or(Fat32Error::CorruptChain)?;
+        if self.is_root_region(last_cluster) {
+            // The fixed root directory region can't grow
+            return Err(Fat32Error::DirectoryFull);
+        }
+        while run_length < count {
+            let cluster_num = self.allocate_cluster(Some(last_cluster))?;
+            self.zero_cluster(cluster_num)?;
+
+            if run_start.is_none() {
+                run_start = Some(EntryPosition { cluster: cluster_num, index: 0 });
+            }
+            run_length += entries_per_cluster;
+            last_cluster = cluster_num;
+        }
+
+        Ok(run_start.unwrap())
+    }
+
+    fn zero_cluster(&mut self, cluster_num: u32) -> Result<(), Fat32Error> {
+        let zeros = [0; MAX_BLOCK_SIZE];
+        let cluster_offset = self.cluster_offset(cluster_num);
+        let bytes_per_cluster = u64::from(self.bytes_per_cluster());
+
+        let mut position = 0;
+        while position < bytes_per_cluster {
+            let length = u64::min(bytes_per_cluster - position, zeros.len() as u64);
+            self.write_volume(cluster_offset + position, &zeros[..length as usize])?;
+            position += length;
+        }
+
+        Ok(())
+    }
+
+    /// The position of the entry after `position` in a directory.
+    pub(super) fn entry_after(&mut self, position: EntryPosition) -> Option<EntryPosition> {
+        let entries_per_cluster = self.entries_in_cluster(position.cluster);
+
+        if position.index + 1 < entries_per_cluster {
+            Some(EntryPosition { cluster: position.cluster, index: position.index + 1 })
+        } else if self.is_root_region(position.cluster) {
+            None
+        } else {
+            self.cluster_number_after(position.cluster)
+                .map(|cluster| EntryPosition { cluster, index: 0 })
+        }
+    }
+
+    /// Mark all of an item's entries, LFN entries included, as deleted.
+    fn delete_entries(&mut self, location: EntryLocation) -> Result<(), Fat32Error> {
+        let mut position = location.first;
+
+        loop {
+            let mut entry = self.read_entry(position);
+            entry[0] = 0xE5;
+            self.write_entry(position, &entry)?;
+
+            if position == location.short {
+                return Ok(());
+            }
+
+            position = self.entry_after(position).ok_or(Fat32Error::CorruptChain)?;
+        }
+    }
+
+    /// Free every cluster in the chain starting at `first_cluster`.
+    pub(super) fn free_chain(&mut self, first_cluster: u32) -> Result<(), Fat32Error> {
+        if !self.is_valid_cluster(first_cluster) {
+            return Ok(());
+        }
+
+        // A chain can't be longer than the volume, this stops looped chains
+        // from being followed forever
+        let mut cluster_num = first_cluster;
+        for _ in 0..self.cluster_count() {
+            let entry = self.fat_entry(cluster_num);
+            self.set_fat_entry(cluster_num, FatEntry::Free)?;
+
+            match entry {
+                FatEntry::Next(next_cluster) => cluster_num = next_cluster,
+                _ => break
+            }
+        }
+
+        self.free_cluster_hint = u32::min(self.free_cluster_hint, first_cluster);
+        self.write_fs_info()
+    }
+
+    /// Write the entries for a new item called `name` into `directory`,
+    /// taking everything but the name from the 8.3 entry `template`.
+    ///
+    /// Names that don't fit in an 8.3 entry get a set of LFN entries and a
+    /// unique `NAME~N.EXT` alias.
+    fn create_entries(&mut self, directory: &Directory, name: &str, template: &[u8; 32])
+        -> Result<EntryLocation, Fat32Error>
+    {
+        let mut long_name = [0; MAX_LFN_LENGTH];
+        let long_name_length = encode_long_name(name, &mut long_name)?;
+        let long_name = &long_name[..long_name_length];
+
+        let (short_name, case_flags, lfn_entries) = match short_name_with_case(name) {
+            Some((short_name, case_flags)) => (short_name, case_flags, 0),
+            None => {
+                let alias = self.unique_alias(directory, name)?;
+                let lfn_entries = (long_name_length - 1) / LFN_CHARACTERS_PER_ENTRY + 1;
+                (alias, NameCaseFlags::empty(), lfn_entries as u8)
+            }
+        };
+
+        let first = self.find_free_entries(directory, u32::from(lfn_entries) + 1)?;
+        let checksum = short_name_checksum(&short_name);
+
+        // LFN entries are stored in reverse order, ahead of the 8.3 entry. If
+        // writing stops part of the way through they're orphans, and ignored.
+        let mut position = first;
+        for sequence_number in (1..=lfn_entries).rev() {
+            let entry = lfn_entry(long_name, sequence_number, sequence_number == lfn_entries, checksum);
+            self.write_entry(position, &entry)?;
+            position = self.entry_after(position).ok_or(Fat32Error::CorruptChain)?;
+        }
+
+        let mut entry = *template;
+        entry[0..11].copy_from_slice(&short_name);
+        entry[0x0C] = case_flags.bits();
+        self.write_entry(position, &entry)?;
+
+        Ok(EntryLocation {
+            first,
+            short: position
+        })
+    }
+
+    /// Generate an 8.3 alias for `name` that isn't used in `directory`, the
+    /// way Windows does: invalid characters become `_`, and the base name is
+    /// cut short to fit a `~N` tail. Once the tails run out, the base name
+    /// is swapped for one made from a hash of the name.
+    ///
+    /// Each basis takes one pass over the directory, which picks out the
+    /// tails already in use.
+    fn unique_alias(&mut self, directory: &Directory, name: &str) -> Result<[u8; 11], Fat32Error> {
+        let basis = alias_basis(name);
+        let hashed_basis = hashed_alias_basis(basis, name);
+
+        for &(basis, base_length) in &[basis, hashed_basis] {
+            let mut used_tails = [0u8; ALIAS_TAILS / 8];
+            let mut entries = self.iter_dir(directory);
+            while let Some((_, entry)) = entries.next_entry() {
+                match entry[0] {
+                    0x00 => break,
+                    0xE5 => continue,
+                    _ if entry[0x0B] == 0x0F => continue,
+                    _ => if let Some(number) = alias_tail(&basis, base_length, &entry) {
+                        used_tails[number / 8] |= 1 << (number % 8);
+                    }
+                }
+            }
+
+            let unused = (1..ALIAS_TAILS).find(|number| used_tails[number / 8] & (1 << (number % 8)) == 0);
+            if let Some(number) = unused {
+                return Ok(alias_with_tail(&basis, base_length, number));
+            }
+        }
+
+        Err(Fat32Error::AlreadyExists)
+    }
+
+    /// Create an empty file at `path`. The parent directory must already
+    /// exist.
+    pub fn create_file(&mut self, path: &str) -> Result<File, Fat32Error> {
+        let (parent_path, name) = split_path(path);
+        let parent = self.directory_at(parent_path)?;
+
+        if self.find_item(&parent, name).is_some() {
+            return Err(Fat32Error::AlreadyExists);
+        }
+
+        let mut entry = [0; 32];
+        entry[0x0B] = DirectoryEntryFlags::ARCHIVE.bits();
+        set_entry_created(&mut entry, self.time_source.now());
+        let location = self.create_entries(&parent, name, &entry)?;
+
+        let mut file_name = ::heapless::String::new();
+        file_name.push_str(name).map_err(|_| Fat32Error::InvalidName)?;
+
+        Ok(File {
+            name: file_name,
+            cluster: 0,
+            size: 0,
+            location,
+            info: DirectoryEntry::new(&entry, self.fat_type()).info()
+        })
+    }
+
+    /// Delete the file at `path` and free its clusters.
+    pub fn remove(&mut self, path: &str) -> Result<(), Fat32Error> {
+        let file = match self.item_info(path) {
+            Some(DirectoryItem::File(f)) => f,
+            Some(DirectoryItem::Directory(_)) => return Err(Fat32Error::NotAFile),
+            None => return Err(Fat32Error::NotFound)
+        };
+
+        // The entries go first, so a power loss part way through leaves
+        // lost clusters rather than a file pointing at free ones
+        self.delete_entries(file.location)?;
+        self.free_chain(file.cluster)
+    }
+
+    /// Change the size of the file at `path` to `length` bytes. Clusters past
+    /// the new end of the file are freed, a file that grows is padded with
+    /// zeros.
+    pub fn truncate(&mut self, path: &str, length: u32) -> Result<(), Fat32Error> {
+        let file = match self.item_info(path) {
+            Some(DirectoryItem::File(f)) => f,
+            Some(DirectoryItem::Directory(_)) => return Err(Fat32Error::NotAFile),
+            None => return Err(Fat32Error::NotFound)
+        };
+
+        if length > file.size {
+            let zeros = [0; 512];
+            let mut writer = self.append_file(&file)?;
+            while writer.len() < u64::from(length) {
+                let chunk = u64::min(u64::from(length) - writer.len(), zeros.len() as u64);
+                writer.write(&zeros[..chunk as usize])?;
+            }
+            return Ok(());
+        }
+
+        let mut entry = self.read_entry(file.location.short);
+        set_entry_modified(&mut entry, self.time_source.now());
+
+        if length == 0 {
+            set_entry_cluster(&mut entry, 0);
+            entry[0x1C..0x20].copy_from_slice(&to_little_endian(0));
+            self.write_entry(file.location.short, &entry)?;
+            return self.free_chain(file.cluster);
+        }
+
+        let clusters_in_use = (length - 1) / self.bytes_per_cluster() + 1;
+        let last_cluster = self.cluster_in_chain(file.cluster, clusters_in_use - 1)?;
+
+        entry[0x1C..0x20].copy_from_slice(&to_little_endian(length));
+        self.write_entry(file.location.short, &entry)?;
+
+        if let Some(next_cluster) = self.cluster_number_after(last_cluster) {
+            self.set_fat_entry(last_cluster, FatEntry::EndOfChain)?;
+            self.free_chain(next_cluster)?;
+        }
+
+        Ok(())
+    }
+
+    /// Move the file or directory at `from` to `to`, which may be in a
+    /// different directory. Only the directory entries move, file data stays
+    /// where it is.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), Fat32Error> {
+        let (location, moved_directory) = match self.item_info(from) {
+            Some(DirectoryItem::File(f)) => (f.location, None),
+            Some(DirectoryItem::Directory(d)) => match d.location {
+                Some(location) => (location, Some(d.cluster)),
+                // The root directory can't be moved
+                None => return Err(Fat32Error::InvalidPath)
+            },
+            None => return Err(Fat32Error::NotFound)
+        };
+
+        let (parent_path, name) = split_path(to);
+        let parent = self.directory_at(parent_path)?;
+
+        // Renaming an item to its own name in a different case is fine
+        match self.find_item(&parent, name) {
+            Some(ref existing) if existing.location() != Some(location) => {
+                return Err(Fat32Error::AlreadyExists);
+            },
+            _ => {}
+        }
+
+        let parent_cluster = self.directory_cluster(parent.cluster);
+        if let Some(directory_cluster) = moved_directory {
+            if self.is_same_or_ancestor(directory_cluster, parent_cluster) {
+                return Err(Fat32Error::InvalidPath);
+            }
+        }
+
+        // The new entries are written before the old ones are removed, so a
+        // power loss leaves the item in both places rather than in neither
+        let mut entry = self.read_entry(location.short);
+        set_accessed_date(&mut entry, self.time_source.now());
+        self.create_entries(&parent, name, &entry)?;
+        self.delete_entries(location)?;
+
+        if let Some(directory_cluster) = moved_directory {
+            self.set_parent_entry(directory_cluster, parent_cluster)?;
+        }
+
+        Ok(())
+    }
+
+    /// Check whether `directory_cluster` is `cluster_num`, or one of the
+    /// directories above it.
+    fn is_same_or_ancestor(&mut self, directory_cluster: u32, mut cluster_num: u32) -> bool {
+        let root_cluster = self.directory_cluster(0);
+
+        for _ in 0..self.cluster_count() {
+            if cluster_num == directory_cluster {
+                return true;
+            }
+            if cluster_num == root_cluster || !self.is_valid_cluster(cluster_num) {
+                return false;
+            }
+
+            // The `..` entry is always the second entry of a directory
+            let dotdot = self.read_entry(EntryPosition { cluster: cluster_num, index: 1 });
+            cluster_num = self.directory_cluster(entry_cluster(&dotdot, self.fat_type()));
+        }
+
+        false
+    }
+
+    /// Point the `..` entry of a directory at a new parent directory.
+    fn set_parent_entry(&mut self, directory_cluster: u32, parent_cluster: u32) -> Result<(), Fat32Error> {
+        let position = EntryPosition { cluster: directory_cluster, index: 1 };
+        let mut dotdot = self.read_entry(position);
+        if dotdot[0..11] != b"..         "[..] {
+            return Err(Fat32Error::CorruptChain);
+        }
+
+        // `..` refers to the root directory as cluster 0
+        let parent_cluster = if parent_cluster == self.directory_cluster(0) {
+            0
+        } else {
+            parent_cluster
+        };
+
+        set_entry_cluster(&mut dotdot, parent_cluster);
+        self.write_entry(position, &dotdot)
+    }
+
+    /// Create an empty directory at `path`. The parent directory must
+    /// already exist.
+    pub fn create_dir(&mut self, path: &str) -> Result<Directory, Fat32Error> {
+        let (parent_path, name) = split_path(path);
+        let parent = self.directory_at(parent_path)?;
+
+        if self.find_item(&parent, name).is_some() {
+            return Err(Fat32Error::AlreadyExists);
+        }
+
+        let cluster_num = self.allocate_cluster(None)?;
+        self.zero_cluster(cluster_num)?;
+
+        // `..` refers to the root directory as cluster 0
+        let parent_cluster = self.directory_cluster(parent.cluster);
+        let parent_cluster = if parent_cluster == self.directory_cluster(0) {
+            0
+        } else {
+            parent_cluster
+        };
+
+        let now = self.time_source.now();
+        let dot = directory_entry(b".          ", cluster_num, now);
+        self.write_entry(EntryPosition { cluster: cluster_num, index: 0 }, &dot)?;
+        let dotdot = directory_entry(b"..         ", parent_cluster, now);
+        self.write_entry(EntryPosition { cluster: cluster_num, index: 1 }, &dotdot)?;
+
+        // The directory is complete before it's linked into its parent
+        let entry = directory_entry(&[b' '; 11], cluster_num, now);
+        let location = match self.create_entries(&parent, name, &entry) {
+            Ok(location) => location,
+            Err(e) => {
+                self.free_chain(cluster_num)?;
+                return Err(e);
+            }
+        };
+
+        let mut directory_name = ::heapless::String::new();
+        directory_name.push_str(name).map_err(|_| Fat32Error::InvalidName)?;
+
+        Ok(Directory {
+            name: directory_name,
+            cluster: cluster_num,
+            location: Some(location),
+            info: DirectoryEntry::new(&entry, self.fat_type()).info()
+        })
+    }
+
+    /// Delete the empty directory at `path`.
+    pub fn remove_dir(&mut self, path: &str) -> Result<(), Fat32Error> {
+        let directory = self.directory_at(path)?;
+        let location = directory.location.ok_or(Fat32Error::InvalidPath)?;
+
+        {
+            let mut entries = self.iter_dir(&directory);
+            while let Some((_, entry)) = entries.next_entry() {
+                match entry[0] {
+                    0x00 => break,
+                    0xE5 | b'.' => continue,
+                    _ => if entry[0x0B] != 0x0F {
+                        return Err(Fat32Error::DirectoryNotEmpty);
+                    }
+                }
+            }
+        }
+
+        self.delete_entries(location)?;
+        self.free_chain(directory.cluster)
+    }
+
+    /// Set the timestamps of the file or directory at `path`. Times given as
+    /// `None` are left as they are.
+    pub fn set_times(&mut self, path: &str, created: Option<DateTime>, modified: Option<DateTime>,
+                     accessed: Option<DateTime>) -> Result<(), Fat32Error>
+    {
+        let location = match self.item_info(path) {
+            Some(item) => item.location().ok_or(Fat32Error::InvalidPath)?,
+            None => return Err(Fat32Error::NotFound)
+        };
+
+        let mut entry = self.read_entry(location.short);
+        if let Some(created) = created {
+            set_created_time(&mut entry, created);
+        }
+        if let Some(modified) = modified {
+            set_modified_time(&mut entry, modified);
+        }
+        if let Some(accessed) = accessed {
+            set_accessed_date(&mut entry, accessed);
+        }
+        self.write_entry(location.short, &entry)
+    }
+
+    /// Open `file` for appending data to its end. Clusters the file already
+    /// has past its end, like ones allocated ahead of time, are filled
+    /// before any more are allocated.
+    pub fn append_file(&mut self, file: &File) -> Result<FileWriter<B, T>, Fat32Error> {
+        let bytes_per_cluster = self.bytes_per_cluster();
+
+        let last_cluster = if file.size == 0 {
+            None
+        } else {
+            let clusters_in_use = (file.size - 1) / bytes_per_cluster + 1;
+            Some(self.cluster_in_chain(file.cluster, clusters_in_use - 1)?)
+        };
+
+        // An empty file may still have a chain, which is kept
+        let first_cluster = if self.is_valid_cluster(file.cluster) { file.cluster } else { 0 };
+
+        Ok(FileWriter {
+            fat32: self,
+            location: file.location,
+            first_cluster,
+            last_cluster,
+            size: file.size
+        })
+    }
+
+    /// Walk `chain_index` steps along the chain starting at `first_cluster`.
+    fn cluster_in_chain(&mut self, first_cluster: u32, chain_index: u32) -> Result<u32, Fat32Error> {
+        if !self.is_valid_cluster(first_cluster) {
+            return Err(Fat32Error::CorruptChain);
+        }
+
+        let mut cluster_num = first_cluster;
+        for _ in 0..chain_index {
+            cluster_num = self.cluster_number_after(cluster_num).ok_or(Fat32Error::CorruptChain)?;
+        }
+
+        Ok(cluster_num)
+    }
+}
+
+/// Appends data to the end of a file.
+///
+/// The file's directory entry is updated after every write, so the data that
+/// was written is reachable even if the writer is never dropped cleanly.
+pub struct FileWriter<'a, B: 'a, T: 'a = FixedTime>
+    where B: BlockAccessor
+{
+    fat32: &'a mut Fat32<B, T>,
+    location: EntryLocation,
+    /// 0 until the file has a cluster
+    first_cluster: u32,
+    /// The cluster holding the end of the data, `None` while there's none
+    last_cluster: Option<u32>,
+    size: u32
+}
+
+impl<'a, B: BlockAccessor, T: TimeSource> FileWriter<'a, B, T> {
+    /// The size of the file in bytes.
+    pub fn len(&self) -> u64 {
+        u64::from(self.size)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Append all of `data` to the file, allocating clusters as needed.
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, Fat32Error> {
+        if u64::from(self.size) + data.len() as u64 > u64::from(u32::MAX) {
+            return Err(Fat32Error::FileTooLarge);
+        }
+
+        let result = self.write_clusters(data);
+
+        // Record whatever made it to the disk, even if the write failed part
+        // of the way through
+        self.update_entry()?;
+        result.map(|_| data.len())
+    }
+
+    fn write_clusters(&mut self, data: &[u8]) -> Result<(), Fat32Error> {
+        let bytes_per_cluster = self.fat32.bytes_per_cluster();
+
+        let mut written = 0;
+        while written < data.len() {
+            let offset_in_cluster = self.size % bytes_per_cluster;
+
+            if offset_in_cluster == 0 {
+                let cluster_num = match self.next_allocated_cluster() {
+                    Some(cluster_num) => cluster_num,
+                    None => self.fat32.allocate_cluster(self.last_cluster)?
+                };
+                if self.last_cluster.is_none() {
+                    self.first_cluster = cluster_num;
+                }
+                self.last_cluster = Some(cluster_num);
+            }
+
+            let cluster_num = self.last_cluster.unwrap();
+            let length = usize::min((bytes_per_cluster - offset_in_cluster) as usize,
+                                    data.len() - written);
+
+            let offset = self.fat32.cluster_offset(cluster_num) + u64::from(offset_in_cluster);
+            self.fat32.write_volume(offset, &data[written..written+length])?;
+
+            written += length;
+            self.size += length as u32;
+        }
+
+        Ok(())
+    }
+
+    /// The cluster after the one holding the end of the file, if the chain
+    /// already goes on past it.
+    fn next_allocated_cluster(&mut self) -> Option<u32> {
+        match self.last_cluster {
+            Some(last_cluster) => self.fat32.cluster_number_after(last_cluster),
+            None if self.first_cluster != 0 => Some(self.first_cluster),
+            None => None
+        }
+    }
+
+    fn update_entry(&mut self) -> Result<(), Fat32Error> {
+        let mut entry = self.fat32.read_entry(self.location.short);
+        set_entry_cluster(&mut entry, self.first_cluster);
+        entry[0x1C..0x20].copy_from_slice(&to_little_endian(self.size));
+        entry[0x0B] |= DirectoryEntryFlags::ARCHIVE.bits();
+        set_entry_modified(&mut entry, self.fat32.time_source.now());
+        self.fat32.write_entry(self.location.short, &entry)
+    }
+}
+
+/// A fresh 8.3 entry for a subdirectory created at `now`.
+pub(super) fn directory_entry(short_name: &[u8; 11], cluster_num: u32, now: DateTime) -> [u8; 32] {
+    let mut entry = [0; 32];
+    entry[0..11].copy_from_slice(short_name);
+    entry[0x0B] = DirectoryEntryFlags::SUBDIRECTORY.bits();
+    set_entry_cluster(&mut entry, cluster_num);
+    set_entry_created(&mut entry, now);
+    entry
+}
+
+/// Stamp an entry as created, and so also modified and accessed, at `now`.
+fn set_entry_created(entry: &mut [u8; 32], now: DateTime) {
+    set_created_time(entry, now);
+    set_entry_modified(entry, now);
+}
+
+/// Stamp an entry as modified, and so also accessed, at `now`.
+fn set_entry_modified(entry: &mut [u8; 32], now: DateTime) {
+    set_modified_time(entry, now);
+    set_accessed_date(entry, now);
+}
+
+fn set_created_time(entry: &mut [u8; 32], created: DateTime) {
+    let (date, time, fine) = created.to_fat();
+    entry[0x0D] = fine;
+    entry[0x0E..0x10].copy_from_slice(&to_little_endian(u32::from(time))[..2]);
+    entry[0x10..0x12].copy_from_slice(&to_little_endian(u32::from(date))[..2]);
+}
+
+fn set_modified_time(entry: &mut [u8; 32], modified: DateTime) {
+    let (date, time, _) = modified.to_fat();
+    entry[0x16..0x18].copy_from_slice(&to_little_endian(u32::from(time))[..2]);
+    entry[0x18..0x1A].copy_from_slice(&to_little_endian(u32::from(date))[..2]);
+}
+
+fn set_accessed_date(entry: &mut [u8; 32], accessed: DateTime) {
+    let (date, _, _) = accessed.to_fat();
+    entry[0x12..0x14].copy_from_slice(&to_little_endian(u32::from(date))[..2]);
+}
+
+/// The first cluster of an 8.3 entry. Only FAT32 uses the high word.
+pub(super) fn entry_cluster(entry: &[u8; 32], fat_type: FatType) -> u32 {
+    let high_word = if fat_type == FatType::Fat32 { little_endian_to_int(&entry[0x14..0x16]) } else { 0 };
+    (high_word << 16) + little_endian_to_int(&entry[0x1A..0x1C])
+}
+
+pub(super) fn set_entry_cluster(entry: &mut [u8; 32], cluster_num: u32) {
+    let cluster_bytes = to_little_endian(cluster_num);
+    entry[0x14..0x16].copy_from_slice(&cluster_bytes[2..4]);
+    entry[0x1A..0x1C].copy_from_slice(&cluster_bytes[0..2]);
+}
+
+pub(super) fn to_little_endian(value: u32) -> [u8; 4] {
+    [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
+
+/// Convert a name into the space-padded form used by 8.3 entries, if it's a
+/// valid 8.3 name.
+pub(super) fn short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, extension) = match name.rfind('.') {
+        Some(position) => (&name[..position], &name[position+1..]),
+        None => (name, "")
+    };
+
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return None;
+    }
+
+    let mut short_name = [b' '; 11];
+    let parts = base.bytes().zip(0..8).chain(extension.bytes().zip(8..11));
+
+    for (ch, position) in parts {
+        if !is_short_name_character(ch) {
+            return None;
+        }
+        short_name[position] = ch.to_ascii_uppercase();
+    }
+
+    Some(short_name)
+}
+
+/// The 8.3 entry for `name` if it's a valid 8.3 name apart from its case,
+/// with the NT flags for a base or extension that's all lowercase. Names in
+/// mixed case need a long name to keep it.
+fn short_name_with_case(name: &str) -> Option<([u8; 11], NameCaseFlags)> {
+    let short_name = short_name(name)?;
+    let (base, extension) = match name.rfind('.') {
+        Some(position) => (&name[..position], &name[position+1..]),
+        None => (name, "")
+    };
+
+    let mut case_flags = NameCaseFlags::empty();
+    for &(part, flag) in &[(base, NameCaseFlags::LOWERCASE_BASE), (extension, NameCaseFlags::LOWERCASE_EXTENSION)] {
+        let has_lowercase = part.bytes().any(|ch| ch.is_ascii_lowercase());
+        let has_uppercase = part.bytes().any(|ch| ch.is_ascii_uppercase());
+        match (has_lowercase, has_uppercase) {
+            (true, true) => return None,
+            (true, false) => case_flags |= flag,
+            _ => {}
+        }
+    }
+
+    Some((short_name, case_flags))
+}
+
+/// The 8.3 name an alias for `name` is based on, and the length of its
+/// base name.
+fn alias_basis(name: &str) -> ([u8; 11], usize) {
+    let (base, extension) = match name.rfind('.') {
+        Some(position) if position > 0 => (&name[..position], &name[position+1..]),
+        _ => (name, "")
+    };
+
+    let mut basis = [b' '; 11];
+    let mut base_length = 0;
+    for ch in base.chars().filter_map(alias_character).take(8) {
+        basis[base_length] = ch;
+        base_length += 1;
+    }
+    for (position, ch) in extension.chars().filter_map(alias_character).take(3).enumerate() {
+        basis[8 + position] = ch;
+    }
+
+    if base_length == 0 {
+        basis[0] = b'_';
+        base_length = 1;
+    }
+
+    (basis, base_length)
+}
+
+/// A basis for when every tail of `basis` is used: its first two characters
+/// followed by four hex digits of a hash of the name.
+fn hashed_alias_basis((basis, base_length): ([u8; 11], usize), name: &str) -> ([u8; 11], usize) {
+    let hash = name.bytes().fold(0u32, |hash, ch| hash.wrapping_mul(31).wrapping_add(u32::from(ch)));
+    let kept = usize::min(base_length, 2);
+
+    let mut hashed = basis;
+    for (position, ch) in hashed[kept..kept+4].iter_mut().enumerate() {
+        let digit = (hash >> (12 - 4 * position)) & 0xF;
+        *ch = b"0123456789ABCDEF"[digit as usize];
+    }
+    for ch in hashed[kept+4..8].iter_mut() {
+        *ch = b' ';
+    }
+
+    (hashed, kept + 4)
+}
+
+/// `basis` with a `~N` tail, cutting its base name short to make room.
+fn alias_with_tail(basis: &[u8; 11], base_length: usize, number: usize) -> [u8; 11] {
+    let mut tail = [b'~'; 8];
+    let mut tail_length = 1;
+    let mut remaining = number;
+    while remaining > 0 {
+        tail_length += 1;
+        remaining /= 10;
+    }
+    let mut remaining = number;
+    for position in (1..tail_length).rev() {
+        tail[position] = b'0' + (remaining % 10) as u8;
+        remaining /= 10;
+    }
+
+    let kept = usize::min(base_length, 8 - tail_length);
+    let mut alias = *basis;
+    alias[kept..kept+tail_length].copy_from_slice(&tail[..tail_length]);
+    for ch in alias[kept+tail_length..8].iter_mut() {
+        *ch = b' ';
+    }
+    alias
+}
+
+/// The number `N` if `entry` holds `basis` with a `~N` tail, and `N` is
+/// below `ALIAS_TAILS`.
+fn alias_tail(basis: &[u8; 11], base_length: usize, entry: &[u8; 32]) -> Option<usize> {
+    if entry[8..11] != basis[8..11] {
+        return None;
+    }
+
+    let tilde = entry[..8].iter().rposition(|ch| *ch == b'~')?;
+    let digits = &entry[tilde+1..8];
+    let digit_count = digits.iter().take_while(|ch| ch.is_ascii_digit()).count();
+    if digit_count == 0 || digits[0] == b'0' || digits[digit_count..].iter().any(|ch| *ch != b' ') {
+        return None;
+    }
+    if tilde != usize::min(base_length, 8 - (digit_count + 1)) || entry[..tilde] != basis[..tilde] {
+        return None;
+    }
+
+    let number = digits[..digit_count].iter().fold(0, |number, ch| number * 10 + usize::from(ch - b'0'));
+    if number < ALIAS_TAILS { Some(number) } else { None }
+}
+
+pub(super) fn is_short_name_character(ch: u8) -> bool {
+    ch.is_ascii_alphanumeric() || b"!#$%&'()-@^_`{}~".contains(&ch)
+}
+
+/// The character used for `ch` in the 8.3 alias of a long name. Spaces and
+/// dots are left out.
+fn alias_character(ch: char) -> Option<u8> {
+    match ch {
+        ' ' | '.' => None,
+        _ if ch.is_ascii() && is_short_name_character(ch as u8) => Some((ch as u8).to_ascii_uppercase()),
+        _ => Some(b'_')
+    }
+}
+
+/// Encode `name` as UTF-16 for storing in LFN entries, returning the number
+/// of code units used.
+fn encode_long_name(name: &str, long_name: &mut [u16; MAX_LFN_LENGTH]) -> Result<usize, Fat32Error> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(Fat32Error::InvalidName);
+    }
+
+    let mut length = 0;
+    for ch in name.chars() {
+        if (ch as u32) < 0x20 || "\"*/:<>?\\|".contains(ch) {
+            return Err(Fat32Error::InvalidName);
+        }
+
+        let mut units = [0; 2];
+        for unit in ch.encode_utf16(&mut units).iter() {
+            if length == MAX_LFN_LENGTH {
+                return Err(Fat32Error::InvalidName);
+            }
+            long_name[length] = *unit;
+            length += 1;
+        }
+    }
+
+    Ok(length)
+}
+
+/// Build the LFN entry holding part `sequence_number` of `long_name`. The
+/// name is terminated with a null character, then padded with 0xFFFF.
+fn lfn_entry(long_name: &[u16], sequence_number: u8, is_last: bool, checksum: u8) -> [u8; 32] {
+    let mut entry = [0; 32];
+    entry[0x00] = if is_last { sequence_number | LFN_LAST_ENTRY } else { sequence_number };
+    entry[0x0B] = 0x0F;
+    entry[0x0D] = checksum;
+
+    let first_character = (usize::from(sequence_number) - 1) * LFN_CHARACTERS_PER_ENTRY;
+    for (position, offset) in LFN_CHARACTER_OFFSETS.iter().enumerate() {
+        let character = first_character + position;
+        let unit = if character < long_name.len() {
+            long_name[character]
+        } else if character == long_name.len() {
+            0x0000
+        } else {
+            0xFFFF
+        };
+
+        entry[*offset] = unit as u8;
+        entry[*offset + 1] = (unit >> 8) as u8;
+    }
+
+    entry
+}
@@ -1,6 +1,6 @@
 #![no_std]
 
-#[cfg(test)]
+#[cfg(any(test, feature = "std"))]
 #[macro_use]
 extern crate std;
 
@@ -19,8 +19,11 @@ extern crate block_accessor;
 
 // Crate modules
 pub mod byte_util;
+mod chain;
+pub mod exfat;
 pub mod fat32;
 pub mod mbr;
+mod path;
 pub mod sd;
 
 #[cfg(test)]
@@ -29,16 +32,25 @@ mod tests {
     extern crate file_block_accessor;
     extern crate md5;
 
-    use block_accessor::{BlockAccessor};
+    use block_accessor::{BlockAccessor, BlockAccessError};
     use self::file_block_accessor::BlockAccessFile;
     use self::linux_embedded_hal::spidev::{Spidev, SpidevOptions, SPI_MODE_0};
 
     use sd::SDCard;
     use mbr::MBR;
-    use fat32::{Fat32, DirectoryItem};
+    use fat32::{Fat32, Fat32Error, BootSectorCopy, DateTime, DirectoryItem, EntryPosition, FatEntry, FatType, FsInfo, MountError, SeekFrom,
+                TimeSource,
+                short_name_checksum};
+    use fat32::{self, Change, CheckReport, Finding, FormatError, FormatOptions, RepairOptions};
+    use fat32::File as FatFile;
+    use exfat::{self, ExFat, ExFatError};
 
+    use std::cell::Cell;
+    use std::collections::HashMap;
     use std::fs::File;
     use std::io::prelude::*;
+    use std::rc::Rc;
+    use std::string::String;
     use std::vec::Vec;
 
     use embedded_hal::blocking::delay::DelayMs;
@@ -97,6 +109,2070 @@ mod tests {
         }
     }
 
+    /// Sparse in-memory block device, blocks that were never written read
+    /// back as zeros.
+    struct MemoryBlockAccessor {
+        block_size: usize,
+        blocks: HashMap<u64, Vec<u8>>,
+        reads: Rc<Cell<usize>>
+    }
+
+    impl BlockAccessor for MemoryBlockAccessor {
+        fn block_size(&self) -> u64 {
+            self.block_size as u64
+        }
+
+        fn read_block(&mut self, block_num: u64, block: &mut [u8]) {
+            assert_eq!(block.len(), self.block_size);
+            self.reads.set(self.reads.get() + 1);
+            match self.blocks.get(&block_num) {
+                Some(stored) => block.copy_from_slice(stored),
+                None => for b in block.iter_mut() { *b = 0 }
+            }
+        }
+
+        fn write_block(&mut self, block_num: u64, block: &[u8]) -> Result<(), BlockAccessError> {
+            assert_eq!(block.len(), self.block_size);
+            self.blocks.insert(block_num, block.to_vec());
+            Ok(())
+        }
+    }
+
+    impl MemoryBlockAccessor {
+        fn new(block_size: usize) -> MemoryBlockAccessor {
+            MemoryBlockAccessor { block_size, blocks: HashMap::new(), reads: Rc::new(Cell::new(0)) }
+        }
+
+        fn write_bytes(&mut self, address: u64, bytes: &[u8]) {
+            let block_size = self.block_size;
+            let mut block = vec![0; block_size];
+            let mut position = 0;
+            while position < bytes.len() {
+                let address = address + position as u64;
+                let block_num = address / block_size as u64;
+                let offset = (address % block_size as u64) as usize;
+                let length = usize::min(block_size - offset, bytes.len() - position);
+                self.read_block(block_num, &mut block);
+                block[offset..offset+length].copy_from_slice(&bytes[position..position+length]);
+                self.write_block(block_num, &block).ok().unwrap();
+                position += length;
+            }
+        }
+
+        fn read_bytes(&mut self, address: u64, length: usize) -> Vec<u8> {
+            let mut block = vec![0; self.block_size];
+            let mut bytes = Vec::new();
+            while bytes.len() < length {
+                let address = address + bytes.len() as u64;
+                let offset = (address % self.block_size as u64) as usize;
+                let end = usize::min(self.block_size, offset + length - bytes.len());
+                self.read_block(address / self.block_size as u64, &mut block);
+                bytes.extend_from_slice(&block[offset..end]);
+            }
+            bytes
+        }
+    }
+
+    /// A FAT volume built by hand, starting at block 0 of its storage.
+    struct TestImage {
+        storage: MemoryBlockAccessor,
+        bytes_per_sector: u32,
+        sectors_per_cluster: u32,
+        sectors_per_fat: u32,
+        /// 12, 16 or 32
+        fat_bits: u32,
+        /// Sectors in the fixed root directory region of FAT12 and FAT16
+        root_sectors: u32
+    }
+
+    const TEST_RESERVED_SECTORS: u32 = 32;
+
+    impl TestImage {
+        fn fat32(sectors_per_cluster: u8, sector_count: u32, root_cluster: u32) -> TestImage {
+            TestImage::fat32_with_geometry(512, 512, sectors_per_cluster, sector_count, root_cluster)
+        }
+
+        fn fat32_with_geometry(block_size: usize, bytes_per_sector: u16, sectors_per_cluster: u8,
+                               sector_count: u32, root_cluster: u32) -> TestImage
+        {
+            let clusters = (sector_count - TEST_RESERVED_SECTORS) / u32::from(sectors_per_cluster);
+            let bytes_per_sector_u32 = u32::from(bytes_per_sector);
+            let sectors_per_fat = ((clusters + 2) * 4 + bytes_per_sector_u32 - 1) / bytes_per_sector_u32;
+
+            let mut image = TestImage {
+                storage: MemoryBlockAccessor::new(block_size),
+                bytes_per_sector: bytes_per_sector_u32,
+                sectors_per_cluster: u32::from(sectors_per_cluster),
+                sectors_per_fat,
+                fat_bits: 32,
+                root_sectors: 0
+            };
+
+            let mut boot = [0; 512];
+            boot[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+            boot[3..11].copy_from_slice(b"MSWIN4.1");
+            boot[11..13].copy_from_slice(&bytes_per_sector.to_le_bytes());
+            boot[13] = sectors_per_cluster;
+            boot[14..16].copy_from_slice(&(TEST_RESERVED_SECTORS as u16).to_le_bytes());
+            boot[16] = 2;
+            boot[21] = 0xF8;
+            boot[32..36].copy_from_slice(&sector_count.to_le_bytes());
+            boot[36..40].copy_from_slice(&sectors_per_fat.to_le_bytes());
+            boot[44..48].copy_from_slice(&root_cluster.to_le_bytes());
+            boot[48..50].copy_from_slice(&1u16.to_le_bytes());
+            boot[50..52].copy_from_slice(&6u16.to_le_bytes());
+            boot[64] = 0x80;
+            boot[66] = 0x29;
+            boot[67..71].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+            boot[71..82].copy_from_slice(b"NO NAME    ");
+            boot[82..90].copy_from_slice(b"FAT32   ");
+            boot[510] = 0x55;
+            boot[511] = 0xAA;
+            image.write_bytes(0, &boot);
+
+            image.set_fat_entry(0, 0x0FFF_FFF8);
+            image.set_fat_entry(1, 0x0FFF_FFFF);
+            image.set_fat_entry(root_cluster, 0x0FFF_FFFF);
+            image
+        }
+
+        /// A FAT12 or FAT16 volume with 512 byte sectors and room for
+        /// `root_entries` entries in its root directory.
+        fn fat16(fat_bits: u32, sectors_per_cluster: u8, sector_count: u32, root_entries: u16) -> TestImage {
+            let root_sectors = u32::from(root_entries) * 32 / 512;
+            let clusters = (sector_count - TEST_RESERVED_SECTORS - root_sectors) /
+                           u32::from(sectors_per_cluster);
+            let sectors_per_fat = ((clusters + 2) * fat_bits / 8 + 512) / 512;
+
+            let mut image = TestImage {
+                storage: MemoryBlockAccessor::new(512),
+                bytes_per_sector: 512,
+                sectors_per_cluster: u32::from(sectors_per_cluster),
+                sectors_per_fat,
+                fat_bits,
+                root_sectors
+            };
+
+            let mut boot = [0; 512];
+            boot[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+            boot[3..11].copy_from_slice(b"MSDOS5.0");
+            boot[11..13].copy_from_slice(&512u16.to_le_bytes());
+            boot[13] = sectors_per_cluster;
+            boot[14..16].copy_from_slice(&(TEST_RESERVED_SECTORS as u16).to_le_bytes());
+            boot[16] = 2;
+            boot[17..19].copy_from_slice(&root_entries.to_le_bytes());
+            boot[21] = 0xF8;
+            boot[22..24].copy_from_slice(&(sectors_per_fat as u16).to_le_bytes());
+            boot[32..36].copy_from_slice(&sector_count.to_le_bytes());
+            boot[36] = 0x80;
+            boot[38] = 0x29;
+            boot[39..43].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+            boot[43..54].copy_from_slice(b"SMALL CARD ");
+            boot[54..62].copy_from_slice(if fat_bits == 12 { b"FAT12   " } else { b"FAT16   " });
+            boot[510] = 0x55;
+            boot[511] = 0xAA;
+            image.write_bytes(0, &boot);
+
+            image.set_fat_entry(0, 0x0FFF_FFF8);
+            image.set_fat_entry(1, 0x0FFF_FFFF);
+            image
+        }
+
+        fn write_bytes(&mut self, address: u64, bytes: &[u8]) {
+            self.storage.write_bytes(address, bytes);
+        }
+
+        /// Set an entry in both FATs, cutting FAT32 values down to size for
+        /// FAT12 and FAT16.
+        fn set_fat_entry(&mut self, cluster: u32, value: u32) {
+            for fat in 0..2 {
+                let fat_address = u64::from(TEST_RESERVED_SECTORS + fat * self.sectors_per_fat) *
+                                  u64::from(self.bytes_per_sector);
+                match self.fat_bits {
+                    12 => {
+                        let address = fat_address + u64::from(cluster + cluster / 2);
+                        let mut pair = [0; 2];
+                        let mut block = vec![0; self.storage.block_size];
+                        for (i, byte) in pair.iter_mut().enumerate() {
+                            let address = address + i as u64;
+                            self.storage.read_block(address / block.len() as u64, &mut block);
+                            *byte = block[(address % block.len() as u64) as usize];
+                        }
+                        let old = u16::from_le_bytes(pair);
+                        let value = (value & 0x0FFF) as u16;
+                        let new = if cluster % 2 == 1 { (old & 0x000F) | (value << 4) } else { (old & 0xF000) | value };
+                        self.write_bytes(address, &new.to_le_bytes());
+                    },
+                    16 => self.write_bytes(fat_address + u64::from(cluster) * 2, &(value as u16).to_le_bytes()),
+                    _ => self.write_bytes(fat_address + u64::from(cluster) * 4, &value.to_le_bytes())
+                }
+            }
+        }
+
+        /// The address of a cluster, cluster 0 being the fixed root directory
+        /// region of FAT12 and FAT16.
+        fn cluster_address(&self, cluster: u32) -> u64 {
+            let root_start = TEST_RESERVED_SECTORS + 2 * self.sectors_per_fat;
+            let sector = if cluster == 0 && self.fat_bits != 32 {
+                root_start
+            } else {
+                root_start + self.root_sectors + (cluster - 2) * self.sectors_per_cluster
+            };
+            u64::from(sector) * u64::from(self.bytes_per_sector)
+        }
+
+        fn write_dir_entry(&mut self, cluster: u32, index: u32, short_name: &[u8; 11],
+                           attributes: u8, first_cluster: u32, size: u32)
+        {
+            let mut entry = [0; 32];
+            entry[0..11].copy_from_slice(short_name);
+            entry[11] = attributes;
+            entry[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+            entry[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+            entry[28..32].copy_from_slice(&size.to_le_bytes());
+            let address = self.cluster_address(cluster) + u64::from(index) * 32;
+            self.write_bytes(address, &entry);
+        }
+
+        /// Write `data` into a contiguous run of clusters starting at
+        /// `first_cluster`, linking them in the FAT.
+        fn write_file_data(&mut self, first_cluster: u32, data: &[u8]) {
+            let bytes_per_cluster = (self.bytes_per_sector * self.sectors_per_cluster) as usize;
+            let cluster_count = (data.len() + bytes_per_cluster - 1) / bytes_per_cluster;
+            for i in 0..cluster_count as u32 {
+                let next = if i + 1 == cluster_count as u32 { 0x0FFF_FFFF } else { first_cluster + i + 1 };
+                self.set_fat_entry(first_cluster + i, next);
+            }
+            let address = self.cluster_address(first_cluster);
+            self.write_bytes(address, data);
+        }
+
+        /// Write the FSInfo sector of a FAT32 volume, which the boot sector
+        /// puts at sector 1.
+        fn write_fs_info(&mut self, free_cluster_count: u32, next_free_cluster: u32) {
+            let mut sector = [0; 512];
+            sector[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+            sector[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+            sector[488..492].copy_from_slice(&free_cluster_count.to_le_bytes());
+            sector[492..496].copy_from_slice(&next_free_cluster.to_le_bytes());
+            sector[508..512].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+            let address = u64::from(self.bytes_per_sector);
+            self.write_bytes(address, &sector);
+        }
+
+        fn mount(self) -> Fat32<MemoryBlockAccessor> {
+            Fat32::new(self.storage, 0)
+        }
+    }
+
+    /// Read a raw entry straight from one of the copies of the FAT.
+    fn raw_fat_entry(storage: &mut MemoryBlockAccessor, sectors_per_fat: u32,
+                     fat_num: u32, cluster: u32) -> u32
+    {
+        let address = u64::from(TEST_RESERVED_SECTORS + fat_num * sectors_per_fat) * 512 +
+                      u64::from(cluster) * 4;
+        let mut block = [0; 512];
+        storage.read_block(address / 512, &mut block);
+        let offset = (address % 512) as usize;
+        u32::from_le_bytes([block[offset], block[offset+1], block[offset+2], block[offset+3]])
+    }
+
+    fn test_pattern(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    fn read_whole_file(fat32: &mut Fat32<MemoryBlockAccessor>, file: &FatFile) -> Vec<u8> {
+        let mut data = Vec::new();
+        for block in fat32.iter_file(file) {
+            data.extend_from_slice(&block.unwrap());
+        }
+        data
+    }
+
+    fn first_file(fat32: &mut Fat32<MemoryBlockAccessor>) -> FatFile {
+        let root = fat32.root_dir();
+        for item in fat32.iter_dir(&root) {
+            if let DirectoryItem::File(f) = item {
+                return f;
+            }
+        }
+        panic!("No file in the root directory");
+    }
+
+    #[test]
+    fn large_clusters() {
+        // 64 KiB clusters, so a file spans two and a bit of them
+        let data = test_pattern(140_000);
+        let mut image = TestImage::fat32(128, 0x0200_0000, 2);
+        image.write_dir_entry(2, 0, b"BIG     BIN", 0x20, 3, data.len() as u32);
+        image.write_file_data(3, &data);
+        let mut fat32 = image.mount();
+        assert_eq!(fat32.bytes_per_cluster(), 65536);
+
+        let file = first_file(&mut fat32);
+        assert!(read_whole_file(&mut fat32, &file) == data);
+    }
+
+    #[test]
+    fn logical_sectors_larger_than_blocks() {
+        let data = test_pattern(10_000);
+        let mut image = TestImage::fat32_with_geometry(512, 4096, 2, 0x40000, 2);
+        image.write_dir_entry(2, 0, b"DATA    BIN", 0x20, 3, data.len() as u32);
+        image.write_file_data(3, &data);
+        let mut fat32 = image.mount();
+        assert_eq!(fat32.bytes_per_cluster(), 8192);
+
+        let file = first_file(&mut fat32);
+        assert!(read_whole_file(&mut fat32, &file) == data);
+    }
+
+    #[test]
+    fn four_k_native_storage() {
+        for &bytes_per_sector in &[512, 4096] {
+            let data = test_pattern(20_000);
+            let mut image = TestImage::fat32_with_geometry(4096, bytes_per_sector, 1, 0x40000, 2);
+            image.write_dir_entry(2, 0, b"DATA    BIN", 0x20, 3, data.len() as u32);
+            image.write_file_data(3, &data);
+            let mut fat32 = image.mount();
+
+            let file = first_file(&mut fat32);
+            assert!(read_whole_file(&mut fat32, &file) == data);
+        }
+    }
+
+    #[test]
+    fn root_dir_from_bpb() {
+        let mut image = TestImage::fat32(1, 0x40000, 5);
+        image.write_dir_entry(5, 0, b"DATA       ", 0x10, 6, 0);
+        image.set_fat_entry(6, 0x0FFF_FFFF);
+        image.write_dir_entry(6, 0, b"LOG     TXT", 0x20, 0, 0);
+        let mut fat32 = image.mount();
+
+        let root = fat32.root_dir();
+        assert_eq!(root.cluster, 5);
+
+        let data = match fat32.iter_dir(&root).next() {
+            Some(DirectoryItem::Directory(d)) => d,
+            other => panic!("Expected a directory, got {:?}", other)
+        };
+        assert_eq!(data.cluster, 6);
+        assert_eq!(fat32.iter_dir(&data).count(), 1);
+    }
+
+    #[test]
+    fn directory_spanning_clusters() {
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        // 16 entries fit in one cluster, so the root continues in cluster 9
+        image.set_fat_entry(2, 9);
+        image.set_fat_entry(9, 0x0FFF_FFFF);
+        for index in 0..20 {
+            let name = format!("FILE{:04}TXT", index);
+            let mut short_name = [0; 11];
+            short_name.copy_from_slice(name.as_bytes());
+            let (cluster, entry) = if index < 16 { (2, index) } else { (9, index - 16) };
+            image.write_dir_entry(cluster, entry, &short_name, 0x20, 0, 0);
+        }
+        let mut fat32 = image.mount();
+
+        let root = fat32.root_dir();
+        assert_eq!(fat32.iter_dir(&root).count(), 20);
+    }
+
+    #[test]
+    fn full_directory_ends_with_chain() {
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        for index in 0..16 {
+            let name = format!("FILE{:04}TXT", index);
+            let mut short_name = [0; 11];
+            short_name.copy_from_slice(name.as_bytes());
+            image.write_dir_entry(2, index, &short_name, 0x20, 0, 0);
+        }
+        let mut fat32 = image.mount();
+
+        let root = fat32.root_dir();
+        assert_eq!(fat32.iter_dir(&root).count(), 16);
+    }
+
+    #[test]
+    fn file_reader_seek() {
+        let data = test_pattern(300 * 512 + 100);
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_dir_entry(2, 0, b"DATA    BIN", 0x20, 3, data.len() as u32);
+        image.write_file_data(3, &data);
+        let mut fat32 = image.mount();
+        let file = first_file(&mut fat32);
+
+        let mut reader = fat32.open_file(&file);
+        let mut buffer = [0; 1000];
+
+        // Across a cluster boundary
+        assert_eq!(reader.seek(SeekFrom::Start(1000)), Ok(1000));
+        assert_eq!(reader.read(&mut buffer), Ok(1000));
+        assert!(buffer[..] == data[1000..2000]);
+        assert_eq!(reader.position(), 2000);
+
+        // Short read at the end of the file
+        assert_eq!(reader.seek(SeekFrom::End(-10)), Ok(data.len() as u64 - 10));
+        assert_eq!(reader.read(&mut buffer), Ok(10));
+        assert!(buffer[..10] == data[data.len()-10..]);
+        assert_eq!(reader.read(&mut buffer), Ok(0));
+
+        assert_eq!(reader.seek(SeekFrom::Current(-(data.len() as i64) - 1)), Err(Fat32Error::InvalidSeek));
+        assert_eq!(reader.seek(SeekFrom::Current(-300)), Ok(data.len() as u64 - 300));
+        assert_eq!(reader.read(&mut buffer[..300]), Ok(300));
+        assert!(buffer[..300] == data[data.len()-300..]);
+    }
+
+    #[test]
+    fn file_reader_backwards_seek_uses_cached_chain() {
+        let data = test_pattern(300 * 512);
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_dir_entry(2, 0, b"DATA    BIN", 0x20, 3, data.len() as u32);
+        image.write_file_data(3, &data);
+        let reads = image.storage.reads.clone();
+        let mut fat32 = image.mount();
+        let file = first_file(&mut fat32);
+
+        let mut reader = fat32.open_file(&file);
+        let mut buffer = [0; 512];
+        reader.seek(SeekFrom::End(-512)).unwrap();
+        assert_eq!(reader.read(&mut buffer), Ok(512));
+
+        let reads_before = reads.get();
+        reader.seek(SeekFrom::Start(250 * 512)).unwrap();
+        assert_eq!(reader.read(&mut buffer), Ok(512));
+        assert!(buffer[..] == data[250 * 512..251 * 512]);
+        // Walking from the start of the chain would take 250 FAT reads
+        assert!(reads.get() - reads_before < 40);
+    }
+
+    #[test]
+    fn create_and_append_file() {
+        let data = test_pattern(1300);
+        let image = TestImage::fat32(1, 0x40000, 2);
+        let sectors_per_fat = image.sectors_per_fat;
+        let mut fat32 = image.mount();
+
+        let file = fat32.create_file("sensor.log").unwrap();
+        assert_eq!(fat32.create_file("SENSOR.LOG").err(), Some(Fat32Error::AlreadyExists));
+        {
+            let mut writer = fat32.append_file(&file).unwrap();
+            assert_eq!(writer.write(&data[..700]), Ok(700));
+        }
+        let file = first_file(&mut fat32);
+        assert_eq!(file.size, 700);
+        {
+            let mut writer = fat32.append_file(&file).unwrap();
+            assert_eq!(writer.write(&data[700..]), Ok(600));
+            assert_eq!(writer.len(), 1300);
+        }
+
+        let file = first_file(&mut fat32);
+        assert_eq!(file.size, 1300);
+        assert!(read_whole_file(&mut fat32, &file) == data);
+
+        // The chain is linked in both copies of the FAT
+        let mut cluster = file.cluster;
+        for _ in 0..2 {
+            let next = raw_fat_entry(&mut fat32.block_storage, sectors_per_fat, 0, cluster);
+            assert_eq!(raw_fat_entry(&mut fat32.block_storage, sectors_per_fat, 1, cluster), next);
+            cluster = next;
+        }
+        assert_eq!(raw_fat_entry(&mut fat32.block_storage, sectors_per_fat, 0, cluster), 0x0FFF_FFFF);
+        assert_eq!(raw_fat_entry(&mut fat32.block_storage, sectors_per_fat, 1, cluster), 0x0FFF_FFFF);
+    }
+
+    #[test]
+    fn append_fills_allocated_clusters() {
+        let data = test_pattern(1300);
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        // Empty, but with a cluster already
+        image.write_dir_entry(2, 0, b"EMPTY   LOG", 0x20, 3, 0);
+        image.set_fat_entry(3, 0x0FFF_FFFF);
+        // Three clusters allocated ahead of time for 100 bytes
+        image.write_dir_entry(2, 1, b"PREALLOCLOG", 0x20, 5, 100);
+        image.write_file_data(5, &test_pattern(1536));
+        let mut fat32 = image.mount();
+
+        let files: Vec<FatFile> = fat32.iter_dir(&fat32.root_dir()).filter_map(|item| match item {
+            DirectoryItem::File(f) => Some(f),
+            _ => None
+        }).collect();
+        fat32.append_file(&files[0]).unwrap().write(&data[..10]).unwrap();
+        fat32.append_file(&files[1]).unwrap().write(&data).unwrap();
+
+        let files: Vec<FatFile> = fat32.iter_dir(&fat32.root_dir()).filter_map(|item| match item {
+            DirectoryItem::File(f) => Some(f),
+            _ => None
+        }).collect();
+        assert_eq!((files[0].cluster, files[0].size), (3, 10));
+        assert_eq!(fat32.fat_entry(3), FatEntry::EndOfChain);
+        assert_eq!((files[1].cluster, files[1].size), (5, 1400));
+        assert_eq!(fat32.fat_entry(7), FatEntry::EndOfChain);
+        assert_eq!(fat32.fat_entry(4), FatEntry::Free);
+        assert_eq!(fat32.fat_entry(8), FatEntry::Free);
+        assert!(read_whole_file(&mut fat32, &files[1])[100..] == data[..]);
+
+        assert_eq!(fat32.set_fat_entry(1, FatEntry::Free), Err(Fat32Error::InvalidFatEntry));
+        assert_eq!(fat32.set_fat_entry(4, FatEntry::Next(1)), Err(Fat32Error::InvalidFatEntry));
+        assert_eq!(fat32.set_fat_entry(4, FatEntry::Reserved), Err(Fat32Error::InvalidFatEntry));
+    }
+
+    #[test]
+    fn create_file_in_subdirectory() {
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_dir_entry(2, 0, b"LOGS       ", 0x10, 3, 0);
+        image.set_fat_entry(3, 0x0FFF_FFFF);
+        let mut fat32 = image.mount();
+
+        let logs = match fat32.iter_dir(&fat32.root_dir()).next() {
+            Some(DirectoryItem::Directory(d)) => d,
+            other => panic!("Expected a directory, got {:?}", other)
+        };
+        let file = fat32.create_file(&format!("{}/day1.csv", &*logs.name)).unwrap();
+        fat32.append_file(&file).unwrap().write(b"t,value\n").unwrap();
+
+        assert_eq!(fat32.iter_dir(&logs).count(), 1);
+        assert_eq!(fat32.create_file("missing/day1.csv").err(), Some(Fat32Error::NotFound));
+        assert_eq!(fat32.create_file("bad:name.csv").err(), Some(Fat32Error::InvalidName));
+    }
+
+    #[test]
+    fn remove_file_frees_chain() {
+        let data = test_pattern(2000);
+        let image = TestImage::fat32(1, 0x40000, 2);
+        let sectors_per_fat = image.sectors_per_fat;
+        let mut fat32 = image.mount();
+
+        let file = fat32.create_file("OLD.LOG").unwrap();
+        fat32.append_file(&file).unwrap().write(&data).unwrap();
+        let file = first_file(&mut fat32);
+        let first_cluster = file.cluster;
+
+        assert_eq!(fat32.remove("OLD.LOG"), Ok(()));
+        assert_eq!(fat32.remove("OLD.LOG"), Err(Fat32Error::NotFound));
+        let root = fat32.root_dir();
+        assert_eq!(fat32.iter_dir(&root).count(), 0);
+        for cluster in first_cluster..first_cluster + 4 {
+            assert_eq!(raw_fat_entry(&mut fat32.block_storage, sectors_per_fat, 0, cluster), 0);
+            assert_eq!(raw_fat_entry(&mut fat32.block_storage, sectors_per_fat, 1, cluster), 0);
+        }
+
+        // The freed clusters get reused
+        let file = fat32.create_file("NEW.LOG").unwrap();
+        fat32.append_file(&file).unwrap().write(&data[..10]).unwrap();
+        assert_eq!(first_file(&mut fat32).cluster, first_cluster);
+    }
+
+    #[test]
+    fn mount_errors() {
+        let mount_changed = |address: u64, bytes: &[u8]| {
+            let mut image = TestImage::fat32(1, 0x40000, 2);
+            image.write_bytes(address, bytes);
+            Fat32::mount(image.storage, 0).err()
+        };
+
+        assert_eq!(Fat32::mount(MemoryBlockAccessor::new(512), 0).err(), Some(MountError::MissingSignature));
+        assert_eq!(Fat32::mount(MemoryBlockAccessor::new(256), 0).err(), Some(MountError::UnsupportedBlockSize));
+        assert_eq!(mount_changed(66, &[0x2A]), Some(MountError::UnsupportedBootSignature));
+        assert_eq!(mount_changed(11, &300u16.to_le_bytes()), Some(MountError::InvalidBytesPerSector));
+        assert_eq!(mount_changed(13, &[0]), Some(MountError::InvalidClusterSize));
+        assert_eq!(mount_changed(13, &[3]), Some(MountError::InvalidClusterSize));
+        assert_eq!(mount_changed(14, &[0, 0]), Some(MountError::InvalidReservedSectors));
+        assert_eq!(mount_changed(16, &[0]), Some(MountError::InvalidFatCount));
+        assert_eq!(mount_changed(32, &[0; 4]), Some(MountError::InvalidSectorCount));
+        assert_eq!(mount_changed(36, &1u32.to_le_bytes()), Some(MountError::InvalidFatSize));
+        assert_eq!(mount_changed(36, &0x2_0000u32.to_le_bytes()), Some(MountError::InvalidFatSize));
+        // Two FATs this size overflow 32 bits
+        assert_eq!(mount_changed(36, &0x8000_0000u32.to_le_bytes()), Some(MountError::InvalidFatSize));
+        assert_eq!(mount_changed(44, &0x0FFF_FFF0u32.to_le_bytes()), Some(MountError::InvalidRootDirectory));
+
+        let mut image = TestImage::fat16(16, 4, 0x8000, 512);
+        image.write_bytes(17, &[0, 0]);
+        assert_eq!(Fat32::mount(image.storage, 0).err(), Some(MountError::InvalidRootDirectory));
+
+        // The older extended boot signature has no label
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_bytes(66, &[0x28]);
+        image.write_bytes(71, b"NOT A LABEL");
+        let mut fat32 = Fat32::mount(image.storage, 0).ok().unwrap();
+        assert_eq!(fat32.boot_sector.bpb.serial_number, 0x1234_5678);
+        assert!(fat32.volume_label().is_none());
+    }
+
+    #[test]
+    fn fs_info_free_space() {
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_fs_info(1000, 10);
+        let mut fat32 = image.mount();
+        let cluster_count = fat32.cluster_count();
+        assert_eq!(fat32.fs_info(), Some(FsInfo { free_cluster_count: Some(1000), next_free_cluster: Some(10) }));
+        assert_eq!(fat32.free_space(), 1000 * 512);
+        assert_eq!(fat32.total_space(), u64::from(cluster_count) * 512);
+
+        // Allocation starts at the next free cluster, and the FSInfo sector
+        // is kept up to date
+        let file = fat32.create_file("DATA.BIN").unwrap();
+        fat32.append_file(&file).unwrap().write(&test_pattern(1500)).unwrap();
+        assert_eq!(first_file(&mut fat32).cluster, 10);
+        assert_eq!(fat32.free_space(), 997 * 512);
+        let mut sector = [0; 512];
+        fat32.block_storage.read_block(1, &mut sector);
+        assert_eq!(FsInfo::new(&sector, cluster_count),
+                   Some(FsInfo { free_cluster_count: Some(997), next_free_cluster: Some(13) }));
+
+        fat32.remove("DATA.BIN").unwrap();
+        fat32.block_storage.read_block(1, &mut sector);
+        assert_eq!(FsInfo::new(&sector, cluster_count),
+                   Some(FsInfo { free_cluster_count: Some(1000), next_free_cluster: Some(10) }));
+    }
+
+    #[test]
+    fn free_space_without_fs_info() {
+        // A wrong signature means the whole sector is ignored
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_fs_info(1000, 10);
+        image.write_bytes(512 + 511, &[0]);
+        image.set_fat_entry(3, 0x0FFF_FFFF);
+        let mut fat32 = image.mount();
+        let cluster_count = fat32.cluster_count();
+        assert_eq!(fat32.fs_info(), None);
+        assert_eq!(fat32.free_space(), u64::from(cluster_count - 2) * 512);
+
+        // Counts that are unknown or too large are scanned for, and written
+        // out once clusters are allocated
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_fs_info(0xFFFF_FFFF, cluster_count + 2);
+        let mut fat32 = image.mount();
+        assert_eq!(fat32.fs_info(), Some(FsInfo { free_cluster_count: None, next_free_cluster: None }));
+        assert_eq!(fat32.free_space(), u64::from(cluster_count - 1) * 512);
+        let file = fat32.create_file("DATA.BIN").unwrap();
+        fat32.append_file(&file).unwrap().write(&[1]).unwrap();
+        let mut sector = [0; 512];
+        fat32.block_storage.read_block(1, &mut sector);
+        assert_eq!(FsInfo::new(&sector, cluster_count),
+                   Some(FsInfo { free_cluster_count: Some(cluster_count - 2), next_free_cluster: Some(4) }));
+
+        // FSInfo only exists on FAT32
+        let mut fat16 = TestImage::fat16(16, 4, 0x8000, 512).mount();
+        assert_eq!(fat16.fs_info(), None);
+        assert_eq!(fat16.free_space(), u64::from(fat16.cluster_count()) * 2048);
+    }
+
+    #[test]
+    fn truncate_file() {
+        let data = test_pattern(2000);
+        let mut fat32 = TestImage::fat32(1, 0x40000, 2).mount();
+
+        let file = fat32.create_file("DATA.BIN").unwrap();
+        fat32.append_file(&file).unwrap().write(&data).unwrap();
+        let first_cluster = first_file(&mut fat32).cluster;
+
+        fat32.truncate("DATA.BIN", 600).unwrap();
+        let file = first_file(&mut fat32);
+        assert_eq!(file.size, 600);
+        assert!(read_whole_file(&mut fat32, &file) == &data[..600]);
+        assert_eq!(fat32.fat_entry(first_cluster + 1), FatEntry::EndOfChain);
+        assert_eq!(fat32.fat_entry(first_cluster + 2), FatEntry::Free);
+
+        fat32.truncate("DATA.BIN", 1100).unwrap();
+        let file = first_file(&mut fat32);
+        let contents = read_whole_file(&mut fat32, &file);
+        assert!(contents[..600] == data[..600]);
+        assert!(contents[600..].iter().all(|b| *b == 0));
+        assert_eq!(contents.len(), 1100);
+
+        fat32.truncate("DATA.BIN", 0).unwrap();
+        let file = first_file(&mut fat32);
+        assert_eq!((file.size, file.cluster), (0, 0));
+        assert_eq!(fat32.fat_entry(first_cluster), FatEntry::Free);
+    }
+
+    #[test]
+    fn rename_across_directories() {
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_dir_entry(2, 0, b"A          ", 0x10, 3, 0);
+        image.write_dir_entry(2, 1, b"B          ", 0x10, 4, 0);
+        for &cluster in &[3, 4] {
+            image.set_fat_entry(cluster, 0x0FFF_FFFF);
+            image.write_dir_entry(cluster, 0, b".          ", 0x10, cluster, 0);
+            image.write_dir_entry(cluster, 1, b"..         ", 0x10, 0, 0);
+        }
+        let mut fat32 = image.mount();
+
+        let file = fat32.create_file("A/NOTES.TXT").unwrap();
+        fat32.append_file(&file).unwrap().write(b"hello").unwrap();
+
+        fat32.rename("A/NOTES.TXT", "B/MOVED.TXT").unwrap();
+        let moved = match fat32.item_info("B/MOVED.TXT") {
+            Some(DirectoryItem::File(f)) => f,
+            other => panic!("Expected a file, got {:?}", other)
+        };
+        assert_eq!(moved.size, 5);
+        assert!(read_whole_file(&mut fat32, &moved) == b"hello");
+        assert!(fat32.item_info("A/NOTES.TXT").is_none());
+
+        // Moving a directory updates its `..` entry
+        fat32.rename("B", "A/B").unwrap();
+        let moved = fat32.directory_at("A/B").unwrap();
+        match fat32.iter_dir(&moved).include_special_entries().nth(1) {
+            Some(DirectoryItem::Directory(d)) => assert_eq!(d.cluster, 3),
+            other => panic!("Expected a directory, got {:?}", other)
+        }
+
+        assert_eq!(fat32.rename("A", "A/B/A"), Err(Fat32Error::InvalidPath));
+    }
+
+    #[test]
+    fn create_and_remove_directories() {
+        let mut fat32 = TestImage::fat32(1, 0x40000, 2).mount();
+
+        let logs = fat32.create_dir("LOGS").unwrap();
+        let day = fat32.create_dir("LOGS/2018").unwrap();
+        assert_eq!(fat32.create_dir("LOGS").err(), Some(Fat32Error::AlreadyExists));
+
+        let dot_clusters: Vec<u32> = fat32.iter_dir(&logs).include_special_entries().map(|item| match item {
+            DirectoryItem::Directory(d) => d.cluster,
+            DirectoryItem::File(f) => panic!("Unexpected file {:?}", f)
+        }).collect();
+        // `.`, `..` pointing at the root as cluster 0, and the new directory
+        assert_eq!(dot_clusters, vec![logs.cluster, 0, day.cluster]);
+
+        let dot_clusters: Vec<u32> = fat32.iter_dir(&day).include_special_entries().map(|item| match item {
+            DirectoryItem::Directory(d) => d.cluster,
+            DirectoryItem::File(f) => panic!("Unexpected file {:?}", f)
+        }).collect();
+        assert_eq!(dot_clusters, vec![day.cluster, logs.cluster]);
+
+        fat32.create_file("LOGS/2018/A.CSV").unwrap();
+        assert_eq!(fat32.remove_dir("LOGS/2018"), Err(Fat32Error::DirectoryNotEmpty));
+        fat32.remove("LOGS/2018/A.CSV").unwrap();
+        assert_eq!(fat32.remove_dir("LOGS/2018"), Ok(()));
+        assert_eq!(fat32.fat_entry(day.cluster), FatEntry::Free);
+        assert_eq!(fat32.iter_dir(&logs).include_special_entries().count(), 2);
+        assert_eq!(fat32.remove_dir(""), Err(Fat32Error::InvalidPath));
+    }
+
+    #[test]
+    fn directories_grow_when_full() {
+        let mut fat32 = TestImage::fat32(1, 0x40000, 2).mount();
+
+        let logs = fat32.create_dir("LOGS").unwrap();
+        for index in 0..40 {
+            fat32.create_file(&format!("LOGS/{}.CSV", index)).unwrap();
+        }
+
+        // 16 entries fit in a cluster, so the directory now spans three
+        assert_eq!(fat32.iter_dir(&logs).include_special_entries().count(), 42);
+        let second = fat32.cluster_number_after(logs.cluster).unwrap();
+        let third = fat32.cluster_number_after(second).unwrap();
+        assert_eq!(fat32.fat_entry(third), FatEntry::EndOfChain);
+
+        // The root directory grows the same way
+        for index in 0..20 {
+            fat32.create_file(&format!("{}.CSV", index)).unwrap();
+        }
+        let root = fat32.root_dir();
+        assert_eq!(fat32.iter_dir(&root).count(), 21);
+    }
+
+    /// Read the raw entry at `index` in the directory starting at `address`.
+    fn raw_directory_entry(storage: &mut MemoryBlockAccessor, address: u64, index: u32) -> [u8; 32] {
+        let address = address + u64::from(index) * 32;
+        let mut block = vec![0; storage.block_size];
+        storage.read_block(address / block.len() as u64, &mut block);
+        let offset = (address % block.len() as u64) as usize;
+        let mut entry = [0; 32];
+        entry.copy_from_slice(&block[offset..offset+32]);
+        entry
+    }
+
+    #[test]
+    fn long_file_names() {
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_dir_entry(2, 0, b"SENSOR~1CSV", 0x20, 0, 0);
+        let root_address = image.cluster_address(2);
+        let mut fat32 = image.mount();
+
+        let name = "Sensor readings from the north field.csv";
+        let file = fat32.create_file(name).unwrap();
+        fat32.append_file(&file).unwrap().write(b"t,value\n").unwrap();
+        fat32.create_file("log.txt").unwrap();
+        fat32.create_file("Notes.txt").unwrap();
+
+        assert_eq!(fat32.create_file("SENSOR READINGS FROM THE NORTH FIELD.CSV").err(),
+                   Some(Fat32Error::AlreadyExists));
+        assert_eq!(fat32.create_file("Log.TXT").err(), Some(Fat32Error::AlreadyExists));
+
+        let file = match fat32.item_info(name) {
+            Some(DirectoryItem::File(f)) => f,
+            other => panic!("Expected a file, got {:?}", other)
+        };
+        assert_eq!(read_whole_file(&mut fat32, &file), b"t,value\n");
+        let root = fat32.root_dir();
+        let names: Vec<String> = fat32.iter_dir(&root).map(|item| match item {
+            DirectoryItem::File(f) => String::from(&*f.name),
+            DirectoryItem::Directory(d) => String::from(&*d.name)
+        }).collect();
+        assert_eq!(&names[2..], &["log.txt", "Notes.txt"]);
+
+        // 40 characters take four LFN entries, ahead of the second alias
+        let storage = &mut fat32.block_storage;
+        let first = raw_directory_entry(storage, root_address, 1);
+        assert_eq!(first[0], 0x44);
+        assert_eq!(first[11], 0x0F);
+        assert_eq!(&first[1..7], &[b'v', 0, 0, 0, 0xFF, 0xFF]);
+        let short = raw_directory_entry(storage, root_address, 5);
+        assert_eq!(&short[0..11], b"SENSOR~2CSV");
+        assert_eq!(first[13], short_name_checksum(b"SENSOR~2CSV"));
+        // A lowercase 8.3 name keeps its case in the NT flags instead of an LFN
+        let log = raw_directory_entry(storage, root_address, 6);
+        assert_eq!(&log[0..11], b"LOG     TXT");
+        assert_eq!(log[0x0C], 0x18);
+        assert_eq!(&raw_directory_entry(storage, root_address, 8)[0..11], b"NOTES~1 TXT");
+    }
+
+    #[test]
+    fn hashed_short_name_aliases() {
+        // Every `~N` tail up to 255 is taken, filling 16 clusters of root directory
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        let mut entries = Vec::new();
+        for number in 1..256 {
+            let alias = if number < 10 {
+                format!("LONGNA~{}TXT", number)
+            } else if number < 100 {
+                format!("LONGN~{}TXT", number)
+            } else {
+                format!("LONG~{}TXT", number)
+            };
+            let mut entry = [0; 32];
+            entry[0..11].copy_from_slice(alias.as_bytes());
+            entry[11] = 0x20;
+            entries.extend_from_slice(&entry);
+        }
+        image.write_file_data(2, &entries);
+        let root_address = image.cluster_address(2);
+        let mut fat32 = image.mount();
+
+        fat32.create_file("long name.txt").unwrap();
+        assert!(fat32.item_info("long name.txt").is_some());
+
+        let storage = &mut fat32.block_storage;
+        let alias = raw_directory_entry(storage, root_address, 256);
+        assert_eq!(&alias[0..2], b"LO");
+        assert!(alias[2..6].iter().all(|ch| ch.is_ascii_hexdigit()));
+        assert_eq!(&alias[6..11], b"~1TXT");
+    }
+
+    #[test]
+    fn unicode_long_file_names() {
+        let mut fat32 = TestImage::fat32(1, 0x40000, 2).mount();
+
+        let names = ["Größenmessung.csv", "測定データ.txt", "\u{1F4C8} chart.png"];
+        let longest: String = std::iter::repeat('ü').take(255).collect();
+        for name in names.iter().chain(std::iter::once(&&*longest)) {
+            fat32.create_file(name).unwrap();
+        }
+
+        let root = fat32.root_dir();
+        let listing: Vec<String> = fat32.iter_dir(&root).map(|item| match item {
+            DirectoryItem::File(f) => String::from(&*f.name),
+            DirectoryItem::Directory(d) => String::from(&*d.name)
+        }).collect();
+        assert_eq!(&listing[..3], &names[..]);
+        assert_eq!(listing[3], longest);
+
+        let too_long: String = std::iter::repeat('\u{1F4C8}').take(128).collect();
+        assert_eq!(fat32.create_file(&too_long).err(), Some(Fat32Error::InvalidName));
+    }
+
+    #[test]
+    fn short_name_formatting() {
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_dir_entry(2, 0, b"README  TXT", 0x20, 0, 0);
+        image.write_dir_entry(2, 1, b"FOO        ", 0x10, 0, 0);
+        image.write_dir_entry(2, 2, b"\x05BC     DAT", 0x20, 0, 0);
+        image.write_dir_entry(2, 3, b"MAKEFILE   ", 0x20, 0, 0);
+        image.write_dir_entry(2, 4, b"NOTES   MD ", 0x20, 0, 0);
+        image.write_dir_entry(2, 5, b"\x80TE     TXT", 0x20, 0, 0);
+        let address = image.cluster_address(2);
+        image.write_bytes(address + 3 * 32 + 0x0C, &[0x08]);
+        image.write_bytes(address + 4 * 32 + 0x0C, &[0x10]);
+        let mut fat32 = image.mount();
+
+        let root = fat32.root_dir();
+        let listing: Vec<String> = fat32.iter_dir(&root).map(|item| match item {
+            DirectoryItem::File(f) => String::from(&*f.name),
+            DirectoryItem::Directory(d) => String::from(&*d.name)
+        }).collect();
+        assert_eq!(listing, ["README.TXT", "FOO", "σBC.DAT", "makefile", "NOTES.md", "ÇTE.TXT"]);
+    }
+
+    #[test]
+    fn case_insensitive_normalized_lookup() {
+        let mut fat32 = TestImage::fat32(1, 0x40000, 2).mount();
+        fat32.create_dir("projects").unwrap();
+        fat32.create_dir("projects/Größe").unwrap();
+        fat32.create_file("projects/Größe/Measurements 2018.csv").unwrap();
+
+        let paths = [
+            "PROJECTS/GRÖßE/measurements 2018.CSV",
+            "/projects//größe/MEASUR~1.CSV",
+            "projects/./Größe/../Größe/Measurements 2018.csv",
+            "../projects/Größe/Measurements 2018.csv.",
+        ];
+        for path in paths.iter() {
+            match fat32.item_info(path) {
+                Some(DirectoryItem::File(f)) => assert_eq!(&*f.name, "Measurements 2018.csv"),
+                other => panic!("Expected a file at {}, got {:?}", path, other)
+            }
+        }
+
+        assert!(fat32.item_info("projects/Größe/Measurements 2018.csv/").is_none());
+        assert!(fat32.item_info("projects/Größe/Measurements 2018.csv/..").is_none());
+        assert_eq!(fat32.directory_at("projects/Größe/Measurements 2018.csv/..").err(),
+                   Some(Fat32Error::NotADirectory));
+        assert_eq!(fat32.directory_at("projects/missing/..").err(), Some(Fat32Error::NotFound));
+        assert_eq!(&*fat32.directory_at("Projects/GRÖßE/").unwrap().name, "Größe");
+        assert_eq!(&*fat32.directory_at("projects/größe/..").unwrap().name, "projects");
+        assert!(fat32.directory_at("/").unwrap().location.is_none());
+        assert_eq!(fat32.create_dir("Projects/").err(), Some(Fat32Error::AlreadyExists));
+    }
+
+    #[test]
+    fn timestamps_and_attributes() {
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_dir_entry(2, 0, b"MYCARD     ", 0x08, 0, 0);
+        image.write_dir_entry(2, 1, b"DATA    CSV", 0x23, 0, 0);
+        image.write_dir_entry(2, 2, b"SYSTEM~1   ", 0x16, 0, 0);
+        let address = image.cluster_address(2) + 32;
+        // Created 2018-07-14 13:45:31.250, modified 2018-07-15 08:00:02,
+        // accessed 2018-07-16
+        image.write_bytes(address + 0x0D, &[125]);
+        image.write_bytes(address + 0x0E, &((13 << 11) | (45 << 5) | 15u16).to_le_bytes());
+        image.write_bytes(address + 0x10, &((38 << 9) | (7 << 5) | 14u16).to_le_bytes());
+        image.write_bytes(address + 0x12, &((38 << 9) | (7 << 5) | 16u16).to_le_bytes());
+        image.write_bytes(address + 0x16, &((8 << 11) | 1u16).to_le_bytes());
+        image.write_bytes(address + 0x18, &((38 << 9) | (7 << 5) | 15u16).to_le_bytes());
+        let mut fat32 = image.mount();
+
+        let root = fat32.root_dir();
+        let items: Vec<DirectoryItem> = fat32.iter_dir(&root).include_special_entries().collect();
+        let info = match items[1] {
+            DirectoryItem::File(ref f) => f.info,
+            ref other => panic!("Expected a file, got {:?}", other)
+        };
+        let date_time = |day, hour, minute, second, millisecond| DateTime {
+            year: 2018, month: 7, day, hour, minute, second, millisecond
+        };
+        assert_eq!(info.created, Some(date_time(14, 13, 45, 31, 250)));
+        assert_eq!(info.modified, Some(date_time(15, 8, 0, 2, 0)));
+        assert_eq!(info.accessed, Some(date_time(16, 0, 0, 0, 0)));
+        assert!(info.is_read_only() && info.is_hidden() && info.is_archive());
+        assert!(!info.is_system() && !info.is_volume_label());
+
+        match items[0] {
+            DirectoryItem::File(ref f) => {
+                assert!(f.info.is_volume_label());
+                assert_eq!(f.info.modified, None);
+            },
+            ref other => panic!("Expected the volume label, got {:?}", other)
+        }
+        match items[2] {
+            DirectoryItem::Directory(ref d) => assert!(d.info.is_system() && d.info.is_hidden()),
+            ref other => panic!("Expected a directory, got {:?}", other)
+        }
+    }
+
+    struct TestClock(Rc<Cell<DateTime>>);
+
+    impl TimeSource for TestClock {
+        fn now(&self) -> DateTime {
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn timestamps_from_time_source() {
+        let date_time = |day, hour, second, millisecond| DateTime {
+            year: 2018, month: 9, day, hour, minute: 30, second, millisecond
+        };
+        let now = Rc::new(Cell::new(date_time(1, 9, 15, 730)));
+        let image = TestImage::fat32(1, 0x40000, 2);
+        let mut fat32 = Fat32::with_time_source(image.storage, 0, TestClock(now.clone()));
+
+        let file = fat32.create_file("LOG.CSV").unwrap();
+        assert_eq!(file.info.created, Some(date_time(1, 9, 15, 730)));
+        assert_eq!(file.info.modified, Some(date_time(1, 9, 14, 0)));
+
+        now.set(date_time(2, 10, 0, 0));
+        fat32.append_file(&file).unwrap().write(b"1,2\n").unwrap();
+        fat32.create_dir("OLD").unwrap();
+        now.set(date_time(3, 11, 0, 0));
+        fat32.rename("LOG.CSV", "OLD/LOG.CSV").unwrap();
+
+        let info = match fat32.item_info("OLD/LOG.CSV") {
+            Some(DirectoryItem::File(f)) => f.info,
+            other => panic!("Expected a file, got {:?}", other)
+        };
+        assert_eq!(info.created, Some(date_time(1, 9, 15, 730)));
+        assert_eq!(info.modified, Some(date_time(2, 10, 0, 0)));
+        assert_eq!(info.accessed, Some(DateTime { minute: 0, ..date_time(3, 0, 0, 0) }));
+        match fat32.item_info("OLD") {
+            Some(DirectoryItem::Directory(d)) => assert_eq!(d.info.created, Some(date_time(2, 10, 0, 0))),
+            other => panic!("Expected a directory, got {:?}", other)
+        }
+
+        let created = DateTime { year: 2017, ..date_time(5, 8, 1, 990) };
+        fat32.set_times("OLD/LOG.CSV", Some(created), None, None).unwrap();
+        match fat32.item_info("OLD/LOG.CSV") {
+            Some(DirectoryItem::File(f)) => {
+                assert_eq!(f.info.created, Some(created));
+                assert_eq!(f.info.modified, Some(date_time(2, 10, 0, 0)));
+            },
+            other => panic!("Expected a file, got {:?}", other)
+        }
+
+        // Out of range fields are clamped instead of spilling into the next
+        let invalid = DateTime { year: 2018, month: 13, day: 40, hour: 25, minute: 61, second: 75, millisecond: 2500 };
+        fat32.set_times("OLD/LOG.CSV", Some(invalid), Some(invalid), None).unwrap();
+        let latest = |second, millisecond| DateTime {
+            year: 2018, month: 12, day: 31, hour: 23, minute: 59, second, millisecond
+        };
+        match fat32.item_info("OLD/LOG.CSV") {
+            Some(DirectoryItem::File(f)) => {
+                assert_eq!(f.info.created, Some(latest(59, 990)));
+                assert_eq!(f.info.modified, Some(latest(58, 0)));
+            },
+            other => panic!("Expected a file, got {:?}", other)
+        }
+
+        // Past the last year FAT can store, the whole time is the latest
+        let future = DateTime { year: 2200, month: 3, day: 4, hour: 5, minute: 6, second: 7, millisecond: 0 };
+        fat32.set_times("OLD/LOG.CSV", Some(future), Some(future), None).unwrap();
+        let last = DateTime { year: 2107, month: 12, day: 31, hour: 23, minute: 59, second: 58, millisecond: 0 };
+        match fat32.item_info("OLD/LOG.CSV") {
+            Some(DirectoryItem::File(f)) => assert_eq!((f.info.created, f.info.modified), (Some(last), Some(last))),
+            other => panic!("Expected a file, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn special_entries_are_skipped() {
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_dir_entry(2, 0, b"DATA    CSV", 0x20, 0, 0);
+        image.write_dir_entry(2, 1, b"FIELD DATA ", 0x08, 0, 0);
+        let mut fat32 = image.mount();
+        assert_eq!(fat32.volume_label().as_ref().map(|l| &**l), Some("FIELD DATA"));
+
+        fat32.create_dir("LOGS").unwrap();
+        fat32.create_file("LOGS/A.CSV").unwrap();
+        let logs = fat32.directory_at("LOGS").unwrap();
+        let names: Vec<String> = fat32.iter_dir(&logs).map(|item| match item {
+            DirectoryItem::File(f) => String::from(&*f.name),
+            DirectoryItem::Directory(d) => String::from(&*d.name)
+        }).collect();
+        assert_eq!(names, ["A.CSV"]);
+
+        let root = fat32.root_dir();
+        assert_eq!(fat32.iter_dir(&root).count(), 2);
+        assert_eq!(fat32.iter_dir(&root).include_special_entries().count(), 3);
+    }
+
+    #[test]
+    fn volume_label_from_boot_sector() {
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_bytes(0x47, b"LOGGER 7   ");
+        assert_eq!(image.mount().volume_label().as_ref().map(|l| &**l), Some("LOGGER 7"));
+
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_bytes(0x47, b"NO NAME    ");
+        assert!(image.mount().volume_label().is_none());
+    }
+
+    #[test]
+    fn boot_sector_strings() {
+        let fat32 = TestImage::fat32(1, 0x40000, 2).mount();
+        assert_eq!(fat32.boot_sector.bpb.file_system_type_str(), "FAT32");
+        assert!(fat32.boot_sector_label().is_none());
+
+        let fat16 = TestImage::fat16(16, 4, 0x8000, 512).mount();
+        assert_eq!(fat16.boot_sector.bpb.file_system_type_str(), "FAT16");
+        assert_eq!(fat16.boot_sector_label().as_ref().map(|l| &**l), Some("SMALL CARD"));
+    }
+
+    #[test]
+    fn fat_mirroring_flags() {
+        // Mirroring disabled, with only the second FAT in use
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_bytes(40, &0x0081u16.to_le_bytes());
+        let sectors_per_fat = image.sectors_per_fat;
+        let address = u64::from(TEST_RESERVED_SECTORS + sectors_per_fat) * 512 + 3 * 4;
+        image.write_bytes(address, &0x0FFF_FFFFu32.to_le_bytes());
+        let mut fat32 = image.mount();
+        assert_eq!(fat32.boot_sector.bpb.active_fat(), 1);
+        assert!(fat32.boot_sector.bpb.is_mirroring_disabled());
+        assert_eq!(fat32.fat_entry(3), FatEntry::EndOfChain);
+
+        let file = fat32.create_file("DATA.BIN").unwrap();
+        fat32.append_file(&file).unwrap().write(&[1]).unwrap();
+        assert_eq!(first_file(&mut fat32).cluster, 4);
+        assert_eq!(raw_fat_entry(&mut fat32.block_storage, sectors_per_fat, 1, 4), 0x0FFF_FFFF);
+        assert_eq!(raw_fat_entry(&mut fat32.block_storage, sectors_per_fat, 0, 4), 0);
+
+        // The active FAT is ignored while mirroring is on
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_bytes(40, &0x0001u16.to_le_bytes());
+        let mut fat32 = image.mount();
+        assert!(!fat32.boot_sector.bpb.is_mirroring_disabled());
+        fat32.set_fat_entry(5, FatEntry::EndOfChain).unwrap();
+        assert_eq!(raw_fat_entry(&mut fat32.block_storage, sectors_per_fat, 0, 5), 0x0FFF_FFFF);
+        assert_eq!(raw_fat_entry(&mut fat32.block_storage, sectors_per_fat, 1, 5), 0x0FFF_FFFF);
+
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_bytes(40, &0x0082u16.to_le_bytes());
+        assert_eq!(Fat32::mount(image.storage, 0).err(), Some(MountError::InvalidActiveFat));
+
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_bytes(42, &0x0100u16.to_le_bytes());
+        assert_eq!(Fat32::mount(image.storage, 0).err(), Some(MountError::UnsupportedVersion));
+    }
+
+    #[test]
+    fn backup_boot_sector() {
+        for &bytes_per_sector in &[512, 4096] {
+            let sector = u64::from(bytes_per_sector);
+            let mut image = TestImage::fat32_with_geometry(512, bytes_per_sector, 1, 0x40000, 2);
+            image.write_fs_info(1000, 10);
+            let mut fat32 = image.mount();
+            assert!(!fat32.mounted_from_backup());
+            fat32.copy_boot_sector(BootSectorCopy::Primary).unwrap();
+
+            // A damaged primary boot sector is replaced by the backup
+            let mut storage = fat32.block_storage;
+            storage.write_bytes(510, &[0, 0]);
+            storage.write_bytes(sector, &[0; 512]);
+            let mut fat32 = Fat32::mount(storage, 0).ok().unwrap();
+            assert!(fat32.mounted_from_backup());
+            assert_eq!(fat32.boot_sector.bpb.bytes_per_logical_sector, bytes_per_sector);
+            let root = fat32.root_dir();
+            assert_eq!(fat32.iter_dir(&root).count(), 0);
+            // So is the damaged FSInfo sector, but the backup's free count
+            // may be out of date
+            assert_eq!(fat32.fs_info(), Some(FsInfo { free_cluster_count: None, next_free_cluster: Some(10) }));
+
+            // Counts are written to the backup FSInfo sector as well, leaving
+            // the damaged one alone
+            fat32.create_dir("LOGS").unwrap();
+            let next_free_cluster = fat32.fs_info().and_then(|info| info.next_free_cluster);
+            assert_ne!(next_free_cluster, Some(10));
+            let mut fat32 = Fat32::mount(fat32.block_storage, 0).ok().unwrap();
+            assert!(fat32.mounted_from_backup());
+            assert_eq!(fat32.fs_info(), Some(FsInfo { free_cluster_count: None, next_free_cluster }));
+            assert!(fat32.block_storage.read_bytes(sector, 512) == vec![0; 512]);
+
+            fat32.copy_boot_sector(BootSectorCopy::Backup).unwrap();
+            let fat32 = Fat32::mount(fat32.block_storage, 0).ok().unwrap();
+            assert!(!fat32.mounted_from_backup());
+            assert_eq!(fat32.fs_info(), Some(FsInfo { free_cluster_count: None, next_free_cluster }));
+        }
+
+        // Without a good backup the primary's error is reported
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_bytes(510, &[0, 0]);
+        assert_eq!(Fat32::mount(image.storage, 0).err(), Some(MountError::MissingSignature));
+
+        let image = TestImage::fat16(16, 4, 0x8000, 512);
+        let mut fat32 = image.mount();
+        assert_eq!(fat32.copy_boot_sector(BootSectorCopy::Primary).err(), Some(Fat32Error::NoBackupBootSector));
+    }
+
+    #[test]
+    fn dirty_flag() {
+        let image = TestImage::fat32(1, 0x40000, 2);
+        let sectors_per_fat = image.sectors_per_fat;
+        let mut fat32 = image.mount();
+        assert!(fat32.was_cleanly_unmounted());
+        assert!(!fat32.had_hard_error());
+
+        // Reading leaves the flag alone, the first write clears it
+        let root = fat32.root_dir();
+        assert_eq!(fat32.iter_dir(&root).count(), 0);
+        assert_eq!(raw_fat_entry(&mut fat32.block_storage, sectors_per_fat, 0, 1), 0x0FFF_FFFF);
+        let file = fat32.create_file("DATA.BIN").unwrap();
+        for fat_num in 0..2 {
+            assert_eq!(raw_fat_entry(&mut fat32.block_storage, sectors_per_fat, fat_num, 1), 0x07FF_FFFF);
+        }
+        fat32.flush().unwrap();
+        assert_eq!(raw_fat_entry(&mut fat32.block_storage, sectors_per_fat, 1, 1), 0x0FFF_FFFF);
+        fat32.append_file(&file).unwrap().write(&[1]).unwrap();
+        assert_eq!(raw_fat_entry(&mut fat32.block_storage, sectors_per_fat, 0, 1), 0x07FF_FFFF);
+
+        let storage = fat32.unmount().ok().unwrap();
+        let mut fat32 = Fat32::new(storage, 0);
+        assert!(fat32.was_cleanly_unmounted());
+        assert_eq!(raw_fat_entry(&mut fat32.block_storage, sectors_per_fat, 0, 1), 0x0FFF_FFFF);
+
+        // A volume that wasn't clean stays dirty until it's checked
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.set_fat_entry(1, 0x03FF_FFFF);
+        let mut fat32 = image.mount();
+        assert!(!fat32.was_cleanly_unmounted());
+        assert!(fat32.had_hard_error());
+        fat32.create_file("DATA.BIN").unwrap();
+        let mut storage = fat32.unmount().ok().unwrap();
+        assert_eq!(raw_fat_entry(&mut storage, sectors_per_fat, 0, 1), 0x03FF_FFFF);
+
+        let mut image = TestImage::fat16(16, 4, 0x8000, 512);
+        image.set_fat_entry(1, 0x7FFF);
+        assert!(!image.mount().was_cleanly_unmounted());
+        let mut fat32 = TestImage::fat16(16, 4, 0x8000, 512).mount();
+        fat32.create_file("DATA.BIN").unwrap();
+        let mut entry = [0; 512];
+        fat32.block_storage.read_block(u64::from(TEST_RESERVED_SECTORS), &mut entry);
+        assert_eq!(&entry[2..4], &[0xFF, 0x7F]);
+        fat32.flush().unwrap();
+        fat32.block_storage.read_block(u64::from(TEST_RESERVED_SECTORS), &mut entry);
+        assert_eq!(&entry[2..4], &[0xFF, 0xFF]);
+    }
+
+    /// Check the volume, collecting what's found.
+    fn check_volume(fat32: &mut Fat32<MemoryBlockAccessor>) -> (CheckReport, Vec<Finding>) {
+        let mut findings = Vec::new();
+        let report = fat32::check(fat32, |finding| findings.push(finding));
+        (report, findings)
+    }
+
+    #[test]
+    fn check_consistent_volume() {
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_fs_info(0xFFFF_FFFF, 0xFFFF_FFFF);
+        let mut fat32 = image.mount();
+        let free_clusters = fat32.free_space() / 512;
+        fat32.create_dir("logs").unwrap();
+        let file = fat32.create_file("logs/A long file name.txt").unwrap();
+        fat32.append_file(&file).unwrap().write(&test_pattern(1500)).unwrap();
+        fat32.create_file("empty").unwrap();
+
+        let (report, findings) = check_volume(&mut fat32);
+        assert_eq!(findings, []);
+        assert!(report.is_consistent());
+        assert_eq!(report, CheckReport {
+            files: 2,
+            directories: 2,
+            free_clusters: free_clusters as u32 - 4,
+            lost_clusters: 0,
+            findings: 0
+        });
+
+        let mut fat32 = TestImage::fat16(12, 1, 2000, 32).mount();
+        fat32.create_dir("logs").unwrap();
+        let file = fat32.create_file("logs/data.bin").unwrap();
+        fat32.append_file(&file).unwrap().write(&test_pattern(1500)).unwrap();
+        let (report, findings) = check_volume(&mut fat32);
+        assert_eq!(findings, []);
+        assert_eq!((report.files, report.directories), (1, 2));
+    }
+
+    #[test]
+    fn check_findings() {
+        let position = |cluster, index| EntryPosition { cluster, index };
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_fs_info(1000, 10);
+        image.write_dir_entry(2, 0, b"GOOD    TXT", 0x20, 3, 600);
+        image.write_file_data(3, &test_pattern(600));
+        image.write_dir_entry(2, 1, b"SHORT   TXT", 0x20, 5, 2000);
+        image.set_fat_entry(5, 0x0FFF_FFFF);
+        // Runs into a free cluster
+        image.write_dir_entry(2, 2, b"BROKEN  TXT", 0x20, 6, 1024);
+        image.set_fat_entry(6, 7);
+        // Starts in the middle of GOOD.TXT
+        image.write_dir_entry(2, 3, b"CROSS   TXT", 0x20, 4, 512);
+        image.write_dir_entry(2, 4, b"LOOP    TXT", 0x20, 10, 1024);
+        image.set_fat_entry(10, 11);
+        image.set_fat_entry(11, 10);
+        image.write_dir_entry(2, 5, b"..         ", 0x10, 0, 0);
+
+        // A long name made for some other 8.3 name
+        let mut lfn = [0; 32];
+        lfn[0] = 0x41;
+        lfn[11] = 0x0F;
+        lfn[13] = short_name_checksum(b"SUBDIR     ") ^ 1;
+        let address = image.cluster_address(2) + 6 * 32;
+        image.write_bytes(address, &lfn);
+        image.write_dir_entry(2, 7, b"SUBDIR     ", 0x10, 8, 0);
+        image.set_fat_entry(8, 0x0FFF_FFFF);
+        image.write_dir_entry(8, 0, b".          ", 0x10, 8, 0);
+        image.write_dir_entry(8, 1, b"..         ", 0x10, 5, 0);
+
+        // Outside of the first window of clusters
+        image.set_fat_entry(5000, 5001);
+        image.set_fat_entry(5001, 0x0FFF_FFFF);
+        // Lost loops, one with a chain leading into it
+        image.set_fat_entry(6000, 6001);
+        image.set_fat_entry(6001, 6000);
+        image.set_fat_entry(7000, 7001);
+        image.set_fat_entry(7001, 7002);
+        image.set_fat_entry(7002, 7001);
+        let address = u64::from(TEST_RESERVED_SECTORS + image.sectors_per_fat) * 512 + 9000 * 4;
+        image.write_bytes(address, &[1]);
+
+        let mut fat32 = image.mount();
+        let free_clusters = fat32.cluster_count() - 15;
+        let (report, findings) = check_volume(&mut fat32);
+        assert_eq!(findings, [
+            Finding::FatCopyMismatch { fat: 1, cluster: 9000 },
+            Finding::SizeMismatch { entry: position(2, 1), size: 2000, clusters: 1 },
+            Finding::BrokenChain { entry: Some(position(2, 2)), length: 1 },
+            Finding::SizeMismatch { entry: position(2, 2), size: 1024, clusters: 1 },
+            Finding::CrossLink { entry: Some(position(2, 3)), cluster: 4 },
+            Finding::BrokenChain { entry: Some(position(2, 4)), length: 2 },
+            Finding::MisplacedDotEntry { position: position(2, 5) },
+            Finding::OrphanLfn { first: position(2, 6), count: 1 },
+            Finding::BadDotEntry { position: position(8, 1), cluster: 0 },
+            Finding::LostChain { cluster: 5000, length: 2 },
+            Finding::LostChain { cluster: 7000, length: 3 },
+            Finding::LostChain { cluster: 6000, length: 2 },
+            Finding::InvalidFsInfo { free_cluster_count: 1000, next_free_cluster: 10, free_clusters }
+        ]);
+        assert_eq!(report, CheckReport { files: 5, directories: 2, free_clusters, lost_clusters: 7, findings: 13 });
+
+        // With room for every cluster, the volume is checked in one pass
+        let mut buffer = vec![0; 3 * (fat32.cluster_count() as usize / 8 + 1)];
+        let mut one_pass = Vec::new();
+        let one_pass_report = fat32::check_with_buffer(&mut fat32, &mut buffer, |finding| one_pass.push(finding));
+        assert_eq!(one_pass, findings);
+        assert_eq!(one_pass_report, report);
+    }
+
+    #[test]
+    fn repair_volume() {
+        let position = |cluster, index| EntryPosition { cluster, index };
+        let good_data = test_pattern(600);
+        let mut image = TestImage::fat32(1, 70000, 2);
+        image.set_fat_entry(1, 0x07FF_FFFF);
+        image.write_fs_info(1000, 10);
+        image.write_dir_entry(2, 0, b"SHORT   TXT", 0x20, 5, 2000);
+        image.set_fat_entry(5, 0x0FFF_FFFF);
+        image.write_dir_entry(2, 1, b"LONG    TXT", 0x20, 12, 100);
+        image.set_fat_entry(12, 13);
+        image.set_fat_entry(13, 0x0FFF_FFFF);
+        image.write_dir_entry(2, 2, b"BROKEN  TXT", 0x20, 6, 1024);
+        image.set_fat_entry(6, 7);
+        image.write_dir_entry(2, 3, b"GOOD    TXT", 0x20, 3, 600);
+        image.write_file_data(3, &good_data);
+        image.write_dir_entry(2, 4, b"CROSS   TXT", 0x20, 4, 512);
+        let mut lfn = [0; 32];
+        lfn[0] = 0x41;
+        lfn[11] = 0x0F;
+        let address = image.cluster_address(2) + 5 * 32;
+        image.write_bytes(address, &lfn);
+        image.set_fat_entry(5000, 5001);
+        image.set_fat_entry(5001, 0x0FFF_FFFF);
+        let address = u64::from(TEST_RESERVED_SECTORS + image.sectors_per_fat) * 512 + 9000 * 4;
+        image.write_bytes(address, &[1]);
+        let mut fat32 = image.mount();
+        let cluster_count = fat32.cluster_count();
+        let (_, findings) = check_volume(&mut fat32);
+
+        // A dry run shows what would be done to the volume as it is
+        let mut changes = Vec::new();
+        let options = RepairOptions { dry_run: true, recover_lost_chains: false };
+        let report = fat32::repair(&mut fat32, options, |change| changes.push(change)).unwrap();
+        assert_eq!(changes, [
+            Change::TruncatedChain { entry: Some(position(2, 2)), clusters: 1 },
+            Change::CopiedChain { entry: Some(position(2, 4)), cluster: 4 },
+            Change::SetSize { entry: position(2, 0), size: 512 },
+            Change::TruncatedChain { entry: Some(position(2, 1)), clusters: 1 },
+            Change::SetSize { entry: position(2, 2), size: 512 },
+            Change::FreedLostChain { cluster: 5000, length: 2 },
+            Change::RemovedEntries { first: position(2, 5), count: 1 },
+            Change::SyncedFat { fat: 1 },
+            Change::WroteFsInfo { free_cluster_count: cluster_count - 9 }
+        ]);
+        assert_eq!(report.findings, findings.len() as u32);
+        assert_eq!(check_volume(&mut fat32).1, findings);
+        assert!(!fat32.was_cleanly_unmounted());
+
+        let mut changes = Vec::new();
+        let options = RepairOptions { dry_run: false, recover_lost_chains: true };
+        let report = fat32::repair(&mut fat32, options, |change| changes.push(change)).unwrap();
+        assert_eq!(changes, [
+            Change::TruncatedChain { entry: Some(position(2, 2)), clusters: 1 },
+            Change::CopiedChain { entry: Some(position(2, 4)), cluster: 4 },
+            Change::SetSize { entry: position(2, 0), size: 512 },
+            Change::TruncatedChain { entry: Some(position(2, 1)), clusters: 1 },
+            Change::SetSize { entry: position(2, 2), size: 512 },
+            Change::RecoveredLostChain { cluster: 5000, length: 2, number: 0 },
+            Change::RemovedEntries { first: position(2, 5), count: 1 },
+            Change::SyncedFat { fat: 1 },
+            Change::WroteFsInfo { free_cluster_count: cluster_count - 10 }
+        ]);
+        assert!(report.is_consistent());
+        assert_eq!(check_volume(&mut fat32).1, []);
+        assert!(fat32.was_cleanly_unmounted());
+        assert_eq!(fat32.fat_entry(6), FatEntry::EndOfChain);
+        assert_eq!(fat32.fat_entry(7), FatEntry::Free);
+        assert_eq!(fat32.fat_entry(13), FatEntry::Free);
+
+        // The copy keeps the data both files shared
+        let (good, cross) = match (fat32.item_info("GOOD.TXT"), fat32.item_info("CROSS.TXT")) {
+            (Some(DirectoryItem::File(good)), Some(DirectoryItem::File(cross))) => (good, cross),
+            other => panic!("Expected two files, got {:?}", other)
+        };
+        assert_ne!(cross.cluster, 4);
+        assert!(read_whole_file(&mut fat32, &good) == good_data);
+        assert!(read_whole_file(&mut fat32, &cross)[..88] == good_data[512..]);
+
+        match fat32.item_info("FOUND.000/FILE0000.CHK") {
+            Some(DirectoryItem::File(f)) => assert_eq!((f.cluster, f.size), (5000, 1024)),
+            other => panic!("Expected a recovered file, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn repair_cross_link_part_way() {
+        let position = |index| EntryPosition { cluster: 0, index };
+        let data = test_pattern(2048);
+        let image = || {
+            let mut image = TestImage::fat16(12, 1, 2000, 16);
+            image.write_dir_entry(0, 0, b"FIRST   TXT", 0x20, 3, 1536);
+            image.write_file_data(3, &data[..1536]);
+            // Joins the chain of FIRST.TXT at its second cluster
+            image.write_dir_entry(0, 1, b"SECOND  TXT", 0x20, 10, 1536);
+            image.write_file_data(10, &data[1536..]);
+            image.set_fat_entry(10, 4);
+            image
+        };
+        let mut fat32 = image().mount();
+        assert_eq!(check_volume(&mut fat32).1, [
+            Finding::CrossLink { entry: Some(position(0)), cluster: 4 },
+            Finding::CrossLink { entry: Some(position(1)), cluster: 4 }
+        ]);
+
+        // Only the second chain gets a copy, so the shared clusters aren't
+        // left lost
+        let mut changes = Vec::new();
+        let options = RepairOptions { dry_run: false, recover_lost_chains: true };
+        let report = fat32::repair(&mut fat32, options, |change| changes.push(change)).unwrap();
+        assert_eq!(changes, [Change::CopiedChain { entry: Some(position(1)), cluster: 4 }]);
+        assert!(report.is_consistent());
+        let (first, second) = match (fat32.item_info("FIRST.TXT"), fat32.item_info("SECOND.TXT")) {
+            (Some(DirectoryItem::File(first)), Some(DirectoryItem::File(second))) => (first, second),
+            other => panic!("Expected two files, got {:?}", other)
+        };
+        assert_eq!(fat32.fat_entry(3), FatEntry::Next(4));
+        assert_ne!(fat32.fat_entry(10), FatEntry::Next(4));
+        assert!(read_whole_file(&mut fat32, &first) == data[..1536]);
+        let mut expected = data[1536..].to_vec();
+        expected.extend_from_slice(&data[512..1536]);
+        assert!(read_whole_file(&mut fat32, &second) == expected);
+
+        // Without room for the whole copy, nothing is copied
+        let mut fat32 = image().mount();
+        for cluster in 2..fat32.cluster_count() + 2 {
+            if fat32.fat_entry(cluster) == FatEntry::Free && cluster != 20 {
+                fat32.set_fat_entry(cluster, FatEntry::Bad).unwrap();
+            }
+        }
+        let mut changes = Vec::new();
+        let report = fat32::repair(&mut fat32, options, |change| changes.push(change)).unwrap();
+        assert_eq!(changes, []);
+        assert!(!report.is_consistent());
+        assert_eq!(fat32.fat_entry(10), FatEntry::Next(4));
+        assert_eq!(fat32.fat_entry(20), FatEntry::Free);
+    }
+
+    #[test]
+    fn repair_in_batches() {
+        // More lost chains than are fixed from one check
+        let mut image = TestImage::fat32(1, 70000, 2);
+        for cluster in 100..140 {
+            image.set_fat_entry(cluster * 2, 0x0FFF_FFFF);
+        }
+        let mut fat32 = image.mount();
+        let freed: Vec<Change> = (100..140).map(|cluster| Change::FreedLostChain { cluster: cluster * 2, length: 1 })
+                                           .collect();
+
+        let mut changes = Vec::new();
+        let options = RepairOptions { dry_run: true, recover_lost_chains: false };
+        fat32::repair(&mut fat32, options, |change| changes.push(change)).unwrap();
+        assert_eq!(changes, freed);
+
+        let mut changes = Vec::new();
+        let options = RepairOptions { dry_run: false, recover_lost_chains: false };
+        let report = fat32::repair(&mut fat32, options, |change| changes.push(change)).unwrap();
+        assert_eq!(changes, freed);
+        assert!(report.is_consistent());
+    }
+
+    #[test]
+    fn format_volume() {
+        // A 4 GiB card with its partition at 4 MiB
+        let block_count = 8 << 20;
+        let mut storage = MemoryBlockAccessor::new(512);
+        let options = FormatOptions { label: Some("Sensors"), serial_number: 0x1234_5678, ..FormatOptions::new(8192, block_count) };
+        fat32::format(&mut storage, options).unwrap();
+        let mut fat32 = Fat32::mount(storage, 8192).unwrap();
+        assert_eq!(fat32.fat_type(), FatType::Fat32);
+        assert_eq!(fat32.bytes_per_cluster(), 32 << 10);
+        assert_eq!(fat32.volume_label().as_ref().map(|l| &**l), Some("SENSORS"));
+        assert_eq!(fat32.boot_sector_label().as_ref().map(|l| &**l), Some("SENSORS"));
+        assert_eq!(fat32.boot_sector.bpb.serial_number, 0x1234_5678);
+        assert_eq!(fat32.boot_sector.bpb.hidden_sectors, 8192);
+        assert!(fat32.was_cleanly_unmounted());
+        let bpb = &fat32.boot_sector.bpb;
+        let data_start = u32::from(bpb.reserved_logical_sectors) + 2 * bpb.sectors_per_fat;
+        assert_eq!((8192 + data_start) % 8192, 0);
+
+        let cluster_count = fat32.cluster_count();
+        assert_eq!(fat32.fs_info(), Some(FsInfo { free_cluster_count: Some(cluster_count - 1), next_free_cluster: Some(3) }));
+        let (report, findings) = check_volume(&mut fat32);
+        assert_eq!(findings, []);
+        assert_eq!((report.files, report.directories, report.free_clusters), (0, 1, cluster_count - 1));
+        let file = fat32.create_file("data.bin").unwrap();
+        fat32.append_file(&file).unwrap().write(&test_pattern(1500)).unwrap();
+        assert!(fat32::check(&mut fat32, |_| {}).is_consistent());
+
+        // The backup boot sector is there to mount from
+        let mut storage = fat32.unmount().ok().unwrap();
+        storage.write_block(8192, &[0; 512]).unwrap();
+        assert!(Fat32::mount(storage, 8192).unwrap().mounted_from_backup());
+
+        // Small cards get FAT16 with smaller clusters than the table's, to
+        // have enough of them
+        let mut storage = MemoryBlockAccessor::new(512);
+        fat32::format(&mut storage, FormatOptions::new(0, 16 << 11)).unwrap();
+        let mut fat32 = Fat32::mount(storage, 0).unwrap();
+        assert_eq!(fat32.fat_type(), FatType::Fat16);
+        assert_eq!(fat32.bytes_per_cluster(), 2048);
+        assert_eq!(fat32.volume_label(), None);
+        fat32.create_dir("logs").unwrap();
+        assert!(fat32::check(&mut fat32, |_| {}).is_consistent());
+
+        let mut storage = MemoryBlockAccessor::new(4096);
+        let options = FormatOptions { fat_type: Some(FatType::Fat32), ..FormatOptions::new(0, 0x20000) };
+        fat32::format(&mut storage, options).unwrap();
+        let fat32 = Fat32::mount(storage, 0).unwrap();
+        assert_eq!((fat32.fat_type(), fat32.bytes_per_sector()), (FatType::Fat32, 4096));
+
+        // Clusters are at most 64 KiB, whatever the sector size
+        let mut storage = MemoryBlockAccessor::new(4096);
+        let options = FormatOptions { cluster_size: Some(128 << 10), ..FormatOptions::new(0, 0x20000) };
+        assert_eq!(fat32::format(&mut storage, options), Err(FormatError::InvalidClusterSize));
+
+        let mut storage = MemoryBlockAccessor::new(512);
+        let options = FormatOptions::new(0, 0x20000);
+        let errors = [
+            (FormatOptions { fat_type: Some(FatType::Fat12), ..options }, FormatError::UnsupportedFatType),
+            (FormatOptions { fat_type: Some(FatType::Fat32), cluster_size: Some(32 << 10), ..options },
+             FormatError::VolumeTooSmall),
+            (FormatOptions { cluster_size: Some(3000), ..options }, FormatError::InvalidClusterSize),
+            (FormatOptions { erase_block_size: Some(1000), ..options }, FormatError::InvalidEraseBlockSize),
+            (FormatOptions { label: Some("LOGS/2020"), ..options }, FormatError::InvalidLabel)
+        ];
+        for &(options, error) in &errors {
+            assert_eq!(fat32::format(&mut storage, options), Err(error));
+        }
+    }
+
+    #[test]
+    fn fat16_volume() {
+        let data = test_pattern(5000);
+        let mut image = TestImage::fat16(16, 1, 40000, 32);
+        // The high word of the cluster is reserved, OS/2 stored an extended
+        // attribute handle there
+        image.write_dir_entry(0, 0, b"DATA    BIN", 0x20, 0x0007_0002, data.len() as u32);
+        image.write_file_data(2, &data);
+        image.write_dir_entry(0, 1, b"LOGS       ", 0x10, 12, 0);
+        image.set_fat_entry(12, 0xFFFF);
+        image.write_dir_entry(12, 0, b".          ", 0x10, 12, 0);
+        image.write_dir_entry(12, 1, b"..         ", 0x10, 0, 0);
+        let mut fat32 = image.mount();
+        assert_eq!(fat32.fat_type(), FatType::Fat16);
+
+        let file = match fat32.item_info("data.bin") {
+            Some(DirectoryItem::File(f)) => f,
+            other => panic!("Expected a file, got {:?}", other)
+        };
+        assert_eq!(file.cluster, 2);
+        assert!(read_whole_file(&mut fat32, &file) == data);
+        match fat32.item_info("LOGS/..") {
+            Some(DirectoryItem::Directory(d)) => assert!(d.location.is_none()),
+            other => panic!("Expected the root directory, got {:?}", other)
+        }
+
+        let file = fat32.create_file("LOGS/Day one.csv").unwrap();
+        fat32.append_file(&file).unwrap().write(&data).unwrap();
+        match fat32.item_info("logs/day one.csv") {
+            Some(DirectoryItem::File(f)) => assert!(read_whole_file(&mut fat32, &f) == data),
+            other => panic!("Expected a file, got {:?}", other)
+        }
+
+        // The root directory can't grow past its 32 entries
+        let mut created = 2;
+        loop {
+            match fat32.create_file(&format!("{}.TXT", created)) {
+                Ok(_) => created += 1,
+                Err(e) => {
+                    assert_eq!(e, Fat32Error::DirectoryFull);
+                    break;
+                }
+            }
+        }
+        assert_eq!(created, 32);
+    }
+
+    #[test]
+    fn fat12_entries() {
+        let data = test_pattern(3000);
+        let mut image = TestImage::fat16(12, 1, 2000, 16);
+        image.write_dir_entry(0, 0, b"DATA    BIN", 0x20, 3, data.len() as u32);
+        image.write_file_data(3, &data);
+        let mut fat32 = image.mount();
+        assert_eq!(fat32.fat_type(), FatType::Fat12);
+
+        assert_eq!(fat32.fat_entry(3), FatEntry::Next(4));
+        assert_eq!(fat32.fat_entry(4), FatEntry::Next(5));
+        assert_eq!(fat32.fat_entry(8), FatEntry::EndOfChain);
+        assert_eq!(fat32.fat_entry(9), FatEntry::Free);
+        let file = first_file(&mut fat32);
+        assert!(read_whole_file(&mut fat32, &file) == data);
+
+        // Entries share bytes with their neighbours, which have to survive
+        fat32.set_fat_entry(9, FatEntry::Bad).unwrap();
+        fat32.set_fat_entry(10, FatEntry::Next(0x5BC)).unwrap();
+        assert_eq!(fat32.fat_entry(8), FatEntry::EndOfChain);
+        assert_eq!(fat32.fat_entry(9), FatEntry::Bad);
+        assert_eq!(fat32.fat_entry(10), FatEntry::Next(0x5BC));
+        assert_eq!(fat32.fat_entry(11), FatEntry::Free);
+
+        let file = fat32.create_file("MORE.BIN").unwrap();
+        fat32.append_file(&file).unwrap().write(&data).unwrap();
+        let file = match fat32.item_info("MORE.BIN") {
+            Some(DirectoryItem::File(f)) => f,
+            other => panic!("Expected a file, got {:?}", other)
+        };
+        assert_eq!(file.cluster, 2);
+        assert_eq!(fat32.fat_entry(2), FatEntry::Next(11));
+        assert!(read_whole_file(&mut fat32, &file) == data);
+    }
+
+    #[test]
+    fn looped_directory_chain() {
+        let mut image = TestImage::fat16(12, 1, 2000, 16);
+        image.write_dir_entry(0, 0, b"LOGS       ", 0x10, 3, 0);
+        image.set_fat_entry(3, 4);
+        image.set_fat_entry(4, 3);
+        for cluster in 3..5 {
+            for index in 0..16 {
+                image.write_dir_entry(cluster, index, b"OLD     TXT", 0x20, 0, 0);
+            }
+        }
+        let mut fat32 = image.mount();
+
+        // Reading stops after as many clusters as the volume has
+        assert!(fat32.item_info("LOGS/MISSING.TXT").is_none());
+        assert_eq!(fat32.create_file("LOGS/NEW.TXT").err(), Some(Fat32Error::CorruptChain));
+    }
+
+    #[test]
+    fn mismatched_lfn_checksum_is_ignored() {
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        let mut lfn = [0xFF; 32];
+        lfn[0] = 0x41;
+        lfn[11] = 0x0F;
+        lfn[12] = 0;
+        lfn[13] = short_name_checksum(b"OTHER   TXT");
+        lfn[26] = 0;
+        lfn[27] = 0;
+        lfn[1..5].copy_from_slice(&[b'x', 0, 0, 0]);
+        let address = image.cluster_address(2);
+        image.write_bytes(address, &lfn);
+        image.write_dir_entry(2, 1, b"DATA    TXT", 0x20, 0, 0);
+        let mut fat32 = image.mount();
+
+        let file = first_file(&mut fat32);
+        assert_eq!(&*file.name, "DATA.TXT");
+    }
+
+    #[test]
+    fn fat_entry_decoding() {
+        let cluster_count = 1000;
+        assert_eq!(FatEntry::new(0x0000_0000, cluster_count), FatEntry::Free);
+        assert_eq!(FatEntry::new(0xF000_0000, cluster_count), FatEntry::Free);
+        assert_eq!(FatEntry::new(0x0000_0001, cluster_count), FatEntry::Reserved);
+        assert_eq!(FatEntry::new(0x0000_0002, cluster_count), FatEntry::Next(2));
+        assert_eq!(FatEntry::new(0xF000_03E9, cluster_count), FatEntry::Next(1001));
+        assert_eq!(FatEntry::new(0x0000_03EA, cluster_count), FatEntry::Reserved);
+        assert_eq!(FatEntry::new(0x0FFF_FFF6, cluster_count), FatEntry::Reserved);
+        assert_eq!(FatEntry::new(0x0FFF_FFF7, cluster_count), FatEntry::Bad);
+        assert_eq!(FatEntry::new(0x0FFF_FFF8, cluster_count), FatEntry::EndOfChain);
+        assert_eq!(FatEntry::new(0xFFFF_FFFF, cluster_count), FatEntry::EndOfChain);
+    }
+
+    #[test]
+    fn broken_chain_stops_file() {
+        let mut image = TestImage::fat32(1, 0x40000, 2);
+        image.write_dir_entry(2, 0, b"BROKEN  BIN", 0x20, 3, 2048);
+        image.set_fat_entry(3, 4);
+        image.set_fat_entry(4, 0x0FFF_FFF7);
+        let mut fat32 = image.mount();
+
+        let file = match fat32.iter_dir(&fat32.root_dir()).next() {
+            Some(DirectoryItem::File(f)) => f,
+            other => panic!("Expected a file, got {:?}", other)
+        };
+        assert_eq!(fat32.fat_entry(4), FatEntry::Bad);
+        assert_eq!(fat32.cluster_number_after(4), None);
+        // Only the two clusters before the bad one are read, then the chain
+        // is reported as corrupt
+        let blocks: Vec<_> = fat32.iter_file(&file).map(|block| block.map(|b| b.len())).collect();
+        assert_eq!(blocks, [Ok(512), Ok(512), Err(Fat32Error::CorruptChain)]);
+    }
+
+    /// An exFAT volume built by hand, with 512 byte sectors and 4 KiB
+    /// clusters. Cluster 2 holds the allocation bitmap, 3 the up-case table
+    /// and 4 the root directory.
+    struct ExFatImage {
+        storage: MemoryBlockAccessor
+    }
+
+    /// An item for `ExFatImage::write_entry_set` to write an entry set for.
+    struct ExFatItem<'a> {
+        name: &'a str,
+        attributes: u8,
+        cluster: u32,
+        size: u64,
+        contiguous: bool
+    }
+
+    const EXFAT_FAT_OFFSET: u64 = 24;
+    const EXFAT_CLUSTER_HEAP_OFFSET: u64 = 32;
+    const EXFAT_CLUSTER_COUNT: u32 = 64;
+
+    impl ExFatImage {
+        fn new() -> ExFatImage {
+            let mut image = ExFatImage { storage: MemoryBlockAccessor::new(512) };
+
+            let mut boot_region = vec![0; 11 * 512];
+            boot_region[0..3].copy_from_slice(&[0xEB, 0x76, 0x90]);
+            boot_region[3..11].copy_from_slice(b"EXFAT   ");
+            let volume_length = EXFAT_CLUSTER_HEAP_OFFSET + u64::from(EXFAT_CLUSTER_COUNT) * 8;
+            boot_region[72..80].copy_from_slice(&volume_length.to_le_bytes());
+            boot_region[80..84].copy_from_slice(&(EXFAT_FAT_OFFSET as u32).to_le_bytes());
+            boot_region[84..88].copy_from_slice(&8u32.to_le_bytes());
+            boot_region[88..92].copy_from_slice(&(EXFAT_CLUSTER_HEAP_OFFSET as u32).to_le_bytes());
+            boot_region[92..96].copy_from_slice(&EXFAT_CLUSTER_COUNT.to_le_bytes());
+            boot_region[96..100].copy_from_slice(&4u32.to_le_bytes());
+            boot_region[100..104].copy_from_slice(&0xCAFE_F00Du32.to_le_bytes());
+            boot_region[104..106].copy_from_slice(&0x0100u16.to_le_bytes());
+            boot_region[108] = 9;
+            boot_region[109] = 3;
+            boot_region[110] = 1;
+            boot_region[111] = 0x80;
+            boot_region[112] = 0xFF;
+            boot_region[510] = 0x55;
+            boot_region[511] = 0xAA;
+            for sector in 1..9 {
+                boot_region[sector * 512 + 510] = 0x55;
+                boot_region[sector * 512 + 511] = 0xAA;
+            }
+            image.storage.write_bytes(0, &boot_region);
+            image.write_boot_checksum();
+
+            image.set_fat_entry(0, 0xFFFF_FFF8);
+            image.set_fat_entry(1, 0xFFFF_FFFF);
+            for cluster in 2..5 {
+                image.set_fat_entry(cluster, 0xFFFF_FFFF);
+                image.mark_allocated(cluster);
+            }
+
+            // Maps a-z, the Latin-1 lowercase letters except ÿ, and the
+            // Greek lowercase letters
+            let mut up_case: Vec<u16> = vec![0xFFFF, 0x61];
+            up_case.extend(0x41..0x5B);
+            up_case.extend(&[0xFFFF, 0xE0 - 0x7B]);
+            up_case.extend((0xE0..0xFF).map(|unit| if unit == 0xF7 { unit } else { unit - 0x20 }));
+            up_case.extend(&[0xFFFF, 0x3B1 - 0xFF]);
+            up_case.extend((0x3B1..0x3CA).map(|unit| if unit == 0x3C2 { 0x3A3 } else { unit - 0x20 }));
+            let up_case: Vec<u8> = up_case.iter().flat_map(|unit| unit.to_le_bytes().to_vec()).collect();
+            image.write_cluster(3, 0, &up_case);
+
+            let mut label = [0; 32];
+            label[0] = 0x83;
+            label[1] = 6;
+            for (i, unit) in "Logger".encode_utf16().enumerate() {
+                label[2 + i*2..4 + i*2].copy_from_slice(&unit.to_le_bytes());
+            }
+            image.write_cluster(4, 0, &label);
+
+            let mut bitmap = [0; 32];
+            bitmap[0] = 0x81;
+            bitmap[20..24].copy_from_slice(&2u32.to_le_bytes());
+            bitmap[24..32].copy_from_slice(&u64::from(EXFAT_CLUSTER_COUNT / 8).to_le_bytes());
+            image.write_cluster(4, 32, &bitmap);
+
+            let mut up_case_entry = [0; 32];
+            up_case_entry[0] = 0x82;
+            up_case_entry[4..8].copy_from_slice(&exfat::up_case_table_checksum(&up_case).to_le_bytes());
+            up_case_entry[20..24].copy_from_slice(&3u32.to_le_bytes());
+            up_case_entry[24..32].copy_from_slice(&(up_case.len() as u64).to_le_bytes());
+            image.write_cluster(4, 64, &up_case_entry);
+            image
+        }
+
+        fn write_boot_checksum(&mut self) {
+            let mut boot_region = vec![0; 11 * 512];
+            for (sector, chunk) in boot_region.chunks_mut(512).enumerate() {
+                self.storage.read_block(sector as u64, chunk);
+            }
+            let checksum = exfat::boot_checksum(&boot_region);
+            let checksum_sector: Vec<u8> = (0..128).flat_map(|_| checksum.to_le_bytes().to_vec()).collect();
+            self.storage.write_bytes(11 * 512, &checksum_sector);
+        }
+
+        fn set_fat_entry(&mut self, cluster: u32, value: u32) {
+            let address = EXFAT_FAT_OFFSET * 512 + u64::from(cluster) * 4;
+            self.storage.write_bytes(address, &value.to_le_bytes());
+        }
+
+        fn mark_allocated(&mut self, cluster: u32) {
+            let address = EXFAT_CLUSTER_HEAP_OFFSET * 512 + u64::from((cluster - 2) / 8);
+            let mut block = [0; 512];
+            self.storage.read_block(address / 512, &mut block);
+            let byte = block[(address % 512) as usize] | 1 << ((cluster - 2) % 8);
+            self.storage.write_bytes(address, &[byte]);
+        }
+
+        fn write_cluster(&mut self, cluster: u32, offset: u64, bytes: &[u8]) {
+            let address = (EXFAT_CLUSTER_HEAP_OFFSET + u64::from(cluster - 2) * 8) * 512 + offset;
+            self.storage.write_bytes(address, bytes);
+        }
+
+        /// Write the entry set for an item at entry `index` of a directory,
+        /// returning the entry set so tests can tamper with it.
+        fn write_entry_set(&mut self, directory_cluster: u32, index: u64, item: &ExFatItem) -> Vec<u8> {
+            let ExFatItem { name, attributes, cluster, size, contiguous } = *item;
+            let units: Vec<u16> = name.encode_utf16().collect();
+            // Only characters with a single uppercase form are up-cased
+            let up_cased: String = name.chars().map(|ch| {
+                let mut upper = ch.to_uppercase();
+                match (upper.next(), upper.next()) {
+                    (Some(upper), None) => upper,
+                    _ => ch
+                }
+            }).collect();
+            let up_cased: Vec<u16> = up_cased.encode_utf16().collect();
+            let name_entries = units.chunks(15).count();
+
+            let mut set = vec![0; 32 * (2 + name_entries)];
+            set[0] = 0x85;
+            set[1] = (1 + name_entries) as u8;
+            set[4] = attributes;
+            // Modified 2024-05-17 12:30:10.5
+            set[12..16].copy_from_slice(&(0x58B1_63C5u32).to_le_bytes());
+            set[21] = 50;
+
+            set[32] = 0xC0;
+            set[33] = if contiguous { 0x03 } else { 0x01 };
+            set[35] = units.len() as u8;
+            set[36..38].copy_from_slice(&exfat::name_hash(&up_cased).to_le_bytes());
+            set[40..48].copy_from_slice(&size.to_le_bytes());
+            set[52..56].copy_from_slice(&cluster.to_le_bytes());
+            set[56..64].copy_from_slice(&size.to_le_bytes());
+
+            for (i, chunk) in units.chunks(15).enumerate() {
+                let entry = 64 + i * 32;
+                set[entry] = 0xC1;
+                for (j, unit) in chunk.iter().enumerate() {
+                    set[entry + 2 + j*2..entry + 4 + j*2].copy_from_slice(&unit.to_le_bytes());
+                }
+            }
+
+            let checksum = exfat::entry_set_checksum(&set);
+            set[2..4].copy_from_slice(&checksum.to_le_bytes());
+            self.write_cluster(directory_cluster, index * 32, &set);
+            set
+        }
+
+        fn mount(self) -> Result<ExFat<MemoryBlockAccessor>, ExFatError> {
+            ExFat::new(self.storage, 0)
+        }
+    }
+
+    fn read_whole_exfat_file(exfat: &mut ExFat<MemoryBlockAccessor>, path: &str) -> Vec<u8> {
+        let file = match exfat.item_info(path) {
+            Some(exfat::DirectoryItem::File(f)) => f,
+            other => panic!("Expected a file at {}, got {:?}", path, other)
+        };
+        let mut data = Vec::new();
+        for block in exfat.iter_file(&file) {
+            data.extend_from_slice(&block.unwrap());
+        }
+        data
+    }
+
+    #[test]
+    fn exfat_volume() {
+        let mut image = ExFatImage::new();
+
+        // A contiguous file without a FAT chain
+        let report = test_pattern(10_000);
+        image.write_entry_set(4, 3, &ExFatItem {
+            name: "Größe.txt", attributes: 0x20, cluster: 5, size: report.len() as u64, contiguous: true
+        });
+        image.write_cluster(5, 0, &report);
+
+        // A directory holding a fragmented file with a long name
+        image.write_entry_set(4, 6, &ExFatItem {
+            name: "Logs", attributes: 0x10, cluster: 8, size: 4096, contiguous: false
+        });
+        image.set_fat_entry(8, 0xFFFF_FFFF);
+        let log = test_pattern(6000);
+        let log_name = "temperature log for the first day.csv";
+        image.write_entry_set(8, 0, &ExFatItem {
+            name: log_name, attributes: 0x20, cluster: 9, size: log.len() as u64, contiguous: false
+        });
+        image.set_fat_entry(9, 11);
+        image.set_fat_entry(11, 0xFFFF_FFFF);
+        image.set_fat_entry(12, 0x1000_000A);
+        image.write_cluster(9, 0, &log[..4096]);
+        image.write_cluster(11, 0, &log[4096..]);
+        for &cluster in &[5, 6, 7, 8, 9, 11] {
+            image.mark_allocated(cluster);
+        }
+
+        // An empty file with a name past the cached part of the up-case table
+        image.write_entry_set(4, 9, &ExFatItem {
+            name: "Σημειωσεις.txt", attributes: 0x20, cluster: 0, size: 0, contiguous: false
+        });
+
+        let mut exfat = image.mount().unwrap();
+        assert_eq!(exfat.bytes_per_cluster(), 4096);
+        assert_eq!(exfat.volume_label().unwrap().as_str(), "Logger");
+        assert_eq!(exfat.free_cluster_count(), Ok(EXFAT_CLUSTER_COUNT - 9));
+        assert_eq!(exfat.is_cluster_allocated(11), Ok(true));
+        assert_eq!(exfat.is_cluster_allocated(10), Ok(false));
+        assert_eq!(exfat.fat_entry(9), Ok(FatEntry::Next(11)));
+        assert_eq!(exfat.fat_entry(11), Ok(FatEntry::EndOfChain));
+        // All 32 bits count, so this isn't cluster 10 with the top bits set
+        assert_eq!(exfat.fat_entry(12), Ok(FatEntry::Reserved));
+        assert_eq!(exfat.fat_entry(1), Err(ExFatError::InvalidCluster));
+        assert_eq!(exfat.fat_entry(EXFAT_CLUSTER_COUNT + 2), Err(ExFatError::InvalidCluster));
+
+        // The bitmap, up-case table and label aren't listed
+        let root = exfat.root_dir();
+        let names: Vec<String> = exfat.iter_dir(&root).map(|item| match item {
+            exfat::DirectoryItem::File(f) => String::from(f.name.as_str()),
+            exfat::DirectoryItem::Directory(d) => format!("{}/", d.name.as_str())
+        }).collect();
+        assert_eq!(names, vec!["Größe.txt", "Logs/", "Σημειωσεις.txt"]);
+
+        match exfat.item_info("GRÖSSE.txt") {
+            None => {},
+            other => panic!("ß has no single uppercase letter, got {:?}", other)
+        }
+        match exfat.item_info("/größe.TXT") {
+            Some(exfat::DirectoryItem::File(f)) => {
+                assert!(f.contiguous);
+                assert_eq!(f.size, 10_000);
+                assert!(f.info.is_archive());
+                assert_eq!(f.info.modified, Some(DateTime {
+                    year: 2024, month: 5, day: 17, hour: 12, minute: 30, second: 10, millisecond: 500
+                }));
+            },
+            other => panic!("Expected a file, got {:?}", other)
+        }
+        assert!(read_whole_exfat_file(&mut exfat, "GRÖßE.TXT") == report);
+        match exfat.item_info("σημειωσΕΙΣ.TXT") {
+            Some(exfat::DirectoryItem::File(f)) => assert_eq!(&*f.name, "Σημειωσεις.txt"),
+            other => panic!("Expected a file, got {:?}", other)
+        }
+        assert!(read_whole_exfat_file(&mut exfat, "logs/TEMPERATURE LOG FOR THE FIRST DAY.CSV") == log);
+
+        let logs = exfat.directory_at("LOGS/").unwrap();
+        assert_eq!(logs.cluster, 8);
+        assert_eq!(exfat.directory_at("Größe.txt").unwrap_err(), ExFatError::NotADirectory);
+        assert_eq!(exfat.directory_at("missing").unwrap_err(), ExFatError::NotFound);
+        assert!(exfat.item_info("Größe.txt/").is_none());
+        assert!(exfat.item_info("Größe.txt/../LOGS").is_none());
+        assert_eq!(exfat.directory_at("Größe.txt/../LOGS").unwrap_err(), ExFatError::NotADirectory);
+        assert_eq!(exfat.directory_at("missing/..").unwrap_err(), ExFatError::NotFound);
+        assert_eq!(exfat.directory_at("logs/../LOGS/.").unwrap().cluster, 8);
+
+        let file = match exfat.item_info(&format!("Logs/{}", log_name)) {
+            Some(exfat::DirectoryItem::File(f)) => f,
+            other => panic!("Expected a file, got {:?}", other)
+        };
+        let mut reader = exfat.open_file(&file);
+        let mut buffer = [0; 100];
+        reader.seek(SeekFrom::Start(4050)).unwrap();
+        assert_eq!(reader.read(&mut buffer), Ok(100));
+        assert!(buffer[..] == log[4050..4150]);
+        assert_eq!(reader.seek(SeekFrom::End(-10)), Ok(5990));
+        assert_eq!(reader.read(&mut buffer), Ok(10));
+        assert!(buffer[..10] == log[5990..]);
+    }
+
+    #[test]
+    fn exfat_checksums() {
+        // Entry sets that don't match their checksum are ignored
+        let mut image = ExFatImage::new();
+        let mut set = image.write_entry_set(4, 3, &ExFatItem {
+            name: "broken.bin", attributes: 0x20, cluster: 5, size: 10, contiguous: true
+        });
+        set[32 + 24] ^= 0x01;
+        image.write_cluster(4, 3 * 32, &set);
+        image.write_entry_set(4, 6, &ExFatItem {
+            name: "fine.bin", attributes: 0x20, cluster: 6, size: 10, contiguous: true
+        });
+        let mut exfat = image.mount().unwrap();
+        let root = exfat.root_dir();
+        let names: Vec<String> = exfat.iter_dir(&root).map(|item| match item {
+            exfat::DirectoryItem::File(f) => String::from(f.name.as_str()),
+            exfat::DirectoryItem::Directory(d) => String::from(d.name.as_str())
+        }).collect();
+        assert_eq!(names, vec!["fine.bin"]);
+
+        // Only data up to the valid length is read, the rest is zeros
+        let mut image = ExFatImage::new();
+        let mut set = image.write_entry_set(4, 3, &ExFatItem {
+            name: "sparse.bin", attributes: 0x20, cluster: 5, size: 8000, contiguous: true
+        });
+        set[40..48].copy_from_slice(&5000u64.to_le_bytes());
+        let checksum = exfat::entry_set_checksum(&set);
+        set[2..4].copy_from_slice(&checksum.to_le_bytes());
+        image.write_cluster(4, 3 * 32, &set);
+        image.write_cluster(5, 0, &[0xAA; 8000]);
+        let mut exfat = image.mount().unwrap();
+        let data = read_whole_exfat_file(&mut exfat, "sparse.bin");
+        assert_eq!(data.len(), 8000);
+        assert!(data[..5000].iter().all(|b| *b == 0xAA));
+        assert!(data[5000..].iter().all(|b| *b == 0));
+
+        // The volume flags and percent in use aren't covered by the boot
+        // checksum, but everything else is
+        let mut image = ExFatImage::new();
+        image.storage.write_bytes(106, &[0x02, 0x00]);
+        image.storage.write_bytes(112, &[50]);
+        assert!(image.mount().is_ok());
+        let mut image = ExFatImage::new();
+        image.storage.write_bytes(512 + 100, &[1]);
+        assert_eq!(image.mount().err(), Some(ExFatError::BootChecksumMismatch));
+
+        let mut image = ExFatImage::new();
+        image.write_cluster(3, 4, &[0x42, 0x00]);
+        assert_eq!(image.mount().err(), Some(ExFatError::UpCaseChecksumMismatch));
+
+        let mut image = ExFatImage::new();
+        image.write_cluster(4, 32, &[0x01]);
+        assert_eq!(image.mount().err(), Some(ExFatError::MissingAllocationBitmap));
+
+        let image = TestImage::fat32(1, 0x40000, 2);
+        assert_eq!(ExFat::new(image.storage, 0).err(), Some(ExFatError::NotExFat));
+    }
+
     #[test]
     fn basic_file_block_access() {
         let mut t = BlockAccessFile::new("../card-dump/sd-trim.img").unwrap();
@@ -117,9 +2193,9 @@ mod tests {
         let partition = mbr.partition_entries.get(0).unwrap().as_ref().unwrap();
 
         let mut fat32 = Fat32::new(t, partition.first_sector_block_address);
-        // fat32.ls_cluster(3);
 
-        for item in fat32.iter_contents_of_directory_cluster(4) {
+        let root = fat32.root_dir();
+        for item in fat32.iter_dir(&root) {
             match item {
                 DirectoryItem::File(f) => {
                     println!("{:?}", f.name);
@@ -137,7 +2213,7 @@ mod tests {
                 let mut local_file = File::create("python_welcome.mp3").unwrap();
 
                 for block in fat32.iter_file(&f) {
-                    local_file.write(&block).unwrap();
+                    local_file.write(&block.unwrap()).unwrap();
                 }
             },
             _ => panic!("Should be a file")
@@ -0,0 +1,150 @@
+/// Iterator over the parts of a path as written, along with the length of
+/// the path up to the end of each part.
+///
+/// Empty and `.` parts are skipped, but `..` is left for the caller to
+/// resolve against the directory it was looked up in.
+pub struct Parts<'a> {
+    path: &'a str,
+    position: usize
+}
+
+impl<'a> Parts<'a> {
+    pub fn new(path: &'a str) -> Self {
+        Parts {
+            path,
+            position: 0
+        }
+    }
+}
+
+impl<'a> Iterator for Parts<'a> {
+    type Item = (&'a str, usize);
+
+    fn next(&mut self) -> Option<(&'a str, usize)> {
+        while self.position < self.path.len() {
+            let (part, _) = next_part(&self.path[self.position..]);
+            let end = self.position + part.len();
+            self.position = end + 1;
+
+            if !part.is_empty() && part != "." {
+                return Some((part, end));
+            }
+        }
+
+        None
+    }
+}
+
+/// Iterator over the names in a path, after normalizing it lexically.
+///
+/// Leading, doubled and trailing slashes are ignored, as are `.` parts.
+/// `..` removes the part before it, and stays at the root when there isn't
+/// one. This is only right once every part before a `..` is known to be a
+/// directory. Trailing dots are dropped from names, which is what both the
+/// Windows and Linux vfat drivers do.
+pub struct Components<'a> {
+    rest: &'a str
+}
+
+impl<'a> Components<'a> {
+    pub fn new(path: &'a str) -> Self {
+        Components {
+            rest: path
+        }
+    }
+}
+
+impl<'a> Iterator for Components<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        while !self.rest.is_empty() {
+            let (part, rest) = next_part(self.rest);
+            self.rest = rest;
+
+            if part.is_empty() || part == "." || part == ".." {
+                continue;
+            }
+
+            // Skip the part if a later `..` takes it back out
+            let mut depth = 0;
+            let mut removed = false;
+            let mut later = rest;
+            while !later.is_empty() {
+                let (later_part, after) = next_part(later);
+                later = after;
+
+                match later_part {
+                    "" | "." => {},
+                    ".." if depth == 0 => {
+                        removed = true;
+                        break;
+                    },
+                    ".." => depth -= 1,
+                    _ => depth += 1
+                }
+            }
+
+            if !removed {
+                return Some(part.trim_end_matches('.'));
+            }
+        }
+
+        None
+    }
+}
+
+fn next_part(path: &str) -> (&str, &str) {
+    match path.find('/') {
+        Some(position) => (&path[..position], &path[position+1..]),
+        None => (path, "")
+    }
+}
+
+/// Whether `path` can only refer to a directory, because it ends with a
+/// slash, `.` or `..`.
+pub fn is_directory_path(path: &str) -> bool {
+    let last_part = path.rsplit('/').next().unwrap_or("");
+    last_part.is_empty() || last_part == "." || last_part == ".."
+}
+
+/// Split a path into its parent directory and the name of the last part.
+/// Trailing slashes, and trailing dots on the name, are ignored.
+pub fn split_path(path: &str) -> (&str, &str) {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = match path.rfind('/') {
+        Some(position) => (&path[..position], &path[position+1..]),
+        None => ("", path)
+    };
+
+    if name == "." || name == ".." {
+        // Not a name that can be created, and rejected as one
+        (parent, name)
+    } else {
+        (parent, name.trim_end_matches('.'))
+    }
+}
+
+/// Compare two names the way FAT does, ignoring case.
+pub fn names_match(a: &str, b: &str) -> bool {
+    let mut a = a.chars().map(fold_case);
+    let mut b = b.chars().map(fold_case);
+
+    loop {
+        match (a.next(), b.next()) {
+            (None, None) => return true,
+            (Some(a), Some(b)) if a == b => {},
+            _ => return false
+        }
+    }
+}
+
+/// Uppercase `ch` when it has a single uppercase character, like the up-case
+/// table Windows uses.
+fn fold_case(ch: char) -> char {
+    let mut upper = ch.to_uppercase();
+    match (upper.next(), upper.next()) {
+        (Some(upper), None) => upper,
+        _ => ch
+    }
+}